@@ -0,0 +1,26 @@
+#[tokio::main]
+async fn main() {
+    env_logger::builder()
+        .filter(None, log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let config =
+        nwws_oi::Config::from_env().expect("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
+
+    // Unlike Stream, a Connection does not reconnect, so this ends with the first error
+    let connection = nwws_oi::Connection::new(config)
+        .await
+        .expect("connecting failed");
+    let error = connection
+        .run_with(|message| async move {
+            println!(
+                "{} {} {}",
+                message.ttaaii,
+                message.cccc,
+                message.awips_id.as_deref().unwrap_or("-")
+            );
+        })
+        .await;
+    eprintln!("disconnected: {}", error);
+}
@@ -0,0 +1,23 @@
+use futures::StreamExt;
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder()
+        .filter(None, log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let config =
+        nwws_oi::Config::from_env().expect("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
+
+    // Connection states and errors are handled internally; only products come out
+    let mut messages = nwws_oi::Stream::new(config).messages();
+    while let Some(message) = messages.next().await {
+        println!(
+            "{} {} {}",
+            message.ttaaii,
+            message.cccc,
+            message.awips_id.as_deref().unwrap_or("-")
+        );
+    }
+}
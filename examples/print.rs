@@ -9,17 +9,28 @@ async fn main() {
         .parse_default_env()
         .init();
 
-    let username = std::env::var("NWWS_OI_USERNAME").expect("NWWS_OI_USERNAME must be set");
-    let password = std::env::var("NWWS_OI_PASSWORD").expect("NWWS_OI_PASSWORD must be set");
+    let config =
+        nwws_oi::Config::from_env().expect("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
 
-    let mut stream = nwws_oi::Stream::new((username, password));
+    let mut stream = nwws_oi::Stream::new(config);
     while let Some(event) = stream.next().await {
         match event {
             StreamEvent::ConnectionState(_state) => {}
+            StreamEvent::ServerConnectionState(_server, _state) => {}
+            StreamEvent::Joined { server, jid } => log::info!("connected to {} as {}", server, jid),
             StreamEvent::Error(error) => log::error!("error: {}", error),
             StreamEvent::Message(message) => {
-                log::info!("{}", format!("{:#?}", message));
+                log::info!("{:#?}", message);
             }
+            StreamEvent::Presence(_presence) => {}
+            StreamEvent::Subject(subject) => log::info!("joined {:?}", subject),
+            StreamEvent::SequenceGap { missing, .. } => log::warn!("{} messages missing", missing),
+            StreamEvent::Overflow { dropped } => log::warn!("{} messages dropped", dropped),
+            StreamEvent::Unparsed(unparsed) => log::warn!("unparsed: {}", unparsed.error),
+            StreamEvent::Reconnecting { attempt, delay, .. } => {
+                log::info!("reconnecting in {:?} (attempt {})", delay, attempt)
+            }
+            _ => {}
         }
     }
 }
@@ -0,0 +1,30 @@
+use futures::StreamExt;
+
+// A multi-threaded runtime, shared by the rest of the application
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    env_logger::builder()
+        .filter(None, log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let config =
+        nwws_oi::Config::from_env().expect("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
+
+    // Run the connection on this runtime instead of a dedicated one
+    let mut messages =
+        nwws_oi::Stream::spawn_on(config, tokio::runtime::Handle::current()).messages();
+
+    // Other work shares the same runtime
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            log::info!("still running");
+        }
+    });
+
+    while let Some(message) = messages.next().await {
+        println!("{} {}", message.ttaaii, message.cccc);
+    }
+}
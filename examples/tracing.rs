@@ -0,0 +1,22 @@
+use futures::StreamExt;
+
+// cargo run --example tracing --features tracing
+#[tokio::main]
+async fn main() {
+    // Events from nwws_oi carry the server, JID, and channel of the spans they happened in, and
+    // each product received is logged at the trace level with its ID
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info,nwws_oi=trace".into()),
+        )
+        .init();
+
+    let config =
+        nwws_oi::Config::from_env().expect("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
+
+    let mut messages = nwws_oi::Stream::new(config).messages();
+    while let Some(message) = messages.next().await {
+        tracing::info!(id = %message.id, ttaaii = %message.ttaaii, cccc = %message.cccc, "handled");
+    }
+}
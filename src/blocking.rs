@@ -0,0 +1,139 @@
+use crate::*;
+use std::sync::Arc;
+
+/// A [`Stream`](struct.Stream.html) for synchronous code.
+///
+/// `Stream` already runs its connections on a background thread, so `BlockingStream` merely waits
+/// for that thread's events. It is available with the `blocking` feature.
+///
+/// The methods which wait must not be called from within an async context, where they would
+/// block the executor. (Tokio panics if they are called from a Tokio runtime.)
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let mut stream = nwws_oi::BlockingStream::new(("user", "pass"));
+/// while let Some(event) = stream.recv() {
+///     if let nwws_oi::StreamEvent::Message(message) = event {
+///         println!("{}", message.ttaaii);
+///     }
+/// }
+/// ```
+pub struct BlockingStream {
+    stream: Stream,
+}
+
+impl BlockingStream {
+    /// See [`Stream::new()`](struct.Stream.html#method.new).
+    pub fn new<C: Into<Config>>(config: C) -> Self {
+        Stream::new(config).into()
+    }
+
+    /// See [`Stream::with_capacity()`](struct.Stream.html#method.with_capacity).
+    pub fn with_capacity<C: Into<Config>>(config: C, capacity: usize) -> Self {
+        Stream::with_capacity(config, capacity).into()
+    }
+
+    /// See [`Stream::with_failover()`](struct.Stream.html#method.with_failover).
+    pub fn with_failover<P: Into<Config>, B: Into<Config>>(primary: P, backup: B) -> Self {
+        Stream::with_failover(primary, backup).into()
+    }
+
+    /// See [`Stream::resume()`](struct.Stream.html#method.resume).
+    pub fn resume<C: Into<Config>>(config: C, state: ResumeState) -> Self {
+        Stream::resume(config, state).into()
+    }
+
+    /// See [`Stream::resume_with_failover()`](struct.Stream.html#method.resume_with_failover).
+    pub fn resume_with_failover<P: Into<Config>, B: Into<Config>>(
+        primary: P,
+        backup: B,
+        state: ResumeState,
+    ) -> Self {
+        Stream::resume_with_failover(primary, backup, state).into()
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Returns `None` once the stream has ended.
+    pub fn recv(&mut self) -> Option<StreamEvent> {
+        self.stream.blocking_recv()
+    }
+
+    /// See [`Stream::set_filter()`](struct.Stream.html#method.set_filter).
+    pub fn set_filter(&self, filter: MessageFilter) {
+        self.stream.set_filter(filter)
+    }
+
+    /// See [`Stream::metrics()`](struct.Stream.html#method.metrics).
+    pub fn metrics(&self) -> Arc<StreamMetrics> {
+        self.stream.metrics()
+    }
+
+    /// See [`Stream::last_position()`](struct.Stream.html#method.last_position).
+    pub fn last_position(&self) -> ResumeState {
+        self.stream.last_position()
+    }
+
+    /// Leave the room, disconnect, and wait for the background thread to exit.
+    ///
+    /// See [`Stream::shutdown()`](struct.Stream.html#method.shutdown).
+    pub fn shutdown(self) {
+        futures::executor::block_on(self.stream.shutdown())
+    }
+}
+
+impl From<Stream> for BlockingStream {
+    fn from(stream: Stream) -> Self {
+        Self { stream }
+    }
+}
+
+impl Iterator for BlockingStream {
+    type Item = StreamEvent;
+
+    /// Equivalent to [`recv()`](#method.recv).
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn recv() {
+        // Nothing is listening on this port, so every attempt fails immediately
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .backoff(Backoff {
+                initial: Duration::from_secs(60),
+                ..Default::default()
+            })
+            .build();
+
+        let events = std::thread::spawn(move || {
+            let stream = BlockingStream::new(config);
+            let events: Vec<_> = stream.take(3).collect();
+            events
+        })
+        .join()
+        .unwrap();
+
+        assert!(matches!(
+            events[0],
+            StreamEvent::ConnectionState(ConnectionState::Connecting)
+        ));
+        assert!(matches!(events[1], StreamEvent::Error(Error::Network(_))));
+        assert!(matches!(
+            events[2],
+            StreamEvent::ConnectionState(ConnectionState::Disconnected)
+        ));
+    }
+}
@@ -0,0 +1,1091 @@
+use crate::logging::{debug, info};
+use crate::*;
+use futures::{SinkExt, StreamExt};
+use sasl::client::mechanisms::{Anonymous, Plain, Scram};
+use sasl::client::Mechanism;
+use sasl::common::scram::{Sha1, Sha256};
+use sasl::common::{ChannelBinding, Credentials};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use tokio_xmpp::{AuthError, ConnecterError, Packet, ProtocolError};
+use xmpp_parsers::{ns, Element, Jid};
+
+type XmppError = tokio_xmpp::Error;
+#[cfg(feature = "tls-native")]
+type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+#[cfg(feature = "tls-rustls")]
+type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// The default XMPP client port for STARTTLS, used when the SRV lookup fails.
+const DEFAULT_PORT: u16 = 5222;
+
+/// The server's certificate is not one of `Config::pinned_certificates`.
+#[derive(Debug)]
+struct CertificateMismatch;
+
+impl std::fmt::Display for CertificateMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the server's certificate does not match any pinned certificate")
+    }
+}
+
+impl std::error::Error for CertificateMismatch {}
+
+impl From<CertificateMismatch> for XmppError {
+    fn from(e: CertificateMismatch) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+    }
+}
+
+/// Whether `error` was caused by the server's certificate not matching
+/// `Config::pinned_certificates`.
+pub(crate) fn is_certificate_mismatch(error: &XmppError) -> bool {
+    match error {
+        XmppError::Io(e) => e
+            .get_ref()
+            .map_or(false, |inner| inner.is::<CertificateMismatch>()),
+        _ => false,
+    }
+}
+
+/// The server offers none of the mechanisms allowed by `Config::sasl`.
+#[derive(Debug)]
+struct NoMechanism(Vec<String>);
+
+impl std::fmt::Display for NoMechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the server offers no allowed SASL mechanism, only {:?}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NoMechanism {}
+
+impl From<NoMechanism> for XmppError {
+    fn from(e: NoMechanism) -> Self {
+        std::io::Error::new(std::io::ErrorKind::PermissionDenied, e).into()
+    }
+}
+
+/// The mechanisms offered by the server, if `error` was caused by none of them being allowed by
+/// `Config::sasl`.
+pub(crate) fn offered_mechanisms(error: &XmppError) -> Option<&[String]> {
+    match error {
+        XmppError::Io(e) => e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<NoMechanism>())
+            .map(|e| e.0.as_slice()),
+        _ => None,
+    }
+}
+
+/// An authenticated XMPP stream, bound to a resource.
+pub(crate) struct Client {
+    stream: XMPPStream<TlsStream>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl Client {
+    /// Connect, negotiate TLS, authenticate with `password`, and bind a resource.
+    pub(crate) async fn connect(config: &Config, password: String) -> Result<Self, XmppError> {
+        let jid: Jid = config.jid().parse()?;
+        let username = config.username.clone();
+
+        // TCP connection
+        let tcp_stream = connect_tcp(config).await?;
+        let peer_addr = tcp_stream.peer_addr().ok();
+        if let Some(addr) = peer_addr {
+            info!("connected to {} at {}", config.server.hostname(), addr);
+        }
+
+        let tls_stream = match config.transport {
+            Transport::StartTls => {
+                // Unencrypted XMPPStream
+                let xmpp_stream =
+                    XMPPStream::start(tcp_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
+                        .await?;
+                if !xmpp_stream.stream_features.can_starttls() {
+                    return Err(ProtocolError::NoTls.into());
+                }
+                starttls(xmpp_stream, config).await?
+            }
+            Transport::DirectTls { .. } => tls_connect(tcp_stream, config).await?,
+        };
+
+        // Encrypted XMPPStream
+        let xmpp_stream =
+            XMPPStream::start(tls_stream, jid.clone(), ns::JABBER_CLIENT.to_owned()).await?;
+
+        // Authenticated XMPPStream
+        let credentials = Credentials::default()
+            .with_username(username)
+            .with_password(password)
+            .with_channel_binding(ChannelBinding::None);
+        let stream = auth(xmpp_stream, credentials, &config.sasl).await?;
+        let xmpp_stream = XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned()).await?;
+
+        // XMPPStream bound to our resource
+        let stream = bind(xmpp_stream).await?;
+        Ok(Self { stream, peer_addr })
+    }
+
+    /// The JID assigned by the server.
+    pub(crate) fn bound_jid(&self) -> &Jid {
+        &self.stream.jid
+    }
+
+    /// The remote address of the TCP connection.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Send a stanza.
+    pub(crate) async fn send_stanza<E: Into<Element>>(
+        &mut self,
+        stanza: E,
+    ) -> Result<(), XmppError> {
+        self.stream.send(Packet::Stanza(stanza.into())).await
+    }
+
+    /// End the stream, and wait for the server to do the same.
+    pub(crate) async fn end(mut self) -> Result<(), XmppError> {
+        self.stream.send(Packet::StreamEnd).await?;
+        while let Some(Ok(_)) = self.next().await {}
+        Ok(())
+    }
+}
+
+impl futures::Stream for Client {
+    type Item = Result<Element, XmppError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
+                    return Poll::Ready(Some(Ok(stanza)))
+                }
+                Poll::Ready(Some(Ok(Packet::Text(_)))) => {
+                    // Whitespace between stanzas
+                }
+                // Stream end, unexpected packets, and errors all end the stream
+                Poll::Ready(_) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Open a TCP connection for `config`.
+///
+/// If `config` gives resolve override addresses, those are tried in order, through the proxy if
+/// there is one, and nothing is resolved. If `config` gives a proxy, the proxy is asked to connect
+/// to the connect host or hostname, without an SRV lookup. If `config` gives a connect host or
+/// port, that address is connected to directly. Otherwise, the server's `_xmpp-client._tcp` SRV
+/// records, or `_xmpps-client._tcp` for direct TLS, are tried in order, falling back to the
+/// hostname on the transport's default port if there are none.
+async fn connect_tcp(config: &Config) -> Result<TcpStream, XmppError> {
+    let hostname = config.server.hostname();
+    let port = config.connect_port.or_else(|| config.server.port());
+    let (service, default_port) = match config.transport {
+        Transport::StartTls => ("_xmpp-client", DEFAULT_PORT),
+        Transport::DirectTls { port } => ("_xmpps-client", port),
+    };
+    if !config.resolve_override.is_empty() {
+        return connect_addrs(config, &config.resolve_override).await;
+    }
+    if let Some(proxy) = &config.proxy {
+        let host = config.connect_host.as_deref().unwrap_or(hostname);
+        let port = port.unwrap_or(default_port);
+        debug!("connecting to {}:{} via proxy", host, port);
+        return Ok(proxy::connect(proxy, host, port).await?);
+    }
+    if config.connect_host.is_some() || port.is_some() {
+        let host = config.connect_host.as_deref().unwrap_or(hostname);
+        let port = port.unwrap_or(default_port);
+        debug!("connecting to {}:{}", host, port);
+        return connect_host(host, port, config.address_family).await;
+    }
+
+    if hostname.parse::<std::net::IpAddr>().is_ok() {
+        return connect_host(hostname, default_port, config.address_family).await;
+    }
+
+    let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(ConnecterError::Resolve)?;
+    let srv_name = format!("{}._tcp.{}.", service, hostname);
+    match resolver.srv_lookup(srv_name).await {
+        Ok(lookup) => {
+            for srv in lookup.iter() {
+                let target = srv.target().to_ascii();
+                debug!("connecting to {}:{} via SRV", target, srv.port());
+                let target = target.trim_end_matches('.');
+                if let Ok(stream) = connect_host(target, srv.port(), config.address_family).await {
+                    return Ok(stream);
+                }
+            }
+            Err(XmppError::Disconnected)
+        }
+        Err(e) => {
+            debug!(
+                "SRV lookup for {} failed ({}); connecting directly",
+                hostname, e
+            );
+            connect_host(hostname, default_port, config.address_family).await
+        }
+    }
+}
+
+/// Connect to each of `addrs` in turn, through `config.proxy` if set, returning the first
+/// successful connection.
+async fn connect_addrs(config: &Config, addrs: &[SocketAddr]) -> Result<TcpStream, XmppError> {
+    let addrs = match config.proxy {
+        Some(_) => addrs.to_vec(),
+        None => config.address_family.sort(addrs.to_vec()),
+    };
+    if addrs.is_empty() {
+        return Err(no_addresses("resolve_override", config.address_family));
+    }
+
+    let mut last_error = None;
+    for addr in &addrs {
+        let result = match &config.proxy {
+            Some(proxy) => {
+                debug!("connecting to {} via proxy", addr);
+                proxy::connect(proxy, &addr.ip().to_string(), addr.port()).await
+            }
+            None => {
+                debug!("connecting to {}", addr);
+                TcpStream::connect(addr).await
+            }
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("connecting to {} failed: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .map(XmppError::from)
+        .unwrap_or(XmppError::Disconnected))
+}
+
+/// Connect to each address of `host` in the order `family` gives, returning the first successful
+/// connection.
+async fn connect_host(
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+) -> Result<TcpStream, XmppError> {
+    let addrs = family.sort(tokio::net::lookup_host((host, port)).await?.collect());
+    if addrs.is_empty() {
+        return Err(no_addresses(host, family));
+    }
+
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("connecting to {} failed: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .map(XmppError::from)
+        .unwrap_or(XmppError::Disconnected))
+}
+
+/// `what` has no addresses which `family` allows.
+fn no_addresses(what: &str, family: AddressFamily) -> XmppError {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} has no addresses allowed by {:?}", what, family),
+    )
+    .into()
+}
+
+/// Negotiate TLS, validating the server's certificate as `config` specifies.
+async fn starttls(
+    mut stream: XMPPStream<TcpStream>,
+    config: &Config,
+) -> Result<TlsStream, XmppError> {
+    stream
+        .send(Packet::Stanza(
+            Element::builder("starttls", ns::TLS).build(),
+        ))
+        .await?;
+
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.name() == "proceed" => break,
+            Some(Ok(Packet::Text(_))) => {}
+            Some(Err(e)) => return Err(e),
+            _ => return Err(ProtocolError::NoTls.into()),
+        }
+    }
+
+    tls_connect(stream.into_inner(), config).await
+}
+
+#[cfg(feature = "tls-native")]
+async fn tls_connect(stream: TcpStream, config: &Config) -> Result<TlsStream, XmppError> {
+    if let Some(connector) = &config.tls_connector {
+        let connector = tokio_native_tls::TlsConnector::from(connector.inner.as_ref().clone());
+        return Ok(connector.connect(config.tls_domain(), stream).await?);
+    }
+
+    // Pinned certificates are checked after the handshake instead
+    let pinned = !config.pinned_certificates.is_empty();
+
+    let mut builder = tokio_native_tls::native_tls::TlsConnector::builder();
+    for certificate in &config.root_certificates {
+        builder.add_root_certificate(tokio_native_tls::native_tls::Certificate::from_der(
+            certificate,
+        )?);
+    }
+    if pinned || config.accept_invalid_certs() {
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let tls_stream = tokio_native_tls::TlsConnector::from(builder.build()?)
+        .connect(config.tls_domain(), stream)
+        .await?;
+
+    if pinned {
+        let certificate = match tls_stream.get_ref().peer_certificate()? {
+            Some(certificate) => certificate.to_der()?,
+            None => return Err(CertificateMismatch.into()),
+        };
+        if !config.pinned_certificates.contains(&certificate) {
+            return Err(CertificateMismatch.into());
+        }
+    }
+    Ok(tls_stream)
+}
+
+#[cfg(feature = "tls-rustls")]
+async fn tls_connect(stream: TcpStream, config: &Config) -> Result<TlsStream, XmppError> {
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio_rustls::rustls;
+
+    /// Accepts every certificate.
+    struct AcceptInvalidCerts;
+
+    impl rustls::client::ServerCertVerifier for AcceptInvalidCerts {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &rustls::Certificate,
+            _intermediates: &[rustls::Certificate],
+            _server_name: &rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        }
+    }
+
+    /// Accepts only the pinned certificates, noting when another is presented.
+    struct PinnedCerts {
+        pins: Vec<Vec<u8>>,
+        mismatch: Arc<AtomicBool>,
+    }
+
+    impl rustls::client::ServerCertVerifier for PinnedCerts {
+        fn verify_server_cert(
+            &self,
+            end_entity: &rustls::Certificate,
+            _intermediates: &[rustls::Certificate],
+            _server_name: &rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+            if self.pins.contains(&end_entity.0) {
+                Ok(rustls::client::ServerCertVerified::assertion())
+            } else {
+                self.mismatch.store(true, Ordering::SeqCst);
+                Err(rustls::Error::InvalidCertificateData(
+                    CertificateMismatch.to_string(),
+                ))
+            }
+        }
+    }
+
+    if let Some(connector) = &config.tls_connector {
+        let domain = rustls::ServerName::try_from(config.tls_domain())?;
+        let connector = tokio_rustls::TlsConnector::from(connector.inner.clone());
+        return Ok(connector.connect(domain, stream).await?);
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    for certificate in &config.root_certificates {
+        roots
+            .add(&rustls::Certificate(certificate.clone()))
+            .map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+    }
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let mismatch = Arc::new(AtomicBool::new(false));
+    if !config.pinned_certificates.is_empty() {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedCerts {
+                pins: config.pinned_certificates.clone(),
+                mismatch: mismatch.clone(),
+            }));
+    } else if config.accept_invalid_certs() {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptInvalidCerts));
+    }
+
+    let domain = rustls::ServerName::try_from(config.tls_domain())?;
+    let tls_stream = tokio_rustls::TlsConnector::from(Arc::new(tls_config))
+        .connect(domain, stream)
+        .await
+        .map_err(|e| {
+            if mismatch.load(Ordering::SeqCst) {
+                CertificateMismatch.into()
+            } else {
+                XmppError::from(e)
+            }
+        })?;
+    Ok(tls_stream)
+}
+
+/// Authenticate using the preferred SASL mechanism offered by the server.
+async fn auth<S>(
+    mut stream: XMPPStream<S>,
+    credentials: Credentials,
+    policy: &SaslPolicy,
+) -> Result<S, XmppError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use xmpp_parsers::sasl::{
+        Auth, Challenge, Failure, Mechanism as XmppMechanism, Response, Success,
+    };
+
+    let offered: Vec<String> = stream.stream_features.sasl_mechanisms()?.collect();
+    let mut mechanism: Box<dyn Mechanism> = match (policy, policy.choose(&offered)) {
+        (SaslPolicy::Anonymous, _) if offered.iter().any(|o| o == "ANONYMOUS") => {
+            Box::new(Anonymous::new())
+        }
+        (_, Some(SaslMechanism::ScramSha256)) => {
+            Box::new(Scram::<Sha256>::from_credentials(credentials).map_err(AuthError::Sasl)?)
+        }
+        (_, Some(SaslMechanism::ScramSha1)) => {
+            Box::new(Scram::<Sha1>::from_credentials(credentials).map_err(AuthError::Sasl)?)
+        }
+        (_, Some(SaslMechanism::Plain)) => {
+            Box::new(Plain::from_credentials(credentials).map_err(AuthError::Sasl)?)
+        }
+        (_, None) => return Err(NoMechanism(offered).into()),
+    };
+    debug!("authenticating with {}", mechanism.name());
+
+    let name: XmppMechanism = mechanism.name().parse().map_err(ProtocolError::Parsers)?;
+    stream
+        .send(Packet::Stanza(
+            Auth {
+                mechanism: name,
+                data: mechanism.initial(),
+            }
+            .into(),
+        ))
+        .await?;
+
+    loop {
+        let stanza = match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+            None => return Err(XmppError::Disconnected),
+        };
+
+        if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
+            let data = mechanism
+                .response(&challenge.data)
+                .map_err(AuthError::Sasl)?;
+            stream
+                .send(Packet::Stanza(Response { data }.into()))
+                .await?;
+        } else if let Ok(success) = Success::try_from(stanza.clone()) {
+            mechanism.success(&success.data).map_err(AuthError::Sasl)?;
+            return Ok(stream.into_inner());
+        } else if let Ok(failure) = Failure::try_from(stanza) {
+            return Err(AuthError::Fail(failure.defined_condition).into());
+        }
+    }
+}
+
+/// Bind the resource from the stream's JID, if the server supports binding.
+async fn bind<S>(mut stream: XMPPStream<S>) -> Result<XMPPStream<S>, XmppError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use xmpp_parsers::bind::{BindQuery, BindResponse};
+    use xmpp_parsers::iq::{Iq, IqType};
+
+    const BIND_ID: &str = "resource-bind";
+
+    if !stream.stream_features.can_bind() {
+        return Ok(stream);
+    }
+
+    let resource = match &stream.jid {
+        Jid::Full(jid) => Some(jid.resource.clone()),
+        Jid::Bare(_) => None,
+    };
+    stream
+        .send(Packet::Stanza(
+            Iq::from_set(BIND_ID, BindQuery::new(resource)).into(),
+        ))
+        .await?;
+
+    loop {
+        let stanza = match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+            None => return Err(XmppError::Disconnected),
+        };
+
+        match Iq::try_from(stanza) {
+            Ok(iq) if iq.id == BIND_ID => match iq.payload {
+                IqType::Result(payload) => {
+                    if let Some(bind) = payload.and_then(|p| BindResponse::try_from(p).ok()) {
+                        stream.jid = bind.into();
+                    }
+                    return Ok(stream);
+                }
+                _ => return Err(ProtocolError::InvalidBindResponse.into()),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// A scripted XMPP server for tests, speaking direct TLS with the `localhost` certificate from
+/// `tests/data`.
+#[cfg(test)]
+pub(crate) mod test_server {
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    pub(crate) const CERT_DER: &[u8] = include_bytes!("../tests/data/cert.der");
+
+    /// A configuration for connecting to a test server listening on `addr`, which trusts its
+    /// `localhost` certificate.
+    pub(crate) fn config(addr: std::net::SocketAddr) -> crate::ConfigBuilder {
+        crate::Config::builder("user", "pass")
+            .server(crate::Server::Custom("localhost".into()))
+            .resolve_override(vec![addr])
+            .transport(crate::Transport::DirectTls { port: 5223 })
+            .pinned_certificates(vec![CERT_DER.to_vec()])
+    }
+
+    /// Accept a TLS connection using the `localhost` certificate from `tests/data`.
+    #[cfg(feature = "tls-native")]
+    pub(crate) async fn tls_accept(
+        stream: TcpStream,
+    ) -> Option<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> {
+        use tokio_native_tls::native_tls;
+
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../tests/data/cert.pem"),
+            include_bytes!("../tests/data/key.pem"),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        // The client may abort the handshake
+        tokio_native_tls::TlsAcceptor::from(acceptor)
+            .accept(stream)
+            .await
+            .ok()
+    }
+
+    /// Accept a TLS connection using the `localhost` certificate from `tests/data`.
+    #[cfg(feature = "tls-rustls")]
+    pub(crate) async fn tls_accept(
+        stream: TcpStream,
+    ) -> Option<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> {
+        use tokio_rustls::rustls;
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(CERT_DER.to_vec())],
+                rustls::PrivateKey(include_bytes!("../tests/data/key.der").to_vec()),
+            )
+            .unwrap();
+        // The client may abort the handshake
+        tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))
+            .accept(stream)
+            .await
+            .ok()
+    }
+
+    /// Read from `stream` until what has been read satisfies `done`.
+    pub(crate) async fn read_until<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        done: impl Fn(&str) -> bool,
+    ) -> String {
+        let mut received = Vec::new();
+        while !done(&String::from_utf8_lossy(&received)) {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "the client hung up");
+            received.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(received).unwrap()
+    }
+
+    async fn stream_header<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, features: &str) {
+        read_until(stream, |r| {
+            r.contains("<stream:stream") && r.trim_end().ends_with('>')
+        })
+        .await;
+        let header = format!("<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='1' from='localhost' version='1.0'><stream:features>{}</stream:features>", features);
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    /// Accept a client, authenticate it with `PLAIN`, and bind `user@localhost/res`, returning
+    /// the connection before the client asks to join its room.
+    pub(crate) async fn authenticate(stream: TcpStream) -> impl AsyncRead + AsyncWrite + Unpin {
+        let mut stream = tls_accept(stream).await.expect("TLS handshake");
+
+        stream_header(&mut stream, "<mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>PLAIN</mechanism></mechanisms>").await;
+        read_until(&mut stream, |r| r.contains("</auth>")).await;
+        stream
+            .write_all(b"<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+            .await
+            .unwrap();
+
+        stream_header(
+            &mut stream,
+            "<bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>",
+        )
+        .await;
+        read_until(&mut stream, |r| r.contains("</iq>")).await;
+        stream.write_all(b"<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>user@localhost/res</jid></bind></iq>").await.unwrap();
+        stream
+    }
+
+    /// Read a presence stanza from the client, returning it.
+    pub(crate) async fn presence<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        read_until(stream, |r| r.contains("</presence>")).await
+    }
+
+    /// Like `authenticate()`, and let the client join its room, returning the connection once
+    /// the join is confirmed.
+    pub(crate) async fn join(stream: TcpStream) -> impl AsyncRead + AsyncWrite + Unpin {
+        let mut stream = authenticate(stream).await;
+        presence(&mut stream).await;
+        stream.write_all(b"<presence from='nwws@conference.nwws-oi.weather.gov/user/res'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>").await.unwrap();
+        stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{tls_accept, CERT_DER};
+
+    #[tokio::test]
+    async fn explicit_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = Config {
+            server: Server::CustomWithPort("127.0.0.1".into(), addr.port()),
+            ..Config::from(("user", "pass"))
+        };
+        let (stream, accepted) = tokio::join!(connect_tcp(&config), listener.accept());
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn connect_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("nwws.invalid".into()))
+            .connect_addr(addr)
+            .build();
+        let (stream, accepted) = tokio::join!(connect_tcp(&config), listener.accept());
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn connect_host() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The XMPP domain does not resolve, so this only works if the override is used
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("nwws.invalid".into()))
+            .connect_host("127.0.0.1")
+            .connect_port(addr.port())
+            .build();
+        assert_eq!(
+            config.jid(),
+            format!("user@nwws.invalid/{}", config.resource)
+        );
+        let (stream, accepted) = tokio::join!(connect_tcp(&config), listener.accept());
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn resolve_override() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Nothing listens on a port which was just released
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // The override takes precedence over the connect host, which does not resolve
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("nwws.invalid".into()))
+            .connect_host("nwws.invalid")
+            .resolve_override(vec![refused, addr])
+            .build();
+        assert_eq!(
+            config.jid(),
+            format!("user@nwws.invalid/{}", config.resource)
+        );
+        let (stream, accepted) = tokio::join!(connect_tcp(&config), listener.accept());
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+
+        let config = Config::builder("user", "pass")
+            .resolve_override(vec![refused])
+            .build();
+        assert!(connect_tcp(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_assigned_resource() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let jid: Jid = "user@localhost/requested".parse().unwrap();
+        let client = async {
+            let stream = XMPPStream::start(client, jid, ns::JABBER_CLIENT.to_owned()).await?;
+            bind(stream).await
+        };
+        let server = async {
+            server.write_all(b"<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='1' from='localhost' version='1.0'><stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>").await.unwrap();
+            let mut received = Vec::new();
+            while !String::from_utf8_lossy(&received).contains("</iq>") {
+                let mut buf = [0; 1024];
+                let n = server.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            let received = String::from_utf8(received).unwrap();
+            assert!(
+                received.contains("<resource>requested</resource>"),
+                "{}",
+                received
+            );
+
+            // The server assigns a different resource
+            server.write_all(b"<iq type='result' id='resource-bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>user@localhost/assigned</jid></bind></iq>").await.unwrap();
+            server
+        };
+        let (stream, _server) = tokio::join!(client, server);
+        assert_eq!(stream.unwrap().jid.to_string(), "user@localhost/assigned");
+    }
+
+    /// Authenticate as a client would, against a server offering `mechanisms` and accepting
+    /// whatever it is sent, returning the auth request.
+    async fn auth_with(policy: SaslPolicy, mechanisms: &str) -> (Result<(), XmppError>, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let jid: Jid = "localhost/res".parse().unwrap();
+        let client = async {
+            let stream = XMPPStream::start(client, jid, ns::JABBER_CLIENT.to_owned()).await?;
+            let credentials = Credentials::default()
+                .with_username("user")
+                .with_password("pass")
+                .with_channel_binding(ChannelBinding::None);
+            auth(stream, credentials, &policy).await.map(|_| ())
+        };
+        let features = format!("<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='1' from='localhost' version='1.0'><stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>{}</mechanisms></stream:features>", mechanisms);
+        let server = async {
+            server.write_all(features.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            loop {
+                let mut buf = [0; 1024];
+                let n = server.read(&mut buf).await.unwrap_or(0);
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&received);
+                if text.contains("<auth") && (text.ends_with("/>") || text.ends_with("</auth>")) {
+                    server
+                        .write_all(b"<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+                        .await
+                        .unwrap();
+                    break;
+                }
+            }
+            let received = String::from_utf8(received).unwrap();
+            received[received.find("<auth").unwrap_or(received.len())..].to_string()
+        };
+        tokio::join!(client, server)
+    }
+
+    #[tokio::test]
+    async fn auth_anonymous() {
+        let (result, request) = auth_with(
+            SaslPolicy::Anonymous,
+            "<mechanism>PLAIN</mechanism><mechanism>ANONYMOUS</mechanism>",
+        )
+        .await;
+        result.unwrap();
+        assert!(request.contains("mechanism=\"ANONYMOUS\""), "{}", request);
+
+        // The server does not allow anonymous users
+        let (result, request) =
+            auth_with(SaslPolicy::Anonymous, "<mechanism>PLAIN</mechanism>").await;
+        assert_eq!(
+            super::offered_mechanisms(&result.unwrap_err()),
+            Some(&["PLAIN".to_string()][..])
+        );
+        assert_eq!(request, "");
+
+        // Anonymous authentication is never chosen otherwise
+        let (result, _) = auth_with(SaslPolicy::Any, "<mechanism>ANONYMOUS</mechanism>").await;
+        assert!(super::offered_mechanisms(&result.unwrap_err()).is_some());
+    }
+
+    #[test]
+    fn offered_mechanisms() {
+        let offered = vec!["PLAIN".to_string()];
+        let e = XmppError::from(NoMechanism(offered.clone()));
+        assert_eq!(super::offered_mechanisms(&e), Some(&offered[..]));
+        assert!(!is_certificate_mismatch(&e));
+        assert_eq!(super::offered_mechanisms(&CertificateMismatch.into()), None);
+        assert_eq!(super::offered_mechanisms(&XmppError::Disconnected), None);
+    }
+
+    #[tokio::test]
+    async fn address_family() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // IPv4 goes first, so the IPv6 address is never tried
+        let v6: SocketAddr = "[2001:db8::1]:5222".parse().unwrap();
+        let config = Config::builder("user", "pass")
+            .resolve_override(vec![v6, addr])
+            .address_family(AddressFamily::PreferV4)
+            .build();
+        let (stream, accepted) = tokio::join!(connect_tcp(&config), listener.accept());
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+
+        // Nothing is left to try
+        let config = Config::builder("user", "pass")
+            .connect_host("127.0.0.1")
+            .address_family(AddressFamily::V6Only)
+            .build();
+        let error = connect_tcp(&config).await.unwrap_err().to_string();
+        assert!(error.contains("127.0.0.1 has no addresses"), "{}", error);
+    }
+
+    /// Negotiate TLS with a local server.
+    async fn tls_handshake(config: &Config) -> Result<TlsStream, XmppError> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = async { tls_connect(TcpStream::connect(addr).await.unwrap(), config).await };
+        let server = async { tls_accept(listener.accept().await.unwrap().0).await };
+        tokio::join!(client, server).0
+    }
+
+    #[tokio::test]
+    async fn direct_tls() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = test_server::config(addr).build();
+
+        // The server speaks TLS from the first byte, and the XMPP stream starts inside it
+        let client = Client::connect(&config, "pass".into());
+        let server = async {
+            let mut stream = tls_accept(listener.accept().await.unwrap().0)
+                .await
+                .expect("TLS handshake");
+            let mut header = [0; 14];
+            stream.read_exact(&mut header).await.unwrap();
+            header
+        };
+        let (client, header) = tokio::join!(client, server);
+        assert_eq!(&header, b"<stream:stream");
+        // The server hung up without answering
+        assert!(client.is_err());
+    }
+
+    const CA_DER: &[u8] = include_bytes!("../tests/data/ca.der");
+
+    /// A connector which trusts only the test CA, or nothing at all.
+    #[cfg(feature = "tls-native")]
+    fn connector(trust_test_ca: bool) -> TlsConnector {
+        use tokio_native_tls::native_tls;
+
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.disable_built_in_roots(true);
+        if trust_test_ca {
+            builder.add_root_certificate(native_tls::Certificate::from_der(CA_DER).unwrap());
+        }
+        builder.build().unwrap().into()
+    }
+
+    /// A connector which trusts only the test CA, or nothing at all.
+    #[cfg(feature = "tls-rustls")]
+    fn connector(trust_test_ca: bool) -> TlsConnector {
+        use tokio_rustls::rustls;
+
+        let mut roots = rustls::RootCertStore::empty();
+        if trust_test_ca {
+            roots.add(&rustls::Certificate(CA_DER.to_vec())).unwrap();
+        }
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn tls_connector() {
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("localhost".into()))
+            .build();
+        assert!(tls_handshake(&config).await.is_err());
+
+        // The custom root store is honored
+        let config = Config {
+            tls_connector: Some(connector(true)),
+            ..config
+        };
+        assert!(tls_handshake(&config).await.is_ok());
+
+        // The connector takes precedence over accepting invalid certificates
+        let config = Config {
+            tls_connector: Some(connector(false)),
+            danger_accept_invalid_certs: true,
+            ..config
+        };
+        assert!(tls_handshake(&config).await.is_err());
+        assert!(tls_handshake(&Config {
+            tls_connector: None,
+            ..config
+        })
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate() {
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("localhost".into()))
+            .pinned_certificates(vec![b"another certificate".to_vec(), CERT_DER.to_vec()])
+            .build();
+        assert!(tls_handshake(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_mismatch() {
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("localhost".into()))
+            .pinned_certificates(vec![b"another certificate".to_vec()])
+            .build();
+        let error = tls_handshake(&config).await.err().unwrap();
+        assert!(is_certificate_mismatch(&error), "{:?}", error);
+
+        // Without pins, the certificate from the test CA is refused for the usual reasons
+        let config = Config {
+            pinned_certificates: vec![],
+            ..config
+        };
+        let error = tls_handshake(&config).await.err().unwrap();
+        assert!(!is_certificate_mismatch(&error), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn root_certificates() {
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("localhost".into()))
+            .root_certificates(vec![CA_DER.to_vec()])
+            .build();
+        assert!(tls_handshake(&config).await.is_ok());
+
+        // The certificate must still match the domain
+        let config = Config {
+            tls_domain: Some("nwws-oi.weather.gov".into()),
+            ..config
+        };
+        assert!(tls_handshake(&config).await.is_err());
+
+        // Garbage is refused rather than ignored
+        let config = Config {
+            tls_domain: None,
+            root_certificates: vec![b"not a certificate".to_vec()],
+            ..config
+        };
+        assert!(tls_handshake(&config).await.is_err());
+    }
+
+    #[cfg(feature = "tls-rustls")]
+    #[tokio::test]
+    async fn tls_domain_invalid() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let config = Config::builder("user", "pass")
+            .tls_domain("not a domain!")
+            .build();
+        assert!(matches!(
+            tls_connect(stream, &config).await,
+            Err(XmppError::DnsNameError(_))
+        ));
+    }
+}
@@ -1,14 +1,61 @@
+use crate::logging::{debug, error, info, trace, warn};
 use crate::*;
 use futures::{StreamExt, TryStreamExt};
-use log::{debug, error, info, log_enabled, trace, warn, Level};
+use std::time::{Duration, Instant};
+
+/// The features advertised in response to disco#info queries
+/// ([XEP-0030](https://xmpp.org/extensions/xep-0030.html)).
+///
+/// Whatever `iq_response()` answers, and whatever else the server or room can rely on, belongs
+/// here.
+const FEATURES: &[&str] = &[
+    "http://jabber.org/protocol/disco#info",
+    "http://jabber.org/protocol/muc",
+    "jabber:iq:version",
+    "urn:xmpp:ping",
+];
 
 /// A connection to NWWS-OI.
 ///
 /// `Connection` is 1:1 with an underlying XMPP connection. Failures are generally unrecoverable.
 /// Most users will prefer to use [`Stream`](struct.Stream.html) instead.
+///
+/// A connection which is silently dropped, e.g. by a NAT gateway, would otherwise wait forever.
+/// While waiting for messages, `Connection` sends XMPP pings
+/// ([XEP-0199](https://xmpp.org/extensions/xep-0199.html)) every
+/// [`Config::ping_interval`](struct.Config.html#structfield.ping_interval) of silence. It gives up
+/// with `Err(Error::PingTimeout)` if nothing at all, including a reply, arrives within
+/// [`Config::ping_timeout`](struct.Config.html#structfield.ping_timeout) of a ping, and with
+/// `Err(Error::Idle)` if no product arrives within
+/// [`Config::idle_timeout`](struct.Config.html#structfield.idle_timeout). `Stream` then reconnects.
 pub struct Connection {
-    client: tokio_xmpp::SimpleClient,
-    leave_message: xmpp_parsers::Element,
+    client: client::Client,
+    info: ConnectionInfo,
+    stats: ConnectionStats,
+    /// One for each joined room, to send when ending the connection
+    leave_messages: Vec<xmpp_parsers::Element>,
+    /// Stanzas received while joining other rooms, to be read before the socket
+    pending: std::collections::VecDeque<xmpp_parsers::Element>,
+    keepalive: Keepalive,
+    max_message_bytes: Option<usize>,
+    oversize: Oversize,
+    parse_mode: ParseMode,
+    software_version: SoftwareVersion,
+    iq_handler: Option<std::sync::Arc<dyn IqHandler>>,
+    roster: presence::Roster,
+    /// The room, and our nickname in it
+    channel: jid::Jid,
+    room: jid::BareJid,
+    /// Every room currently joined, starting with `room` unless it was left
+    rooms: Vec<jid::BareJid>,
+    /// Rooms left with `leave_channel()` which have not yet confirmed it, whose stanzas may still
+    /// be on their way
+    left_rooms: Vec<jid::BareJid>,
+    /// What `join_channel()` joins with
+    nickname: String,
+    channel_password: Option<String>,
+    history: History,
+    join_timeout: Option<Duration>,
 }
 
 impl Connection {
@@ -17,173 +64,2376 @@ impl Connection {
     /// `new()` returns `Ok(Connection)` once the XMPP connection is established, authenticated, and
     /// joined to the NWWS MUC. If any of these steps fail, it returns `Err(Error)`.
     pub async fn new<C: Into<Config>>(config: C) -> Result<Self> {
-        let config = config.into();
-        let jid = config.jid();
-        let Config {
-            username,
-            resource,
-            password,
-            channel,
-            ..
-        } = config;
-        let nickname = format!("{}/{}", username, resource);
+        Self::authenticate(config.into()).await?.join().await
+    }
+
+    /// Connect to NWWS-OI, unless `cancelled` completes first.
+    ///
+    /// Behaves like [`new()`](#method.new), except that it returns `Err(Error::Cancelled)` once
+    /// `cancelled` completes. Dropping the future returned by `new()` while it joins the room can
+    /// leave a half-joined occupant behind until the server notices it is gone. Cancelling this
+    /// way instead sends unavailable presence to each room it started joining, and ends the
+    /// stream, before returning.
+    ///
+    /// `cancelled` can be any future, e.g. `CancellationToken::cancelled()` from `tokio-util`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # async fn example() {
+    /// let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+    /// let connecting = tokio::task::spawn_local(nwws_oi::Connection::new_cancellable(
+    ///     ("user".to_string(), "pass".to_string()),
+    ///     cancelled,
+    /// ));
+    ///
+    /// // Changed our mind
+    /// cancel.send(()).ok();
+    /// assert!(matches!(
+    ///     connecting.await.unwrap(),
+    ///     Err(nwws_oi::Error::Cancelled)
+    /// ));
+    /// # }
+    /// ```
+    pub async fn new_cancellable<C, F>(config: C, cancelled: F) -> Result<Self>
+    where
+        C: Into<Config>,
+        F: std::future::Future,
+    {
+        let cancelled = async {
+            cancelled.await;
+        };
+        let authenticating = Self::authenticate(config.into());
+        futures::pin_mut!(authenticating, cancelled);
+        match futures::future::select(authenticating, cancelled).await {
+            futures::future::Either::Left((authenticated, cancelled)) => {
+                authenticated?.join_or_cancel(cancelled).await
+            }
+            // Nothing has been joined yet, so dropping the connection leaves nothing behind
+            futures::future::Either::Right(((), _)) => Err(Error::Cancelled),
+        }
+    }
+
+    /// Establish and authenticate the XMPP connection, without joining the MUC yet.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "connect", skip_all, fields(server = %config.server.hostname()))
+    )]
+    pub(crate) async fn authenticate(config: Config) -> Result<Authenticated> {
+        let nickname = config.nickname()?;
+        if config.sasl == SaslPolicy::Anonymous
+            && matches!(config.server, Server::Primary | Server::Backup)
+        {
+            return Err(Error::AnonymousNotAllowed);
+        }
+        let password = config.password().await.map_err(|e| {
+            error!("getting the password failed: {}", e);
+            e
+        })?;
 
         // Connect
         info!("connecting to {}", &config.server.hostname());
-        let mut client = tokio_xmpp::SimpleClient::new(&jid, password)
+        let connecting = client::Client::connect(&config, password);
+        let connected = match config
+            .handshake_timeout
+            .filter(|timeout| !timeout.is_zero())
+        {
+            Some(timeout) => tokio::time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| {
+                    error!("the XMPP handshake timed out after {:?}", timeout);
+                    Error::HandshakeTimeout
+                })?,
+            None => connecting.await,
+        };
+        let client = connected.map_err(|e| {
+            error!("connection failed: {}", e);
+            if let Some(offered) = client::offered_mechanisms(&e) {
+                return Error::SaslMechanism(offered.to_vec());
+            }
+            match e {
+                tokio_xmpp::Error::JidParse(_) => Error::Configuration(e),
+                // The TLS domain is not a valid DNS name
+                #[cfg(feature = "tls-rustls")]
+                tokio_xmpp::Error::DnsNameError(_) => Error::Configuration(e),
+                tokio_xmpp::Error::Auth(_) => Error::Credentials(e),
+                _ if client::is_certificate_mismatch(&e) => Error::CertificateMismatch,
+                _ if proxy::is_refused(&e) => Error::Configuration(e),
+                _ => Error::Network(e),
+            }
+        })?;
+        debug!("connected as {}", client.bound_jid());
+
+        Ok(Authenticated {
+            client,
+            config,
+            nickname,
+        })
+    }
+
+    /// Connect to NWWS-OI, giving up after `config.connect_timeout`.
+    ///
+    /// Behaves like [`new()`](#method.new), except that it returns `Err(Error::Timeout)` if the
+    /// connection is not established, authenticated, and joined within the configured timeout.
+    pub async fn new_with_timeout<C: Into<Config>>(config: C) -> Result<Self> {
+        let config = config.into();
+        within(connect_deadline(&config), Self::new(config)).await
+    }
+
+    /// The full JID this connection is bound to.
+    ///
+    /// The resource is normally `Config::resource`, but the server may assign a different one,
+    /// e.g. if another connection is already using it.
+    pub fn bound_jid(&self) -> &jid::Jid {
+        self.client.bound_jid()
+    }
+
+    /// An alias of [`bound_jid()`](#method.bound_jid).
+    #[doc(hidden)]
+    pub fn jid(&self) -> &jid::Jid {
+        self.bound_jid()
+    }
+
+    /// The JID this connection joined the MUC room as: the room, with our nickname as its
+    /// resource, e.g. `NWWS@conference.nwws-oi.weather.gov/user/res`.
+    ///
+    /// The nickname is normally `Config::nickname`, or `"{username}/{resource}"`, but it has a
+    /// suffix like `-2` if that nickname was already in use.
+    pub fn channel(&self) -> &jid::Jid {
+        &self.channel
+    }
+
+    /// Where this connection landed.
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// What this connection has received so far.
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// The server this connection is connected to, e.g. one of `Config::fallback_servers`.
+    ///
+    /// See [`ConnectionInfo::server`](struct.ConnectionInfo.html#structfield.server).
+    pub fn server(&self) -> &Server {
+        &self.info.server
+    }
+
+    /// The remote address of the underlying TCP connection.
+    ///
+    /// See [`ConnectionInfo::peer_addr`](struct.ConnectionInfo.html#structfield.peer_addr).
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.info.peer_addr
+    }
+
+    /// The participants currently in the NWWS MUC room, sorted by nickname.
+    ///
+    /// This includes this connection's own participant. It reflects the presence received so far,
+    /// so it is only current while the connection is being read, e.g. by
+    /// [`next_event()`](#method.next_event).
+    pub fn occupants(&self) -> Vec<Occupant> {
+        self.roster.occupants()
+    }
+
+    /// Join another MUC room over this connection, as if it were one of
+    /// `Config::additional_channels`.
+    ///
+    /// This waits up to `config.join_timeout` for the room to confirm the join, with the same
+    /// nickname, password, and history as the rooms joined when connecting. Joining a room which
+    /// is already joined does nothing. Stanzas which arrive in the meantime are kept for the
+    /// receiving methods, and [`end()`](#method.end) leaves this room along with the others.
+    pub async fn join_channel(&mut self, channel: Channel) -> Result<()> {
+        let room = channel.room();
+        if self.rooms.iter().any(|joined| same_room(joined, &room)) {
+            return Ok(());
+        }
+        let join = Join {
+            channel: &channel,
+            nickname: &self.nickname,
+            password: &self.channel_password,
+            history: &self.history,
+            join_timeout: self.join_timeout,
+        };
+        let joined = self.leave_messages.len();
+        match join
+            .run(
+                &mut self.client,
+                &mut self.pending,
+                &mut self.leave_messages,
+            )
             .await
-            .map_err(|e| {
-                error!("connection failed: {}", e);
-                match e {
-                    tokio_xmpp::Error::JidParse(_) => Error::Configuration(e),
-                    tokio_xmpp::Error::Auth(_) => Error::Credentials(e),
-                    _ => Error::Network(e),
+        {
+            Ok((channel_jid, roster)) => {
+                info!("joined channel {}", &channel_jid);
+                if same_room(&room, &self.room) {
+                    self.roster = roster;
                 }
-            })?;
-        let jid = client.bound_jid().clone();
-        debug!("connected as {}", &jid);
-
-        // Build the message to join the MUC
-        let channel_jid = channel.jid(nickname);
-        let join_message =
-            xmpp_parsers::presence::Presence::new(xmpp_parsers::presence::Type::None)
-                .with_from(jid.clone())
-                .with_to(channel_jid.clone())
-                .with_payloads(vec![xmpp_parsers::muc::Muc {
-                    password: None,
-                    history: Some(xmpp_parsers::muc::muc::History {
-                        maxchars: None,
-                        maxstanzas: None,
-                        seconds: Some(300),
-                        since: None,
-                    }),
+                self.left_rooms.retain(|left| !same_room(left, &room));
+                self.rooms.push(channel_jid.into());
+                Ok(())
+            }
+            Err(e) => {
+                self.leave_messages.truncate(joined);
+                Err(e)
+            }
+        }
+    }
+
+    /// Leave a MUC room, and wait up to `config.join_timeout` for it to confirm.
+    ///
+    /// Any room can be left, including `Config::channel`, in which case
+    /// [`occupants()`](#method.occupants) is empty from then on. Leaving a room which is not
+    /// joined does nothing. Products from the room which race in after this are ignored by
+    /// [`next_event()`](#method.next_event) and the methods built on it, though not by
+    /// [`next_element()`](#method.next_element) or [`next_stanza()`](#method.next_stanza).
+    ///
+    /// If the room does not confirm in time, this returns `Err(Error::LeaveTimeout)`, but the
+    /// room counts as left all the same.
+    pub async fn leave_channel(&mut self, channel: &Channel) -> Result<()> {
+        let room = channel.room();
+        let index = match self
+            .rooms
+            .iter()
+            .position(|joined| same_room(joined, &room))
+        {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        self.rooms.remove(index);
+        self.left_rooms.push(room.clone());
+        if same_room(&room, &self.room) {
+            self.roster = presence::Roster::default();
+        }
+
+        let leave_message = self
+            .leave_messages
+            .iter()
+            .position(|leave| stanza_room(leave, "to").map_or(false, |to| same_room(&to, &room)))
+            .map(|index| self.leave_messages.remove(index));
+        if let Some(leave_message) = leave_message {
+            self.client.send_stanza(leave_message).await?;
+            wait_for_leave(
+                &mut self.client,
+                self.join_timeout,
+                &room,
+                &mut self.pending,
+            )
+            .await?;
+        }
+        // Nothing more will come from the room, so only what was received before needs ignoring
+        self.left_rooms.retain(|left| !same_room(left, &room));
+        self.pending.retain(|element| {
+            !stanza_room(element, "from").map_or(false, |from| same_room(&from, &room))
+        });
+        info!("left channel {}", room);
+        Ok(())
+    }
+
+    /// Terminate the connection as gracefully as possible.
+    ///
+    /// Leaving the room and ending the stream are each given a couple of seconds, after which the
+    /// socket is simply closed, so a dead connection does not hold things up.
+    pub async fn end(self) -> EndOutcome {
+        self.end_with_timeout(END_STEP_TIMEOUT).await
+    }
+
+    /// Terminate the connection as gracefully as possible, giving each step up to `timeout`.
+    pub async fn end_with_timeout(self, timeout: Duration) -> EndOutcome {
+        end_client(self.client, self.leave_messages, timeout).await
+    }
+
+    /// Receive the next message from NWWS-OI.
+    ///
+    /// While waiting, `next_message()` sends pings every `config.ping_interval`. It returns
+    /// `Err(Error::PingTimeout)` if a ping goes unanswered for `config.ping_timeout`, and
+    /// `Err(Error::Idle)` if no product is received for `config.idle_timeout`.
+    ///
+    /// If the room's operators kick or ban this connection, `next_message()` returns
+    /// `Err(Error::RemovedFromRoom)` right away. If the room is destroyed or shut down, e.g. while
+    /// NWWS-OI restarts its conference service, it returns `Err(Error::RoomClosed)`.
+    ///
+    /// # Cancel safety
+    ///
+    /// Dropping the future while it waits for the server, e.g. with `tokio::time::timeout`, loses
+    /// nothing. Dropping it while it answers one of the server's queries or sends a ping may
+    /// leave that stanza unsent, which the server may take as a sign that the connection is dead.
+    /// [`next_message_timeout()`](#method.next_message_timeout) never gives up partway through.
+    pub async fn next_message(&mut self) -> Result<Message> {
+        loop {
+            if let ConnectionEvent::Message(msg) = self.next_event().await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Receive the next message from NWWS-OI, or `Ok(None)` if none arrives within `timeout`.
+    ///
+    /// Behaves like [`next_message()`](#method.next_message), except that it gives up only
+    /// between stanzas: a query or ping which is being handled when `timeout` elapses is finished
+    /// first, so the connection is left ready for the next call.
+    pub async fn next_message_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        let until = tokio::time::Instant::now() + timeout;
+        loop {
+            match self.next_event_until(Some(until)).await? {
+                Some(ConnectionEvent::Message(msg)) => return Ok(Some(msg)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Call `handler` with each message from NWWS-OI, until the connection fails.
+    ///
+    /// This is a loop over [`next_message()`](#method.next_message), which returns the error which
+    /// ended the connection. Non-fatal errors, such as oversize products, are logged. If
+    /// `handler` panics, the panic is logged, and the loop carries on with the next message.
+    ///
+    /// Messages are handled one at a time, and the connection is not read while `handler` runs,
+    /// so a slow handler delays answering the server's pings. Spawn longer work onto a task.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # async fn example() -> nwws_oi::Result<()> {
+    /// let connection = nwws_oi::Connection::new(("user", "pass")).await?;
+    /// let error = connection
+    ///     .run_with(|message| async move {
+    ///         println!("{} {}", message.ttaaii, message.cccc);
+    ///     })
+    ///     .await;
+    /// eprintln!("disconnected: {}", error);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_with<F, Fut>(mut self, handler: F) -> Error
+    where
+        F: FnMut(Message) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        source::run_with(&mut self, handler).await
+    }
+
+    /// Receive the next message or presence from NWWS-OI.
+    ///
+    /// Behaves like [`next_message()`](#method.next_message), except that it also returns
+    /// participants joining and leaving the room, and products which could not be parsed.
+    ///
+    /// Its cancel safety is the same as `next_message()`.
+    pub async fn next_event(&mut self) -> Result<ConnectionEvent> {
+        self.next_event_until(None)
+            .await
+            .map(|event| event.expect("no deadline"))
+    }
+
+    /// Receive the next event, or `None` if there is none before `until`.
+    async fn next_event_until(
+        &mut self,
+        until: Option<tokio::time::Instant>,
+    ) -> Result<Option<ConnectionEvent>> {
+        loop {
+            let element = match self.next_element_until(until).await? {
+                Some(element) => element,
+                None => return Ok(None),
+            };
+
+            if let Some(index) = stanza_room(&element, "from").and_then(|from| {
+                self.left_rooms
+                    .iter()
+                    .position(|room| same_room(room, &from))
+            }) {
+                if self_unavailable(&element, &self.left_rooms[index]) {
+                    debug!("channel {} confirmed the leave", self.left_rooms[index]);
+                    self.left_rooms.remove(index);
+                } else {
+                    trace!(
+                        "ignoring a stanza from {}, which was left",
+                        self.left_rooms[index]
+                    );
                 }
-                .into()]);
-        debug!("joining channel {}", &channel_jid);
-
-        // Build the message to leave the MUC
-        //   https://xmpp.org/extensions/xep-0045.html#bizrules-presence § 17.3.2
-        let leave_message =
-            xmpp_parsers::presence::Presence::new(xmpp_parsers::presence::Type::Unavailable)
-                .with_from(join_message.from.as_ref().unwrap().clone())
-                .with_to(join_message.to.as_ref().unwrap().clone())
-                .with_payloads(vec![xmpp_parsers::muc::Muc {
-                    password: None,
-                    history: None,
+            } else if let Some(e) = too_large(&element, self.max_message_bytes) {
+                warn!("{}", e);
+                if self.oversize == Oversize::Error {
+                    return Ok(Some(ConnectionEvent::Error(e)));
                 }
-                .into()])
-                .into();
+            } else if let Some(e) = self.rooms.iter().find_map(|room| left_room(&element, room)) {
+                error!("{}", e);
+                return Err(e);
+            } else if let Some(event) = ConnectionEvent::from_element(element, self.parse_mode) {
+                self.stats.record(&event, chrono::Utc::now());
+                if let ConnectionEvent::Message(message) = &event {
+                    #[cfg(not(feature = "tracing"))]
+                    trace!("received product {}", message.id);
+                    #[cfg(feature = "tracing")]
+                    trace!(id = %message.id, ttaaii = %message.ttaaii, cccc = %message.cccc, "received product");
+                }
+                match &event {
+                    ConnectionEvent::Presence(presence)
+                        if same_room(&presence.room, &self.room) =>
+                    {
+                        self.roster.update(presence, chrono::Utc::now())
+                    }
+                    _ => {}
+                }
+                return Ok(Some(event));
+            }
+        }
+    }
 
-        // Join the MUC, and wait for the join to complete
-        client.send_stanza(join_message).await?;
-        'wait_for_join: loop {
-            let item = client.try_next().await?.ok_or(Error::StreamEnded)?;
+    /// Receive the next stanza from NWWS-OI, unparsed.
+    ///
+    /// This is what [`next_event()`](#method.next_event) is built on, for stanzas which this
+    /// crate does not model. Every stanza is returned except IQs, which
+    /// [`next_stanza()`](#method.next_stanza) returns too: queries from the server are still
+    /// answered, and pings are still sent, so the connection stays healthy. Products are
+    /// counted towards `config.idle_timeout` and [`stats()`](#method.stats), but nothing else is
+    /// interpreted: leaving the room is not reported as an error, and
+    /// [`occupants()`](#method.occupants) is not updated.
+    ///
+    /// Its cancel safety is the same as `next_message()`.
+    pub async fn next_element(&mut self) -> Result<xmpp_parsers::Element> {
+        self.next_element_until(None)
+            .await
+            .map(|element| element.expect("no deadline"))
+    }
 
-            if let Ok(presence) = xmpp_parsers::presence::Presence::try_from(item.clone()) {
-                for payload in presence.payloads {
-                    if let Ok(muc_user) = xmpp_parsers::muc::MucUser::try_from(payload) {
-                        if muc_user
-                            .status
-                            .iter()
-                            .any(|s| s == &xmpp_parsers::muc::user::Status::SelfPresence)
-                        {
-                            break 'wait_for_join;
+    /// Receive the next stanza other than an IQ, or `None` if there is none before `until`.
+    async fn next_element_until(
+        &mut self,
+        until: Option<tokio::time::Instant>,
+    ) -> Result<Option<xmpp_parsers::Element>> {
+        loop {
+            match self.next_stanza_until(until).await? {
+                Some(element) if element.is("iq", "jabber:client") => {}
+                element => return Ok(element),
+            }
+        }
+    }
+
+    /// Receive the next stanza from NWWS-OI, unparsed, including IQs.
+    ///
+    /// Behaves like [`next_element()`](#method.next_element), except that IQs are returned as
+    /// well. Each IQ is returned after it has been answered, by
+    /// [`Config::iq_handler`](struct.Config.html#structfield.iq_handler) or the default
+    /// responder, so the answer must not be sent again. This also returns the results of this
+    /// connection's own pings.
+    ///
+    /// `next_stanza()` can be mixed with [`next_message()`](#method.next_message) and the other
+    /// receiving methods: each stanza is returned by whichever of them reads it.
+    ///
+    /// Its cancel safety is the same as `next_message()`.
+    pub async fn next_stanza(&mut self) -> Result<xmpp_parsers::Element> {
+        self.next_stanza_until(None)
+            .await
+            .map(|element| element.expect("no deadline"))
+    }
+
+    /// Receive the next stanza, answering it if it is an IQ, or `None` if there is none before
+    /// `until`.
+    async fn next_stanza_until(
+        &mut self,
+        until: Option<tokio::time::Instant>,
+    ) -> Result<Option<xmpp_parsers::Element>> {
+        let element = match self.read_stanza(until).await? {
+            Some(element) => element,
+            None => return Ok(None),
+        };
+
+        #[cfg(not(feature = "tracing"))]
+        let enabled = log::log_enabled!(log::Level::Trace);
+        #[cfg(feature = "tracing")]
+        let enabled = tracing::enabled!(tracing::Level::TRACE);
+        if enabled {
+            let mut xml = Vec::new();
+            element
+                .write_to(&mut std::io::Cursor::new(&mut xml))
+                .expect("encode");
+            let xml = String::from_utf8(xml).expect("UTF-8");
+            trace!("received: {}", xml);
+        }
+
+        if element.is("iq", "jabber:client") {
+            let iq = xmpp_parsers::iq::Iq::try_from(element.clone())?;
+            self.handle_iq(iq).await?;
+        } else if let Some(size) = product_size(&element) {
+            self.stats.bytes_received += size as u64;
+            self.keepalive.product_received(Instant::now());
+        }
+        Ok(Some(element))
+    }
+
+    /// Read the next stanza, or `None` if there is none before `until`.
+    ///
+    /// Only waiting for the server is cut short by `until`; keepalive pings are always sent.
+    async fn read_stanza(
+        &mut self,
+        until: Option<tokio::time::Instant>,
+    ) -> Result<Option<xmpp_parsers::Element>> {
+        if let Some(element) = self.pending.pop_front() {
+            return Ok(Some(element));
+        }
+        loop {
+            let keepalive = self.keepalive.deadline().map(tokio::time::Instant::from);
+            let deadline = match (keepalive, until) {
+                (Some(keepalive), Some(until)) => Some(keepalive.min(until)),
+                (keepalive, until) => keepalive.or(until),
+            };
+            let next = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, self.client.next()).await
+                {
+                    Ok(next) => next,
+                    Err(_) => {
+                        let now = tokio::time::Instant::now();
+                        if keepalive.map_or(false, |keepalive| keepalive <= now) {
+                            self.on_deadline().await?;
                         }
+                        if until.map_or(false, |until| until <= now) {
+                            return Ok(None);
+                        }
+                        continue;
                     }
-                }
+                },
+                None => self.client.next().await,
+            };
+
+            let element = next.ok_or(Error::StreamEnded)??;
+            self.keepalive.received(Instant::now());
+            return Ok(Some(element));
+        }
+    }
+
+    async fn on_deadline(&mut self) -> Result<()> {
+        match self.keepalive.action(Instant::now()) {
+            Some(KeepaliveAction::Ping) => {
+                let id = self.keepalive.ping_sent(Instant::now());
+                trace!("sending ping {}", id);
+                let ping = xmpp_parsers::iq::Iq::from_get(id, xmpp_parsers::ping::Ping)
+                    .with_to(self.keepalive.server.clone());
+                self.client.send_stanza(ping).await?;
+                Ok(())
             }
+            Some(KeepaliveAction::Idle) => {
+                warn!(
+                    "no product received for {:?}; disconnecting",
+                    self.keepalive.idle_timeout
+                );
+                Err(Error::Idle)
+            }
+            Some(KeepaliveAction::PingTimeout) => {
+                warn!(
+                    "no reply to a ping for {:?}; disconnecting",
+                    self.keepalive.ping_timeout
+                );
+                Err(Error::PingTimeout)
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_iq(&mut self, iq: xmpp_parsers::iq::Iq) -> Result<()> {
+        let handled = self
+            .iq_handler
+            .as_ref()
+            .and_then(|handler| handler.handle(&iq));
+        if let Some(response) = handled.or_else(|| iq_response(iq, &self.software_version)) {
+            self.client.send_stanza(response).await?;
         }
+        Ok(())
+    }
+}
+
+/// How long `Connection::end()` waits for each step of ending the connection.
+const END_STEP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many times to retry joining the room with a suffixed nickname, e.g. `user/res-2`, when
+/// the nickname is already in use.
+const NICKNAME_RETRIES: u32 = 3;
+
+/// A connection which has authenticated, but not yet joined the MUC.
+///
+/// `Stream` reports `ConnectionState::Authenticated` in between.
+pub(crate) struct Authenticated {
+    client: client::Client,
+    config: Config,
+    nickname: String,
+}
+
+impl Authenticated {
+    /// Join the MUC, and wait for the join to complete.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(jid = %self.client.bound_jid()))
+    )]
+    pub(crate) async fn join(self) -> Result<Connection> {
+        self.join_or_cancel(futures::future::pending()).await
+    }
+
+    /// Join the MUC like `join()`, unless `cancelled` completes first.
+    ///
+    /// Once cancelled, this leaves each room it started joining and ends the stream, then returns
+    /// `Err(Error::Cancelled)`.
+    pub(crate) async fn join_or_cancel<F>(self, cancelled: F) -> Result<Connection>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let Self {
+            mut client,
+            config,
+            nickname,
+        } = self;
+        let keepalive = Keepalive::new(&config, Instant::now());
+        let Config {
+            channel,
+            additional_channels,
+            channel_password,
+            history,
+            join_timeout,
+            max_message_bytes,
+            oversize,
+            parse_mode,
+            software_version,
+            iq_handler,
+            server,
+            ..
+        } = config;
+        let jid = client.bound_jid().clone();
+        let join = |channel| Join {
+            channel,
+            nickname: &nickname,
+            password: &channel_password,
+            history: &history,
+            join_timeout,
+        };
+
+        let mut pending = std::collections::VecDeque::new();
+        let mut leave_messages = Vec::new();
+        let joined = {
+            let joining = async {
+                let (channel_jid, roster) = join(&channel)
+                    .run(&mut client, &mut pending, &mut leave_messages)
+                    .await?;
+                let mut rooms = vec![channel_jid.clone().into()];
+                for channel in &additional_channels {
+                    // Rooms joined so far may start delivering before this one is joined
+                    let (channel_jid, _) = join(channel)
+                        .run(&mut client, &mut pending, &mut leave_messages)
+                        .await?;
+                    rooms.push(channel_jid.into());
+                }
+                Ok::<_, Error>((channel_jid, roster, rooms))
+            };
+            futures::pin_mut!(joining, cancelled);
+            match futures::future::select(joining, cancelled).await {
+                futures::future::Either::Left((joined, _)) => Some(joined),
+                futures::future::Either::Right(((), _)) => None,
+            }
+        };
+        let (channel_jid, roster, rooms) = match joined {
+            Some(joined) => joined?,
+            None => {
+                info!("joining was cancelled, disconnecting");
+                end_client(client, leave_messages, END_STEP_TIMEOUT).await;
+                return Err(Error::Cancelled);
+            }
+        };
 
         info!(
             "connected to NWWS-OI {} and joined channel {}",
             &jid, &channel_jid
         );
 
-        Ok(Self {
+        let info = ConnectionInfo {
+            server,
+            peer_addr: client.peer_addr(),
+        };
+        Ok(Connection {
             client,
-            leave_message,
+            info,
+            stats: ConnectionStats::new(chrono::Utc::now()),
+            leave_messages,
+            pending,
+            keepalive,
+            max_message_bytes,
+            oversize,
+            parse_mode,
+            software_version: software_version.unwrap_or_default(),
+            iq_handler,
+            roster,
+            room: channel_jid.clone().into(),
+            channel: channel_jid.into(),
+            rooms,
+            left_rooms: Vec::new(),
+            nickname,
+            channel_password,
+            history,
+            join_timeout,
         })
     }
+}
 
-    /// Terminate the connection as gracefully as possible.
-    pub async fn end(self) {
-        let mut client = self.client;
+/// Joining one MUC room.
+struct Join<'a> {
+    channel: &'a Channel,
+    nickname: &'a str,
+    password: &'a Option<String>,
+    history: &'a History,
+    join_timeout: Option<Duration>,
+}
+
+impl Join<'_> {
+    /// Join the room, and wait for the join to complete, returning our JID in the room and its
+    /// occupants.
+    ///
+    /// The message to leave the room is added to `leave_messages` as soon as the join is sent.
+    /// Stanzas from rooms which were joined before are added to `pending`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "join_channel", skip_all, fields(channel = tracing::field::Empty))
+    )]
+    async fn run(
+        self,
+        client: &mut client::Client,
+        pending: &mut std::collections::VecDeque<xmpp_parsers::Element>,
+        leave_messages: &mut Vec<xmpp_parsers::Element>,
+    ) -> Result<(jid::FullJid, presence::Roster)> {
+        let jid = client.bound_jid().clone();
+        let mut attempt = 1;
+        loop {
+            // Another session may be lingering in the room with our nickname, so try variations
+            let nickname = match attempt {
+                1 => self.nickname.to_string(),
+                n => format!("{}-{}", self.nickname, n),
+            };
 
-        // Attempt to leave the room, ignoring errors
-        client.send_stanza(self.leave_message).await.ok();
+            // Build the message to join the MUC
+            let channel_jid = self.channel.jid(nickname);
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("channel", tracing::field::display(&channel_jid));
+            let join_message =
+                xmpp_parsers::presence::Presence::new(xmpp_parsers::presence::Type::None)
+                    .with_from(jid.clone())
+                    .with_to(channel_jid.clone())
+                    .with_payloads(vec![xmpp_parsers::muc::Muc {
+                        password: self.password.clone(),
+                        history: Some(self.history.muc_history()),
+                    }
+                    .into()]);
+            debug!("joining channel {}", &channel_jid);
 
-        // Attempt to end the stream, ignoring errors
-        client.end().await.ok();
+            // Build the message to leave the MUC
+            //   https://xmpp.org/extensions/xep-0045.html#bizrules-presence § 17.3.2
+            let leave_message: xmpp_parsers::Element =
+                xmpp_parsers::presence::Presence::new(xmpp_parsers::presence::Type::Unavailable)
+                    .with_from(join_message.from.as_ref().unwrap().clone())
+                    .with_to(join_message.to.as_ref().unwrap().clone())
+                    .with_payloads(vec![xmpp_parsers::muc::Muc {
+                        password: None,
+                        history: None,
+                    }
+                    .into()])
+                    .into();
 
-        // Dropping client closes the connection
+            // Join the MUC, and wait for the join to complete
+            leave_messages.push(leave_message.clone());
+            client.send_stanza(join_message).await?;
+            let room = channel_jid.clone().into();
+            let mut roster = presence::Roster::default();
+            match wait_for_join(client, self.join_timeout, &room, &mut roster, pending).await {
+                Ok(()) => return Ok((channel_jid, roster)),
+                Err(Error::NicknameConflict) if attempt <= NICKNAME_RETRIES => {
+                    // This nickname never made it into the room
+                    leave_messages.pop();
+                    warn!(
+                        "nickname {} is already in the room, retrying with another",
+                        &channel_jid.resource
+                    );
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("joining channel {} failed: {}", &channel_jid, e);
+                    if let Error::JoinTimeout = e {
+                        // The room may yet process the join, so withdraw it
+                        client.send_stanza(leave_message).await.ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
     }
+}
 
-    /// Receive the next message from NWWS-OI.
-    pub async fn next_message(&mut self) -> Result<Message> {
-        loop {
-            let element = self.client.next().await.ok_or(Error::StreamEnded)??;
+/// Leave each room with `leave_messages` and end the stream, giving each step up to `timeout`.
+async fn end_client(
+    mut client: client::Client,
+    leave_messages: Vec<xmpp_parsers::Element>,
+    timeout: Duration,
+) -> EndOutcome {
+    // Attempt to leave each room
+    for leave_message in leave_messages {
+        let left = tokio::time::timeout(timeout, client.send_stanza(leave_message)).await;
+        if !matches!(left, Ok(Ok(()))) {
+            // Dropping client closes the connection
+            return EndOutcome::Dropped;
+        }
+    }
 
-            if log_enabled!(Level::Trace) {
-                let mut xml = Vec::new();
-                element
-                    .write_to(&mut std::io::Cursor::new(&mut xml))
-                    .expect("encode");
-                let xml = String::from_utf8(xml).expect("UTF-8");
-                trace!("received: {}", xml);
-            }
+    // Attempt to end the stream, and wait for the server to do the same
+    match tokio::time::timeout(timeout, client.end()).await {
+        Ok(Ok(())) => EndOutcome::Clean,
+        _ => EndOutcome::Left,
+    }
+}
 
-            if element.is("message", "jabber:client") {
-                if let Ok(msg) = Message::try_from(element.clone()) {
-                    return Ok(msg);
-                }
-            } else if element.is("iq", "jabber:client") {
-                let iq = xmpp_parsers::iq::Iq::try_from(element)?;
-                self.handle_iq(iq).await?;
-            } else if element.is("presence", "jabber:client") {
-                trace!("presence message: {:?}", element);
-            } else {
-                warn!("unhandled message: {:?}", element);
+/// Where a [`Connection`](struct.Connection.html) landed, as of connecting.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// The server connected to, which may be one of `Config::fallback_servers`
+    pub server: Server,
+
+    /// The remote address of the underlying TCP connection
+    ///
+    /// This shows which of the server's addresses, and so which IP version, was used, unless the
+    /// connection goes through [`Config::proxy`](struct.Config.html#structfield.proxy), in which
+    /// case it is the proxy's address. It is `None` if the operating system could not say.
+    pub peer_addr: Option<std::net::SocketAddr>,
+}
+
+/// How [`Connection::end()`](struct.Connection.html#method.end) went.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EndOutcome {
+    /// The leave was sent, and the server ended the stream in response
+    Clean,
+    /// The leave was sent, but the server did not end the stream in time
+    Left,
+    /// The leave could not be sent in time, so the socket was closed without it
+    Dropped,
+}
+
+/// Counters describing what a [`Connection`](struct.Connection.html) has received, as of
+/// [`Connection::stats()`](struct.Connection.html#method.stats).
+///
+/// [`Stream`](struct.Stream.html) adds each connection's counters to its
+/// [`StreamMetrics`](struct.StreamMetrics.html) once the connection ends.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// When the connection joined the room
+    pub connected_at: chrono::DateTime<chrono::Utc>,
+
+    /// The number of products parsed into messages, including those replayed from history
+    pub messages_received: u64,
+
+    /// The number of products which could not be parsed, as
+    /// [`ConnectionEvent::Unparsed`](enum.ConnectionEvent.html#variant.Unparsed)
+    pub parse_failures: u64,
+
+    /// The number of bytes of product text received, including products which could not be parsed
+    /// and products larger than `Config::max_message_bytes`
+    pub bytes_received: u64,
+
+    /// When the most recent message was received, if any
+    pub last_message_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ConnectionStats {
+    fn new(connected_at: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            connected_at,
+            messages_received: 0,
+            parse_failures: 0,
+            bytes_received: 0,
+            last_message_at: None,
+        }
+    }
+
+    /// How long the connection has been in the room.
+    pub fn uptime(&self) -> Duration {
+        (chrono::Utc::now() - self.connected_at)
+            .to_std()
+            .unwrap_or_default()
+    }
+
+    /// Count `event`, received at `now`.
+    fn record(&mut self, event: &ConnectionEvent, now: chrono::DateTime<chrono::Utc>) {
+        match event {
+            ConnectionEvent::Message(_) => {
+                self.messages_received += 1;
+                self.last_message_at = Some(now);
             }
+            ConnectionEvent::Unparsed(_) => self.parse_failures += 1,
+            _ => {}
         }
     }
+}
 
-    async fn handle_iq(&mut self, iq: xmpp_parsers::iq::Iq) -> Result<()> {
+/// When connecting with `config` must be complete, if ever.
+pub(crate) fn connect_deadline(config: &Config) -> Option<tokio::time::Instant> {
+    config
+        .connect_timeout
+        .filter(|timeout| !timeout.is_zero())
+        .map(|timeout| tokio::time::Instant::now() + timeout)
+}
+
+/// Run `future` to completion, or fail with `Error::Timeout` once `deadline` passes.
+pub(crate) async fn within<T, F>(deadline: Option<tokio::time::Instant>, future: F) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| Error::Timeout)?,
+        None => future.await,
+    }
+}
+
+/// The response to a received IQ, if it needs one.
+fn iq_response(
+    iq: xmpp_parsers::iq::Iq,
+    software_version: &SoftwareVersion,
+) -> Option<xmpp_parsers::iq::Iq> {
+    use xmpp_parsers::iq::{Iq, IqType};
+
+    let from = iq
+        .from
+        .as_ref()
+        .map(|j| format!(" from {}", j))
+        .unwrap_or_default();
+    match &iq.payload {
+        // XEP-0199 § 4.2
+        IqType::Get(payload) if payload.is("ping", "urn:xmpp:ping") => {
+            debug!("responding to ping{}", from);
+            Some(Iq {
+                from: iq.to,
+                to: iq.from,
+                id: iq.id,
+                payload: IqType::Result(None),
+            })
+        }
+        // XEP-0030 § 3.1, for the client itself rather than one of its nodes
+        IqType::Get(payload)
+            if payload.is("query", "http://jabber.org/protocol/disco#info")
+                && payload.attr("node").is_none() =>
+        {
+            debug!("responding to disco#info{}", from);
+            Some(Iq {
+                from: iq.to,
+                to: iq.from,
+                id: iq.id,
+                payload: IqType::Result(Some(disco_info().into())),
+            })
+        }
+        // XEP-0092 § 2
+        IqType::Get(payload) if payload.is("query", "jabber:iq:version") => {
+            debug!("responding to software version query{}", from);
+            Some(Iq {
+                from: iq.to,
+                to: iq.from,
+                id: iq.id,
+                payload: IqType::Result(Some(
+                    xmpp_parsers::version::VersionResult {
+                        name: software_version.name.clone(),
+                        version: software_version.version.clone(),
+                        os: Some(std::env::consts::OS.into()),
+                    }
+                    .into(),
+                )),
+            })
+        }
         // We may need to respond to this IQ:
         //
         //     If an entity receives an IQ stanza of type "get" or "set" containing a child element
         //     qualified by a namespace it does not understand, the entity SHOULD return an IQ
         //     stanza of type "error" with an error condition of <service-unavailable/>.
-        match &iq.payload {
-            xmpp_parsers::iq::IqType::Get(_) | xmpp_parsers::iq::IqType::Set(_) => {
-                debug!(
-                    "responding to IQ{} with service-unavailable",
-                    iq.from
-                        .as_ref()
-                        .map(|j| format!(" from {}", j))
-                        .unwrap_or_default()
-                );
+        IqType::Get(_) | IqType::Set(_) => {
+            debug!("responding to IQ{} with service-unavailable", from);
+            Some(Iq {
+                from: iq.to,
+                to: iq.from,
+                id: iq.id,
+                payload: IqType::Error(xmpp_parsers::stanza_error::StanzaError {
+                    type_: xmpp_parsers::stanza_error::ErrorType::Cancel,
+                    by: None,
+                    defined_condition:
+                        xmpp_parsers::stanza_error::DefinedCondition::ServiceUnavailable,
+                    texts: Default::default(),
+                    other: None,
+                }),
+            })
+        }
+        IqType::Result(_) | IqType::Error(_) => None,
+    }
+}
 
-                let stanza = xmpp_parsers::iq::Iq {
-                    from: iq.to,
-                    to: iq.from,
-                    id: iq.id,
-                    payload: xmpp_parsers::iq::IqType::Error(
-                        xmpp_parsers::stanza_error::StanzaError {
-                            type_: xmpp_parsers::stanza_error::ErrorType::Cancel,
-                            by: None,
-                            defined_condition:
-                                xmpp_parsers::stanza_error::DefinedCondition::ServiceUnavailable,
-                            texts: Default::default(),
-                            other: None,
-                        },
-                    ),
-                };
-
-                self.client.send_stanza(stanza).await?;
-            }
-            _ => {}
-        };
+/// Our identity and `FEATURES`, as a disco#info result.
+fn disco_info() -> xmpp_parsers::disco::DiscoInfoResult {
+    use xmpp_parsers::disco::{DiscoInfoResult, Feature, Identity};
 
-        Ok(())
+    DiscoInfoResult {
+        node: None,
+        identities: vec![Identity {
+            category: "client".into(),
+            type_: "bot".into(),
+            lang: None,
+            name: Some("nwws-oi".into()),
+        }],
+        features: FEATURES.iter().map(|var| Feature::new(*var)).collect(),
+        extensions: vec![],
+    }
+}
+
+/// Something received from NWWS-OI by
+/// [`Connection::next_event()`](struct.Connection.html#method.next_event).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    Message(Message),
+    Presence(Presence),
+    /// The room's subject, which NWWS-OI sends once the join is complete.
+    Subject(String),
+    /// A product which could not be parsed.
+    Unparsed(UnparsedMessage),
+    /// A problem which does not end the connection, such as a product which was dropped for
+    /// exceeding [`Config::max_message_bytes`](struct.Config.html#structfield.max_message_bytes).
+    Error(Error),
+}
+
+impl ConnectionEvent {
+    /// Interpret a received `<message/>` or `<presence/>` stanza, returning `None` if it is not
+    /// interesting.
+    pub(crate) fn from_element(element: xmpp_parsers::Element, mode: ParseMode) -> Option<Self> {
+        if element.is("message", "jabber:client") {
+            if element.attr("type") == Some("groupchat") {
+                if let Some(subject) = element.get_child("subject", "jabber:client") {
+                    return Some(ConnectionEvent::Subject(subject.text()));
+                }
+            }
+            match Message::parse_with(element, mode) {
+                Ok(msg) => Some(ConnectionEvent::Message(msg)),
+                Err(Some(unparsed)) => {
+                    warn!("failed to parse product: {}", unparsed.error);
+                    Some(ConnectionEvent::Unparsed(unparsed))
+                }
+                Err(None) => None,
+            }
+        } else if element.is("presence", "jabber:client") {
+            match Presence::try_from(element.clone()) {
+                Ok(presence) => Some(ConnectionEvent::Presence(presence)),
+                Err(()) => {
+                    trace!("presence message: {:?}", element);
+                    None
+                }
+            }
+        } else {
+            warn!("unhandled message: {:?}", element);
+            None
+        }
+    }
+}
+
+/// Tracks when to send pings, and when to give up on a connection which does not answer them or
+/// which sends no products.
+struct Keepalive {
+    ping_interval: Option<Duration>,
+    ping_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    /// The server's domain, which pings are addressed to
+    server: xmpp_parsers::Jid,
+    /// When anything at all was last received
+    last_received: Instant,
+    /// When a product was last received
+    last_product: Instant,
+    last_ping: Option<Instant>,
+    /// When the oldest ping still awaiting a reply was sent
+    unanswered_since: Option<Instant>,
+    pings: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum KeepaliveAction {
+    Ping,
+    PingTimeout,
+    Idle,
+}
+
+impl Keepalive {
+    fn new(config: &Config, now: Instant) -> Self {
+        Self {
+            ping_interval: config.ping_interval.filter(|d| !d.is_zero()),
+            ping_timeout: config.ping_timeout.filter(|d| !d.is_zero()),
+            idle_timeout: config.idle_timeout.filter(|d| !d.is_zero()),
+            server: xmpp_parsers::BareJid::domain(config.server.hostname()).into(),
+            last_received: now,
+            last_product: now,
+            last_ping: None,
+            unanswered_since: None,
+            pings: 0,
+        }
+    }
+
+    /// When the next ping, ping timeout, or idle timeout is due, if ever.
+    fn deadline(&self) -> Option<Instant> {
+        let ping = self.ping_interval.map(|interval| {
+            self.last_ping
+                .map_or(self.last_received, |p| p.max(self.last_received))
+                + interval
+        });
+        let reply = self.reply_deadline();
+        let idle = self.idle_timeout.map(|timeout| self.last_product + timeout);
+        [ping, reply, idle].iter().flatten().min().copied()
+    }
+
+    /// When the oldest unanswered ping times out, if there is one.
+    fn reply_deadline(&self) -> Option<Instant> {
+        let ping = self.unanswered_since?;
+        self.ping_timeout.map(|timeout| ping + timeout)
+    }
+
+    /// What to do at `now`, if anything.
+    fn action(&self, now: Instant) -> Option<KeepaliveAction> {
+        // Idle takes priority, so an unanswered ping is not followed by yet another
+        if let Some(timeout) = self.idle_timeout {
+            if now >= self.last_product + timeout {
+                return Some(KeepaliveAction::Idle);
+            }
+        }
+        if self
+            .reply_deadline()
+            .map_or(false, |deadline| now >= deadline)
+        {
+            return Some(KeepaliveAction::PingTimeout);
+        }
+        match self.deadline() {
+            Some(deadline) if now >= deadline && self.ping_interval.is_some() => {
+                Some(KeepaliveAction::Ping)
+            }
+            _ => None,
+        }
+    }
+
+    fn received(&mut self, now: Instant) {
+        self.last_received = now;
+        self.unanswered_since = None;
+    }
+
+    fn product_received(&mut self, now: Instant) {
+        self.last_product = now;
+    }
+
+    /// Record a ping sent at `now`, returning its IQ ID.
+    fn ping_sent(&mut self, now: Instant) -> String {
+        self.last_ping = Some(now);
+        self.unanswered_since.get_or_insert(now);
+        self.pings += 1;
+        format!("ping-{}", self.pings)
+    }
+}
+
+/// Wait for the MUC room to confirm the join with our self-presence, giving up after
+/// `join_timeout`.
+///
+/// The room lists its occupants before confirming the join, and they are added to `roster`.
+/// Stanzas from anywhere but `room` are added to `pending`.
+async fn wait_for_join<S>(
+    stream: &mut S,
+    join_timeout: Option<Duration>,
+    room: &jid::BareJid,
+    roster: &mut presence::Roster,
+    pending: &mut std::collections::VecDeque<xmpp_parsers::Element>,
+) -> Result<()>
+where
+    S: futures::Stream<Item = std::result::Result<xmpp_parsers::Element, tokio_xmpp::Error>>
+        + Unpin,
+{
+    let wait = async {
+        loop {
+            let item = stream.try_next().await?.ok_or(Error::StreamEnded)?;
+            let from = item
+                .attr("from")
+                .and_then(|from| from.parse::<jid::Jid>().ok())
+                .map(jid::BareJid::from);
+            if !matches!(&from, Some(from) if same_room(from, room)) {
+                pending.push_back(item);
+                continue;
+            }
+
+            if let Ok(presence) = Presence::try_from(item.clone()) {
+                roster.update(&presence, chrono::Utc::now());
+            }
+
+            if let Ok(presence) = xmpp_parsers::presence::Presence::try_from(item) {
+                if presence.type_ == xmpp_parsers::presence::Type::Error {
+                    return Err(join_error(presence));
+                }
+                for payload in presence.payloads {
+                    if let Ok(muc_user) = xmpp_parsers::muc::MucUser::try_from(payload) {
+                        if muc_user
+                            .status
+                            .iter()
+                            .any(|s| s == &xmpp_parsers::muc::user::Status::SelfPresence)
+                        {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    };
+    match join_timeout.filter(|timeout| !timeout.is_zero()) {
+        Some(timeout) => tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| Error::JoinTimeout)?,
+        None => wait.await,
+    }
+}
+
+/// Wait for `room` to confirm that we left, or fail with `Error::LeaveTimeout` after
+/// `leave_timeout`.
+///
+/// Other stanzas from `room` are dropped. Stanzas from other rooms are added to `pending`.
+async fn wait_for_leave<S>(
+    stream: &mut S,
+    leave_timeout: Option<Duration>,
+    room: &jid::BareJid,
+    pending: &mut std::collections::VecDeque<xmpp_parsers::Element>,
+) -> Result<()>
+where
+    S: futures::Stream<Item = std::result::Result<xmpp_parsers::Element, tokio_xmpp::Error>>
+        + Unpin,
+{
+    let wait = async {
+        loop {
+            let item = stream.try_next().await?.ok_or(Error::StreamEnded)?;
+            if !stanza_room(&item, "from").map_or(false, |from| same_room(&from, room)) {
+                pending.push_back(item);
+            } else if self_unavailable(&item, room) || left_room(&item, room).is_some() {
+                // Our own unavailable presence, or the room going away: either way, we are out
+                return Ok(());
+            }
+        }
+    };
+    match leave_timeout.filter(|timeout| !timeout.is_zero()) {
+        Some(timeout) => tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| Error::LeaveTimeout)?,
+        None => wait.await,
+    }
+}
+
+/// Whether `element` is our own participant's unavailable presence in `room`.
+fn self_unavailable(element: &xmpp_parsers::Element, room: &jid::BareJid) -> bool {
+    if !element.is("presence", "jabber:client") || element.attr("type") != Some("unavailable") {
+        return false;
+    }
+    if !stanza_room(element, "from").map_or(false, |from| same_room(&from, room)) {
+        return false;
+    }
+    element
+        .get_child("x", "http://jabber.org/protocol/muc#user")
+        .and_then(|x| xmpp_parsers::muc::MucUser::try_from(x.clone()).ok())
+        .map_or(false, |muc_user| {
+            muc_user
+                .status
+                .contains(&xmpp_parsers::muc::user::Status::SelfPresence)
+        })
+}
+
+/// The error for our own participant leaving `channel` at the server's initiative, if `element`
+/// says so: being kicked or banned, or the room being destroyed or shut down.
+fn left_room(element: &xmpp_parsers::Element, channel: &jid::BareJid) -> Option<Error> {
+    const MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+
+    if !element.is("presence", "jabber:client") || element.attr("type") != Some("unavailable") {
+        return None;
+    }
+    let from: jid::FullJid = element.attr("from")?.parse().ok()?;
+    if !same_room(&from.into(), channel) {
+        return None;
+    }
+    let x = element.get_child("x", MUC_USER)?;
+
+    // xmpp_parsers does not know <destroy/>, so it is read by hand
+    //   https://xmpp.org/extensions/xep-0045.html#destroyroom
+    if let Some(destroy) = x.get_child("destroy", MUC_USER) {
+        let reason = destroy
+            .get_child("reason", MUC_USER)
+            .map(xmpp_parsers::Element::text)
+            .filter(|reason| !reason.is_empty());
+        return Some(Error::RoomClosed { reason });
+    }
+
+    let muc_user = xmpp_parsers::muc::MucUser::try_from(x.clone()).ok()?;
+    use xmpp_parsers::muc::user::Status;
+    if !muc_user.status.contains(&Status::SelfPresence) {
+        return None;
+    }
+    let reason = muc_user
+        .items
+        .iter()
+        .find_map(|item| item.reason.clone())
+        .map(|reason| reason.0);
+    Some(if muc_user.status.contains(&Status::Banned) {
+        Error::RemovedFromRoom {
+            reason,
+            banned: true,
+        }
+    } else if muc_user.status.contains(&Status::Kicked) {
+        Error::RemovedFromRoom {
+            reason,
+            banned: false,
+        }
+    } else if muc_user.status.contains(&Status::ServiceShutdown) {
+        Error::RoomClosed { reason }
+    } else {
+        // e.g. we left on our own, or changed nickname
+        return None;
+    })
+}
+
+/// The bare JID of `element`'s `attr` attribute, e.g. the room a stanza is `from`.
+fn stanza_room(element: &xmpp_parsers::Element, attr: &str) -> Option<jid::BareJid> {
+    let jid: jid::Jid = element.attr(attr)?.parse().ok()?;
+    Some(jid.into())
+}
+
+/// Whether `a` and `b` are the same room.
+///
+/// Room names are case-insensitive, and NWWS-OI writes its own in lowercase.
+fn same_room(a: &jid::BareJid, b: &jid::BareJid) -> bool {
+    let same_node = match (&a.node, &b.node) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a == b,
+    };
+    same_node && a.domain.eq_ignore_ascii_case(&b.domain)
+}
+
+/// The size of the product text in `element`, if it carries a product.
+fn product_size(element: &xmpp_parsers::Element) -> Option<usize> {
+    let oi = element.get_child("x", "nwws-oi")?;
+    Some(oi.texts().map(str::len).sum())
+}
+
+/// The error for a product in `element` which is larger than `limit`, if there is one.
+fn too_large(element: &xmpp_parsers::Element, limit: Option<usize>) -> Option<Error> {
+    let limit = limit?;
+    let size = product_size(element)?;
+    if size <= limit {
+        return None;
+    }
+    let oi = element.get_child("x", "nwws-oi")?;
+    let attr = |name| oi.attr(name).unwrap_or_default().to_string();
+    Some(Error::MessageTooLarge {
+        size,
+        limit,
+        ttaaii: attr("ttaaii"),
+        cccc: attr("cccc"),
+    })
+}
+
+/// The error for a presence error received while joining the MUC room.
+fn join_error(presence: xmpp_parsers::presence::Presence) -> Error {
+    use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
+
+    let condition = presence
+        .payloads
+        .into_iter()
+        .find_map(|payload| StanzaError::try_from(payload).ok())
+        .map(|e| e.defined_condition);
+    match condition {
+        // XEP-0045 § 7.2.5
+        Some(DefinedCondition::NotAuthorized) => Error::ChannelPassword,
+        // XEP-0045 § 7.2.9
+        Some(DefinedCondition::Conflict) => Error::NicknameConflict,
+        Some(condition) => Error::JoinRefused(xmpp_parsers::Element::from(condition).name().into()),
+        None => Error::JoinRefused("undefined-condition".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn join_error() {
+        let presence = |xml: &str| {
+            let element: xmpp_parsers::Element = xml.parse().unwrap();
+            xmpp_parsers::presence::Presence::try_from(element).unwrap()
+        };
+
+        assert!(matches!(
+            super::join_error(presence("<presence xmlns=\"jabber:client\" type=\"error\" from=\"room@conference.example.com/user\"><x xmlns=\"http://jabber.org/protocol/muc\"/><error type=\"auth\"><not-authorized xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error></presence>")),
+            Error::ChannelPassword
+        ));
+        assert!(matches!(
+            super::join_error(presence("<presence xmlns=\"jabber:client\" type=\"error\" from=\"room@conference.example.com/user\"><error type=\"cancel\"><conflict xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error></presence>")),
+            Error::NicknameConflict
+        ));
+        assert!(matches!(
+            super::join_error(presence("<presence xmlns=\"jabber:client\" type=\"error\" from=\"room@conference.example.com/user\"><error type=\"auth\"><forbidden xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error></presence>")),
+            Error::JoinRefused(condition) if condition == "forbidden"
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .join_timeout(Some(Duration::from_millis(100)))
+            .build();
+
+        // The room never answers
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = client::test_server::authenticate(socket).await;
+            let join = client::test_server::presence(&mut stream).await;
+            let leave = client::test_server::presence(&mut stream).await;
+            (join, leave)
+        };
+        let (connection, (join, leave)) = tokio::join!(Connection::new(config), server);
+        assert!(matches!(connection, Err(Error::JoinTimeout)));
+        assert!(!join.contains("unavailable"), "{}", join);
+        assert!(leave.contains("type=\"unavailable\""), "{}", leave);
+    }
+
+    #[test]
+    fn stats() {
+        let element = |xml: &str| -> xmpp_parsers::Element { xml.parse().unwrap() };
+        let connected_at = chrono::Utc::now() - chrono::Duration::seconds(90);
+        let mut stats = ConnectionStats::new(connected_at);
+        assert!(stats.uptime() >= Duration::from_secs(90));
+
+        let now = chrono::Utc::now();
+        for xml in [
+            "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\">\n\n987\n\nSRUS43 KLMK 040254\n\n</x></message>",
+            // No issue time
+            "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" awipsid=\"RRMLMK\" id=\"14425.25118\">SRUS43 KLMK 040254\n</x></message>",
+            "<presence xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov/other\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"participant\"/></x></presence>",
+        ] {
+            let element = element(xml);
+            stats.bytes_received += product_size(&element).unwrap_or_default() as u64;
+            let event = ConnectionEvent::from_element(element, ParseMode::Lenient).unwrap();
+            stats.record(&event, now);
+        }
+        assert_eq!(
+            stats,
+            ConnectionStats {
+                connected_at,
+                messages_received: 1,
+                parse_failures: 1,
+                bytes_received: 46,
+                last_message_at: Some(now),
+            }
+        );
+    }
+
+    #[test]
+    fn left_room() {
+        let channel = "NWWS@conference.nwws-oi.weather.gov".parse().unwrap();
+        let removed = |xml: &str| super::left_room(&xml.parse().unwrap(), &channel);
+
+        let kicked = removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"><reason>flooding</reason></item><status code=\"307\"/><status code=\"110\"/></x></presence>");
+        match kicked {
+            Some(e @ Error::RemovedFromRoom { banned: false, .. }) => {
+                assert_eq!(e.to_string(), "kicked from the channel: flooding");
+                assert!(!e.is_fatal());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let banned = removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"outcast\" role=\"none\"/><status code=\"301\"/><status code=\"110\"/></x></presence>");
+        match banned {
+            Some(e @ Error::RemovedFromRoom { banned: true, .. }) => {
+                assert_eq!(e.to_string(), "banned from the channel");
+                assert!(e.is_fatal());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // We left on our own
+        assert!(removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><status code=\"110\"/></x></presence>").is_none());
+        // We changed nickname
+        assert!(removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"participant\" nick=\"user/res-2\"/><status code=\"303\"/><status code=\"110\"/></x></presence>").is_none());
+        // Someone else was kicked
+        assert!(removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/other\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><status code=\"307\"/></x></presence>").is_none());
+        let destroyed = removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><destroy jid=\"nwws2@conference.nwws-oi.weather.gov\"><reason>Service restart</reason></destroy></x></presence>");
+        match destroyed {
+            Some(e @ Error::RoomClosed { .. }) => {
+                assert_eq!(e.to_string(), "the channel was closed: Service restart");
+                assert!(!e.is_fatal());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><destroy/></x></presence>"),
+            Some(Error::RoomClosed { reason: None })
+        ));
+
+        // The service is shutting down, and makes everyone unavailable
+        assert!(matches!(
+            removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><status code=\"332\"/><status code=\"110\"/></x></presence>"),
+            Some(Error::RoomClosed { reason: None })
+        ));
+
+        // Another room
+        assert!(removed("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"other@conference.nwws-oi.weather.gov/user/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"none\"/><destroy/></x></presence>").is_none());
+        assert!(removed("<message xmlns=\"jabber:client\"/>").is_none());
+    }
+
+    #[tokio::test]
+    async fn next_message_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .build();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        // A ping arrives, but no message
+        stream.write_all(b"<iq type='get' id='ping-1' from='localhost' to='user@localhost/res'><ping xmlns='urn:xmpp:ping'/></iq>").await.unwrap();
+        let (received, pong) = tokio::join!(
+            connection.next_message_timeout(Duration::from_millis(200)),
+            client::test_server::read_until(&mut stream, |r| r.contains("ping-1"))
+        );
+        assert!(matches!(received, Ok(None)), "{:?}", received);
+        assert!(pong.contains("type=\"result\""), "{}", pong);
+
+        // The connection carries on
+        stream.write_all(b"<message type='groupchat' from='nwws@conference.nwws-oi.weather.gov/nwws-oi'><x xmlns='nwws-oi' cccc='KLMK' ttaaii='SRUS43' issue='2022-02-04T02:54:00Z' awipsid='RRMLMK' id='14425.25117'>SRUS43 KLMK 040254\n</x></message>").await.unwrap();
+        let message = connection
+            .next_message_timeout(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.id, "14425.25117");
+    }
+
+    #[tokio::test]
+    async fn next_element() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .build();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        // The ping is answered rather than returned
+        stream.write_all(b"<iq type='get' id='ping-1' from='localhost' to='user@localhost/res'><ping xmlns='urn:xmpp:ping'/></iq><message type='groupchat' from='nwws@conference.nwws-oi.weather.gov'><subject>National Weather Wire Service Open Interface</subject></message>").await.unwrap();
+        let (element, pong) = tokio::join!(
+            connection.next_element(),
+            client::test_server::read_until(&mut stream, |r| r.contains("ping-1"))
+        );
+        let element = element.unwrap();
+        assert!(element.is("message", "jabber:client"));
+        assert!(element.has_child("subject", "jabber:client"));
+        assert!(pong.contains("type=\"result\""), "{}", pong);
+    }
+
+    #[tokio::test]
+    async fn join_channel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .join_timeout(Some(Duration::from_millis(500)))
+            .build();
+        let product = |room: &str, id: &str| {
+            format!("<message type='groupchat' from='{}/nwws-oi'><x xmlns='nwws-oi' cccc='KLMK' ttaaii='SRUS43' issue='2022-02-04T02:54:00Z' awipsid='RRMLMK' id='{}'>SRUS43 KLMK 040254\n</x></message>", room, id)
+        };
+        let relay: Channel = "relay@conference.example.com".parse().unwrap();
+
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        // Join the relay room while connected
+        let server = async {
+            let join = client::test_server::presence(&mut stream).await;
+            assert!(
+                join.contains("relay@conference.example.com/user/res"),
+                "{}",
+                join
+            );
+            assert!(!join.contains("unavailable"), "{}", join);
+            stream.write_all(b"<presence from='relay@conference.example.com/user/res'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>").await.unwrap();
+            stream
+                .write_all(product("relay@conference.example.com", "14425.1").as_bytes())
+                .await
+                .unwrap();
+        };
+        let (joined, ()) = tokio::join!(connection.join_channel(relay.clone()), server);
+        joined.unwrap();
+        // Joining again does nothing
+        connection.join_channel(relay.clone()).await.unwrap();
+        let message = connection.next_message().await.unwrap();
+        assert_eq!(message.id, "14425.1");
+        assert_eq!(
+            message.source_channel(),
+            Some("relay@conference.example.com".parse().unwrap())
+        );
+
+        // Then leave it, while it is still delivering
+        let unavailable = b"<presence type='unavailable' from='relay@conference.example.com/user/res'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='none'/><status code='110'/></x></presence>";
+        let server = async {
+            let leave = client::test_server::presence(&mut stream).await;
+            assert!(leave.contains("type=\"unavailable\""), "{}", leave);
+            assert!(
+                leave.contains("relay@conference.example.com/user/res"),
+                "{}",
+                leave
+            );
+            stream
+                .write_all(product("relay@conference.example.com", "14425.2").as_bytes())
+                .await
+                .unwrap();
+            stream.write_all(unavailable).await.unwrap();
+            stream
+                .write_all(product("nwws@conference.nwws-oi.weather.gov", "14425.3").as_bytes())
+                .await
+                .unwrap();
+        };
+        let (left, ()) = tokio::join!(connection.leave_channel(&relay), server);
+        left.unwrap();
+        assert!(connection.left_rooms.is_empty());
+        let message = connection.next_message().await.unwrap();
+        assert_eq!(message.id, "14425.3");
+
+        // Rejoin it, and leave again without the room confirming in time
+        let server = async {
+            client::test_server::presence(&mut stream).await;
+            stream.write_all(b"<presence from='relay@conference.example.com/user/res'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>").await.unwrap();
+        };
+        let (joined, ()) = tokio::join!(connection.join_channel(relay.clone()), server);
+        joined.unwrap();
+        let server = async {
+            client::test_server::presence(&mut stream).await;
+        };
+        let (left, ()) = tokio::join!(connection.leave_channel(&relay), server);
+        assert!(matches!(left, Err(Error::LeaveTimeout)), "{:?}", left);
+
+        // The relay's straggler is ignored, rather than delivered or treated as being removed
+        stream
+            .write_all(product("relay@conference.example.com", "14425.4").as_bytes())
+            .await
+            .unwrap();
+        stream.write_all(unavailable).await.unwrap();
+        stream
+            .write_all(product("nwws@conference.nwws-oi.weather.gov", "14425.5").as_bytes())
+            .await
+            .unwrap();
+        let message = connection.next_message().await.unwrap();
+        assert_eq!(message.id, "14425.5");
+        assert!(connection.left_rooms.is_empty());
+
+        // Only the remaining room is left when ending
+        let server = async {
+            let end =
+                client::test_server::read_until(&mut stream, |r| r.contains("</stream:stream>"))
+                    .await;
+            assert_eq!(end.matches("unavailable").count(), 1, "{}", end);
+            assert!(
+                end.contains("NWWS@conference.nwws-oi.weather.gov/user/res"),
+                "{}",
+                end
+            );
+            stream.write_all(b"</stream:stream>").await.unwrap();
+            stream.flush().await.unwrap();
+        };
+        let (outcome, ()) = tokio::join!(connection.end(), server);
+        assert_eq!(outcome, EndOutcome::Clean);
+    }
+
+    #[tokio::test]
+    async fn next_stanza() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .build();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        stream.write_all(b"<iq type='get' id='ping-1' from='localhost' to='user@localhost/res'><ping xmlns='urn:xmpp:ping'/></iq><message type='groupchat' from='nwws@conference.nwws-oi.weather.gov/nwws-oi'><x xmlns='nwws-oi' cccc='KLMK' ttaaii='SRUS43' issue='2022-02-04T02:54:00Z' awipsid='RRMLMK' id='14425.25117'>SRUS43 KLMK 040254\n</x></message>").await.unwrap();
+        // The ping is returned, and answered as well
+        let (element, pong) = tokio::join!(
+            connection.next_stanza(),
+            client::test_server::read_until(&mut stream, |r| r.contains("ping-1"))
+        );
+        let element = element.unwrap();
+        assert!(element.is("iq", "jabber:client"));
+        assert_eq!(element.attr("id"), Some("ping-1"));
+        assert!(pong.contains("type=\"result\""), "{}", pong);
+
+        // Which leaves the product for next_message()
+        let message = connection.next_message().await.unwrap();
+        assert_eq!(message.id, "14425.25117");
+        assert_eq!(connection.stats().messages_received, 1);
+    }
+
+    #[tokio::test]
+    async fn oversize_skip() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .max_message_bytes(100)
+            .oversize(Oversize::Skip)
+            .build();
+        let product = |id: &str, text: &str| {
+            format!("<message type='groupchat' from='nwws@conference.nwws-oi.weather.gov/nwws-oi'><x xmlns='nwws-oi' cccc='KLMK' ttaaii='SRUS43' issue='2022-02-04T02:54:00Z' awipsid='RRMLMK' id='{}'>{}</x></message>", id, text)
+        };
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        stream
+            .write_all(product("14425.1", &"x".repeat(101)).as_bytes())
+            .await
+            .unwrap();
+        stream
+            .write_all(product("14425.2", "SRUS43 KLMK 040254\n").as_bytes())
+            .await
+            .unwrap();
+
+        // The oversize product is dropped without an error, and the next one is delivered
+        match connection.next_event().await.unwrap() {
+            ConnectionEvent::Message(message) => assert_eq!(message.id, "14425.2"),
+            other => panic!("expected the second product, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn additional_channels() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .additional_channels(vec!["relay@conference.example.com".parse().unwrap()])
+            .build();
+        let product = |room: &str, id: &str| {
+            format!("<message type='groupchat' from='{}/nwws-oi'><x xmlns='nwws-oi' cccc='KLMK' ttaaii='SRUS43' issue='2022-02-04T02:54:00Z' awipsid='RRMLMK' id='{}'>SRUS43 KLMK 040254\n</x></message>", room, id)
+        };
+
+        let relay_joined = std::cell::Cell::new(false);
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = client::test_server::join(socket).await;
+            // The first room delivers a product before the second is joined
+            stream
+                .write_all(product("nwws@conference.nwws-oi.weather.gov", "14425.1").as_bytes())
+                .await
+                .unwrap();
+            let join = client::test_server::presence(&mut stream).await;
+            assert!(
+                join.contains("to=\"relay@conference.example.com/user/res\""),
+                "{}",
+                join
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            relay_joined.set(true);
+            stream.write_all(b"<presence from='relay@conference.example.com/user/res'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>").await.unwrap();
+            stream
+                .write_all(product("relay@conference.example.com", "14425.2").as_bytes())
+                .await
+                .unwrap();
+            stream
+        };
+        let (connection, _stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+        // Connecting waited for both rooms
+        assert!(relay_joined.get());
+
+        let mut sources = Vec::new();
+        for _ in 0..2 {
+            let message = connection.next_message().await.unwrap();
+            sources.push((
+                message.id.clone(),
+                message.source_channel().unwrap().to_string(),
+            ));
+        }
+        assert_eq!(
+            sources,
+            vec![
+                (
+                    "14425.1".to_string(),
+                    "nwws@conference.nwws-oi.weather.gov".to_string()
+                ),
+                (
+                    "14425.2".to_string(),
+                    "relay@conference.example.com".to_string()
+                ),
+            ]
+        );
+        // Only the NWWS room's occupants are listed
+        assert_eq!(connection.occupants().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn iq_handler() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Answers software version queries with an empty result, and leaves the rest alone
+        let handler = |iq: &xmpp_parsers::iq::Iq| match &iq.payload {
+            xmpp_parsers::iq::IqType::Get(payload) if payload.is("query", "jabber:iq:version") => {
+                Some(xmpp_parsers::iq::Iq::from_result(
+                    iq.id.clone(),
+                    None::<xmpp_parsers::version::VersionResult>,
+                ))
+            }
+            _ => None,
+        };
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .iq_handler(handler)
+            .build();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        stream.write_all(b"<iq type='get' id='version-1' from='localhost' to='user@localhost/res'><query xmlns='jabber:iq:version'/></iq><iq type='get' id='unknown-1' from='localhost' to='user@localhost/res'><query xmlns='urn:example:unknown'/></iq>").await.unwrap();
+        let (received, responses) = tokio::join!(
+            connection.next_message_timeout(Duration::from_millis(200)),
+            client::test_server::read_until(&mut stream, |r| r.contains("unknown-1"))
+        );
+        assert!(matches!(received, Ok(None)), "{:?}", received);
+
+        // The handler's answer, rather than this crate's
+        let version = responses.split("</iq>").next().unwrap();
+        assert!(version.contains("id=\"version-1\""), "{}", responses);
+        assert!(version.contains("type=\"result\""), "{}", responses);
+        assert!(!version.contains("jabber:iq:version"), "{}", responses);
+        // Everything else still gets the default answer
+        let unknown = &responses[responses.find("unknown-1").unwrap()..];
+        assert!(unknown.contains("service-unavailable"), "{}", responses);
+    }
+
+    #[tokio::test]
+    async fn end() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .build();
+
+        for respond in [true, false] {
+            let server = async {
+                let (socket, _) = listener.accept().await.unwrap();
+                client::test_server::join(socket).await
+            };
+            let (connection, mut stream) = tokio::join!(Connection::new(config.clone()), server);
+
+            let server = async {
+                let leave = client::test_server::presence(&mut stream).await;
+                assert!(leave.contains("type=\"unavailable\""), "{}", leave);
+                if respond {
+                    stream.write_all(b"</stream:stream>").await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+                // Otherwise the server goes quiet, but keeps the socket open
+                stream
+            };
+            let (outcome, _stream) = tokio::join!(
+                connection
+                    .unwrap()
+                    .end_with_timeout(Duration::from_millis(200)),
+                server
+            );
+            let expected = if respond {
+                EndOutcome::Clean
+            } else {
+                EndOutcome::Left
+            };
+            assert_eq!(outcome, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn new_cancellable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .build();
+
+        let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = client::test_server::authenticate(socket).await;
+            // The room never answers the join, and then the caller gives up
+            let join = client::test_server::presence(&mut stream).await;
+            assert!(!join.contains("unavailable"), "{}", join);
+            cancel.send(()).unwrap();
+
+            // Leaving the room, then ending the stream
+            let leave =
+                client::test_server::read_until(&mut stream, |r| r.contains("</stream:stream>"))
+                    .await;
+            assert!(leave.contains("type=\"unavailable\""), "{}", leave);
+            assert!(
+                leave.contains("NWWS@conference.nwws-oi.weather.gov/user/res"),
+                "{}",
+                leave
+            );
+            stream.write_all(b"</stream:stream>").await.unwrap();
+            stream.flush().await.unwrap();
+            stream
+        };
+        let (connection, _stream) =
+            tokio::join!(Connection::new_cancellable(config, cancelled), server);
+        assert!(matches!(connection, Err(Error::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn nickname_conflict() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .join_timeout(Some(Duration::from_secs(5)))
+            .nickname("user/res")
+            .build();
+        let conflict = b"<presence type='error' from='nwws@conference.nwws-oi.weather.gov/user/res'><x xmlns='http://jabber.org/protocol/muc'/><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></presence>";
+
+        // The first nickname is taken, and the second is accepted
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = client::test_server::authenticate(socket).await;
+            let first = client::test_server::presence(&mut stream).await;
+            stream.write_all(conflict).await.unwrap();
+            let second = client::test_server::presence(&mut stream).await;
+            stream.write_all(b"<presence from='nwws@conference.nwws-oi.weather.gov/user/res-2'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>").await.unwrap();
+            (first, second, stream)
+        };
+        let (connection, (first, second, _stream)) =
+            tokio::join!(Connection::new(config.clone()), server);
+        let connection = connection.unwrap();
+        assert!(first.contains("/user/res\""), "{}", first);
+        assert!(second.contains("/user/res-2\""), "{}", second);
+        assert_eq!(connection.occupants()[0].nickname, "user/res-2");
+        assert_eq!(
+            connection.channel().to_string(),
+            "NWWS@conference.nwws-oi.weather.gov/user/res-2"
+        );
+        assert_eq!(connection.bound_jid().to_string(), "user@localhost/res");
+        assert_eq!(connection.jid(), connection.bound_jid());
+        assert_eq!(
+            connection.info(),
+            &ConnectionInfo {
+                server: Server::Custom("localhost".into()),
+                peer_addr: Some(addr),
+            }
+        );
+
+        // Every nickname is taken
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = client::test_server::authenticate(socket).await;
+            let mut joins = Vec::new();
+            for _ in 0..=NICKNAME_RETRIES {
+                joins.push(client::test_server::presence(&mut stream).await);
+                stream.write_all(conflict).await.unwrap();
+            }
+            (joins, stream)
+        };
+        let (connection, (joins, _stream)) = tokio::join!(Connection::new(config), server);
+        assert!(matches!(connection, Err(Error::NicknameConflict)));
+        assert!(joins[3].contains("/user/res-4\""), "{}", joins[3]);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn wait_for_join() {
+        let element = |xml: &str| -> std::result::Result<xmpp_parsers::Element, tokio_xmpp::Error> {
+            Ok(xml.parse().unwrap())
+        };
+        let occupant = "<presence xmlns=\"jabber:client\" from=\"room@conference.example.com/other\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"participant\"/></x></presence>";
+        let own = "<presence xmlns=\"jabber:client\" from=\"room@conference.example.com/user\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"participant\"/><status code=\"110\"/></x></presence>";
+        let timeout = Some(Duration::from_millis(50));
+        let room = "room@conference.example.com".parse().unwrap();
+        let mut roster = presence::Roster::default();
+        let mut pending = std::collections::VecDeque::new();
+
+        // Other occupants' presence does not complete the join, and the server goes quiet
+        let mut stream =
+            futures::stream::iter(vec![element(occupant)]).chain(futures::stream::pending());
+        assert!(matches!(
+            super::wait_for_join(&mut stream, timeout, &room, &mut roster, &mut pending).await,
+            Err(Error::JoinTimeout)
+        ));
+
+        // A room joined before keeps talking while this one is joined
+        let other_room = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"other@conference.example.com/nwws-oi\"><body>hello</body></message>";
+        let mut stream =
+            futures::stream::iter(vec![element(occupant), element(other_room), element(own)])
+                .chain(futures::stream::pending());
+        let mut roster = presence::Roster::default();
+        super::wait_for_join(&mut stream, timeout, &room, &mut roster, &mut pending)
+            .await
+            .unwrap();
+        let nicknames: Vec<_> = roster.occupants().into_iter().map(|o| o.nickname).collect();
+        assert_eq!(nicknames, vec!["other", "user"]);
+        assert_eq!(
+            pending.drain(..).collect::<Vec<_>>(),
+            vec![other_room.parse::<xmpp_parsers::Element>().unwrap()]
+        );
+
+        let mut stream = futures::stream::iter(vec![element(occupant)]);
+        assert!(matches!(
+            super::wait_for_join(&mut stream, None, &room, &mut roster, &mut pending).await,
+            Err(Error::StreamEnded)
+        ));
+
+        // Another session is using our nickname
+        let conflict = "<presence xmlns=\"jabber:client\" type=\"error\" from=\"room@conference.example.com/user\"><x xmlns=\"http://jabber.org/protocol/muc\"/><error type=\"cancel\"><conflict xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error></presence>";
+        let mut stream = futures::stream::iter(vec![element(occupant), element(conflict)])
+            .chain(futures::stream::pending());
+        assert!(matches!(
+            super::wait_for_join(&mut stream, timeout, &room, &mut roster, &mut pending).await,
+            Err(Error::NicknameConflict)
+        ));
+    }
+
+    #[test]
+    fn too_large() {
+        let element: xmpp_parsers::Element = format!("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[{}]]></x></message>", "A".repeat(1000)).parse().unwrap();
+        assert!(super::too_large(&element, None).is_none());
+        assert!(super::too_large(&element, Some(1000)).is_none());
+        match super::too_large(&element, Some(999)) {
+            Some(Error::MessageTooLarge {
+                size,
+                limit,
+                ttaaii,
+                cccc,
+            }) => {
+                assert_eq!((size, limit), (1000, 999));
+                assert_eq!((ttaaii.as_str(), cccc.as_str()), ("SRUS43", "KLMK"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Only products are limited
+        let subject: xmpp_parsers::Element = "<message xmlns=\"jabber:client\" type=\"groupchat\"><subject>National Weather Wire Service Open Interface</subject></message>".parse().unwrap();
+        assert!(super::too_large(&subject, Some(1)).is_none());
+    }
+
+    #[test]
+    fn iq_response() {
+        let iq = |xml: &str| {
+            let element: xmpp_parsers::Element = xml.parse().unwrap();
+            xmpp_parsers::iq::Iq::try_from(element).unwrap()
+        };
+        let xml = |iq: xmpp_parsers::iq::Iq| {
+            let element = xmpp_parsers::Element::from(iq);
+            let mut xml = Vec::new();
+            element
+                .write_to(&mut std::io::Cursor::new(&mut xml))
+                .unwrap();
+            String::from_utf8(xml).unwrap()
+        };
+        let respond = |xml: &str| super::iq_response(iq(xml), &SoftwareVersion::default());
+
+        let pong = respond("<iq xmlns=\"jabber:client\" type=\"get\" id=\"c2s1\" from=\"nwws-oi.weather.gov\" to=\"user@nwws-oi.weather.gov/res\"><ping xmlns=\"urn:xmpp:ping\"/></iq>").unwrap();
+        assert_eq!(pong.id, "c2s1");
+        assert_eq!(pong.to.as_ref().unwrap().to_string(), "nwws-oi.weather.gov");
+        assert_eq!(
+            pong.from.as_ref().unwrap().to_string(),
+            "user@nwws-oi.weather.gov/res"
+        );
+        assert_eq!(
+            xml(pong),
+            "<iq xmlns='jabber:client' from=\"user@nwws-oi.weather.gov/res\" id=\"c2s1\" to=\"nwws-oi.weather.gov\" type=\"result\"/>"
+        );
+
+        let info = respond("<iq xmlns=\"jabber:client\" type=\"get\" id=\"disco1\" from=\"nwws@conference.nwws-oi.weather.gov\"><query xmlns=\"http://jabber.org/protocol/disco#info\"/></iq>").unwrap();
+        assert_eq!(info.id, "disco1");
+        assert_eq!(
+            info.to.as_ref().unwrap().to_string(),
+            "nwws@conference.nwws-oi.weather.gov"
+        );
+        assert_eq!(
+            xml(info),
+            "<iq xmlns='jabber:client' id=\"disco1\" to=\"nwws@conference.nwws-oi.weather.gov\" type=\"result\"><query xmlns='http://jabber.org/protocol/disco#info'><identity category=\"client\" name=\"nwws-oi\" type=\"bot\"/><feature var=\"http://jabber.org/protocol/disco#info\"/><feature var=\"http://jabber.org/protocol/muc\"/><feature var=\"jabber:iq:version\"/><feature var=\"urn:xmpp:ping\"/></query></iq>"
+        );
+
+        let query = "<iq xmlns=\"jabber:client\" type=\"get\" id=\"v1\" from=\"nwws-oi.weather.gov\"><query xmlns=\"jabber:iq:version\"/></iq>";
+        let version = respond(query).unwrap();
+        assert_eq!(version.id, "v1");
+        assert_eq!(
+            xml(version),
+            format!(
+                "<iq xmlns='jabber:client' id=\"v1\" to=\"nwws-oi.weather.gov\" type=\"result\"><query xmlns='jabber:iq:version'><name>nwws-oi</name><version>{}</version><os>{}</os></query></iq>",
+                env!("CARGO_PKG_VERSION"),
+                std::env::consts::OS
+            )
+        );
+
+        // Applications can identify themselves instead
+        let app = SoftwareVersion {
+            name: "wxalert".into(),
+            version: "2.1.0".into(),
+        };
+        match super::iq_response(iq(query), &app).unwrap().payload {
+            xmpp_parsers::iq::IqType::Result(Some(payload)) => {
+                let result = xmpp_parsers::version::VersionResult::try_from(payload).unwrap();
+                assert_eq!(result.name, "wxalert");
+                assert_eq!(result.version, "2.1.0");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Anything else is unsupported, including nodes we do not have
+        let error = respond("<iq xmlns=\"jabber:client\" type=\"get\" id=\"disco2\"><query xmlns=\"http://jabber.org/protocol/disco#info\" node=\"http://example.com#abc\"/></iq>").unwrap();
+        assert!(matches!(error.payload, xmpp_parsers::iq::IqType::Error(_)));
+        let error = respond("<iq xmlns=\"jabber:client\" type=\"get\" id=\"last1\" from=\"nwws-oi.weather.gov\"><query xmlns=\"jabber:iq:last\"/></iq>").unwrap();
+        assert_eq!(error.id, "last1");
+        assert!(matches!(
+            error.payload,
+            xmpp_parsers::iq::IqType::Error(e)
+                if e.defined_condition == xmpp_parsers::stanza_error::DefinedCondition::ServiceUnavailable
+        ));
+
+        // Responses need no response
+        assert!(respond("<iq xmlns=\"jabber:client\" type=\"result\" id=\"ping-1\" from=\"nwws-oi.weather.gov\"/>").is_none());
+    }
+
+    #[test]
+    fn subject() {
+        let element = |xml: &str| -> xmpp_parsers::Element { xml.parse().unwrap() };
+        assert!(matches!(
+            ConnectionEvent::from_element(element("<message xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov\" to=\"w.glynn@nwws-oi.weather.gov/todo\" type=\"groupchat\"><subject>National Weather Wire Service Open Interface</subject><delay xmlns=\"urn:xmpp:delay\" from=\"nwws@conference.nwws-oi.weather.gov\" stamp=\"2015-02-03T20:48:44.222Z\"/></message>"), ParseMode::Lenient),
+            Some(ConnectionEvent::Subject(subject)) if subject == "National Weather Wire Service Open Interface"
+        ));
+
+        // A subject outside of the room is not the room's subject
+        assert!(ConnectionEvent::from_element(element(
+            "<message xmlns=\"jabber:client\" from=\"someone@example.com\" type=\"chat\"><subject>hi</subject></message>"
+        ), ParseMode::Lenient)
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn anonymous_not_allowed() {
+        // Refused before connecting anywhere
+        for server in [Server::Primary, Server::Backup] {
+            assert!(matches!(
+                Connection::new(Config::anonymous(server, Channel::Default)).await,
+                Err(Error::AnonymousNotAllowed)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_nickname() {
+        // Refused before connecting anywhere
+        let config = Config::builder("user", "pass")
+            .server(Server::Custom("nwws.invalid".into()))
+            .nickname("")
+            .build();
+        assert!(matches!(
+            Connection::new(config).await,
+            Err(Error::InvalidNickname(_))
+        ));
+    }
+
+    #[test]
+    fn keepalive() {
+        let config = Config::from(("user", "pass"));
+        let t0 = Instant::now();
+        let secs = |n| t0 + Duration::from_secs(n);
+        let mut keepalive = Keepalive::new(&config, t0);
+        assert_eq!(keepalive.server.to_string(), "nwws-oi.weather.gov");
+
+        // Quiet for the ping interval
+        assert_eq!(keepalive.deadline(), Some(secs(60)));
+        assert_eq!(keepalive.action(secs(59)), None);
+        assert_eq!(keepalive.action(secs(60)), Some(KeepaliveAction::Ping));
+        assert_eq!(keepalive.ping_sent(secs(60)), "ping-1");
+
+        // The pong arrives before the ping times out, which restarts the ping timer
+        assert_eq!(keepalive.deadline(), Some(secs(90)));
+        keepalive.received(secs(61));
+        assert_eq!(keepalive.deadline(), Some(secs(121)));
+        assert_eq!(keepalive.action(secs(121)), Some(KeepaliveAction::Ping));
+        assert_eq!(keepalive.ping_sent(secs(121)), "ping-2");
+
+        // A product restarts both timers
+        keepalive.received(secs(150));
+        keepalive.product_received(secs(150));
+        assert_eq!(keepalive.deadline(), Some(secs(210)));
+        assert_eq!(keepalive.action(secs(210)), Some(KeepaliveAction::Ping));
+        keepalive.ping_sent(secs(210));
+
+        // Pongs keep arriving, but without products the connection is given up on anyway
+        for pong in [211, 271, 331] {
+            keepalive.received(secs(pong));
+            assert_eq!(
+                keepalive.action(secs(pong + 60)),
+                Some(KeepaliveAction::Ping)
+            );
+            keepalive.ping_sent(secs(pong + 60));
+        }
+        keepalive.received(secs(392));
+        assert_eq!(keepalive.deadline(), Some(secs(450)));
+        assert_eq!(keepalive.action(secs(449)), None);
+        assert_eq!(keepalive.action(secs(450)), Some(KeepaliveAction::Idle));
+    }
+
+    #[test]
+    fn keepalive_ping_timeout() {
+        let t0 = Instant::now();
+        let secs = |n| t0 + Duration::from_secs(n);
+        let config = Config::builder("user", "pass").idle_timeout(None).build();
+        let mut keepalive = Keepalive::new(&config, t0);
+
+        // The ping is never answered, and there is no idle timeout to fall back on
+        assert_eq!(keepalive.action(secs(60)), Some(KeepaliveAction::Ping));
+        keepalive.ping_sent(secs(60));
+        assert_eq!(keepalive.deadline(), Some(secs(90)));
+        assert_eq!(keepalive.action(secs(89)), None);
+        assert_eq!(
+            keepalive.action(secs(90)),
+            Some(KeepaliveAction::PingTimeout)
+        );
+
+        // Further pings do not postpone the timeout of the first
+        let config = Config::builder("user", "pass")
+            .ping_interval(Some(Duration::from_secs(10)))
+            .idle_timeout(None)
+            .build();
+        let mut keepalive = Keepalive::new(&config, t0);
+        keepalive.ping_sent(secs(10));
+        assert_eq!(keepalive.action(secs(20)), Some(KeepaliveAction::Ping));
+        keepalive.ping_sent(secs(20));
+        assert_eq!(keepalive.deadline(), Some(secs(30)));
+        keepalive.ping_sent(secs(30));
+        assert_eq!(keepalive.deadline(), Some(secs(40)));
+        assert_eq!(
+            keepalive.action(secs(40)),
+            Some(KeepaliveAction::PingTimeout)
+        );
+
+        // Without a ping timeout, pings are sent regardless
+        let config = Config::builder("user", "pass")
+            .ping_timeout(None)
+            .idle_timeout(None)
+            .build();
+        let mut keepalive = Keepalive::new(&config, t0);
+        keepalive.ping_sent(secs(60));
+        assert_eq!(keepalive.deadline(), Some(secs(120)));
+        assert_eq!(keepalive.action(secs(120)), Some(KeepaliveAction::Ping));
+    }
+
+    #[tokio::test]
+    async fn ping_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .nickname("user/res")
+            .ping_interval(Some(Duration::from_millis(100)))
+            .ping_timeout(Some(Duration::from_millis(200)))
+            .idle_timeout(None)
+            .build();
+        let server = async {
+            let (socket, _) = listener.accept().await.unwrap();
+            client::test_server::join(socket).await
+        };
+        let (connection, mut stream) = tokio::join!(Connection::new(config), server);
+        let mut connection = connection.unwrap();
+
+        // The server stays connected, but never answers the ping
+        let (received, ping) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), connection.next_message()),
+            client::test_server::read_until(&mut stream, |r| r.contains("urn:xmpp:ping"))
+        );
+        assert!(ping.contains("ping-1"), "{}", ping);
+        assert!(
+            matches!(received, Ok(Err(Error::PingTimeout))),
+            "{:?}",
+            received
+        );
+    }
+
+    #[test]
+    fn keepalive_disabled() {
+        let t0 = Instant::now();
+        let config = Config::builder("user", "pass")
+            .ping_interval(None)
+            .idle_timeout(Some(Duration::from_secs(0)))
+            .build();
+        let keepalive = Keepalive::new(&config, t0);
+        assert_eq!(keepalive.deadline(), None);
+        assert_eq!(keepalive.action(t0 + Duration::from_secs(3600)), None);
+
+        let config = Config::builder("user", "pass").ping_interval(None).build();
+        let keepalive = Keepalive::new(&config, t0);
+        assert_eq!(keepalive.deadline(), Some(t0 + Duration::from_secs(300)));
+        assert_eq!(
+            keepalive.action(t0 + Duration::from_secs(300)),
+            Some(KeepaliveAction::Idle)
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        // Accept TCP connections, but never speak XMPP
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config = Config {
+            server: Server::CustomWithPort("127.0.0.1".into(), port),
+            connect_timeout: Some(Duration::from_millis(250)),
+            ..Config::from(("user", "pass"))
+        };
+
+        let start = Instant::now();
+        let result = Connection::new_with_timeout(config).await;
+        let elapsed = start.elapsed();
+
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        // Accept TCP connections, but stall before TLS
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = client::test_server::config(addr)
+            .connect_timeout(Some(Duration::from_secs(10)))
+            .handshake_timeout(Some(Duration::from_millis(250)))
+            .build();
+
+        let start = Instant::now();
+        let (result, _accepted) =
+            tokio::join!(Connection::new_with_timeout(config), listener.accept());
+        let elapsed = start.elapsed();
+
+        // Distinct from the overall timeout, which has not elapsed
+        assert!(matches!(result, Err(Error::HandshakeTimeout)));
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
     }
 }
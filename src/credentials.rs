@@ -0,0 +1,117 @@
+use crate::*;
+use futures::future::BoxFuture;
+
+/// A source of the password used to authenticate, consulted on every connection attempt.
+///
+/// Set [`Config::credential_provider`](struct.Config.html#structfield.credential_provider) to a
+/// `CredentialProvider` when the password can change while the process is running, e.g. because it
+/// is stored in a secrets manager and rotated periodically. [`Stream`](struct.Stream.html) asks for
+/// the password again each time it reconnects, so a rotated password is picked up without
+/// restarting.
+///
+/// `String` implements `CredentialProvider` by returning itself.
+///
+/// # Example
+///
+/// ```rust
+/// use futures::future::BoxFuture;
+///
+/// struct FromFile(std::path::PathBuf);
+///
+/// impl nwws_oi::CredentialProvider for FromFile {
+///     fn password(&self) -> BoxFuture<'_, nwws_oi::Result<String>> {
+///         Box::pin(async move {
+///             std::fs::read_to_string(&self.0)
+///                 .map(|password| password.trim().to_string())
+///                 .map_err(|e| nwws_oi::Error::CredentialProvider(e.to_string()))
+///         })
+///     }
+/// }
+///
+/// let config = nwws_oi::Config::builder("user", "")
+///     .credential_provider(FromFile("/run/secrets/nwws-oi-password".into()))
+///     .build();
+/// ```
+pub trait CredentialProvider: Send + Sync {
+    /// Return the current password.
+    ///
+    /// Errors should be reported as
+    /// [`Error::CredentialProvider`](enum.Error.html#variant.CredentialProvider), which `Stream`
+    /// retries with its usual backoff.
+    fn password(&self) -> BoxFuture<'_, Result<String>>;
+}
+
+impl CredentialProvider for String {
+    fn password(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(futures::future::ready(Ok(self.clone())))
+    }
+}
+
+impl std::fmt::Debug for dyn CredentialProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CredentialProvider")
+    }
+}
+
+/// Providers are equal only if they are the same provider.
+impl PartialEq for dyn CredentialProvider {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const Self as *const u8,
+        )
+    }
+}
+
+impl Eq for dyn CredentialProvider {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Returns a new password every time.
+    struct Rotating(AtomicU32);
+
+    impl CredentialProvider for Rotating {
+        fn password(&self) -> BoxFuture<'_, Result<String>> {
+            let n = self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move { Ok(format!("pass{}", n)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn password() {
+        let config = Config::from(("user", "pass"));
+        assert_eq!(config.password().await.unwrap(), "pass");
+        assert_eq!("pass".to_string().password().await.unwrap(), "pass");
+
+        // The provider takes precedence, and is asked every time
+        let config = Config::builder("user", "pass")
+            .credential_provider(Rotating(AtomicU32::new(1)))
+            .build();
+        assert_eq!(config.password().await.unwrap(), "pass1");
+        assert_eq!(config.clone().password().await.unwrap(), "pass2");
+    }
+
+    #[test]
+    fn eq() {
+        let provider: Arc<dyn CredentialProvider> = Arc::new(Rotating(AtomicU32::new(0)));
+        let config = Config::builder("user", "pass").build();
+        let with_provider = Config {
+            credential_provider: Some(provider.clone()),
+            ..config.clone()
+        };
+        assert_ne!(config, with_provider);
+        assert_eq!(with_provider, with_provider.clone());
+        assert_ne!(
+            with_provider,
+            Config {
+                credential_provider: Some(Arc::new(Rotating(AtomicU32::new(0)))),
+                ..config
+            }
+        );
+        assert_eq!(format!("{:?}", provider), "CredentialProvider");
+    }
+}
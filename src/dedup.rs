@@ -0,0 +1,337 @@
+use crate::logging::debug;
+use crate::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Settings for [`Dedup`](struct.Dedup.html).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DedupConfig {
+    /// How long to remember a product.
+    ///
+    /// A duplicate which arrives more than `window` after the first copy is passed through.
+    pub window: Duration,
+    /// Which parts of a message identify a product.
+    pub key: DedupKey,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(300),
+            key: DedupKey::default(),
+        }
+    }
+}
+
+/// The parts of a [`Message`](struct.Message.html) which together identify a product.
+///
+/// `Message::id` is deliberately not available: it is assigned by each server, so copies of the
+/// same product received from different servers have different IDs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DedupKey {
+    /// Include `Message::ttaaii`
+    pub ttaaii: bool,
+    /// Include `Message::cccc`
+    pub cccc: bool,
+    /// Include `Message::awips_id`
+    pub awips_id: bool,
+    /// Include `Message::issue`
+    pub issue: bool,
+    /// Include the message body
+    pub message: bool,
+}
+
+impl DedupKey {
+    fn hash(&self, message: &Message) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        if self.ttaaii {
+            message.ttaaii.hash(&mut hasher);
+        }
+        if self.cccc {
+            message.cccc.hash(&mut hasher);
+        }
+        if self.awips_id {
+            message.awips_id.hash(&mut hasher);
+        }
+        if self.issue {
+            message.issue.hash(&mut hasher);
+        }
+        if self.message {
+            message.message.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+impl Default for DedupKey {
+    /// `ttaaii`, `cccc`, `issue`, and `message`.
+    fn default() -> Self {
+        Self {
+            ttaaii: true,
+            cccc: true,
+            awips_id: false,
+            issue: true,
+            message: true,
+        }
+    }
+}
+
+/// A stream adapter which drops duplicate products.
+///
+/// This is most useful with [`Stream::with_failover()`](struct.Stream.html#method.with_failover),
+/// which receives every product once per server. All other events are passed through unchanged.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let stream = nwws_oi::Stream::with_failover(
+///     nwws_oi::Config::primary("user", "pass"),
+///     nwws_oi::Config::backup("user", "pass"),
+/// )
+/// .dedup(Default::default());
+/// ```
+pub struct Dedup<S> {
+    inner: S,
+    config: DedupConfig,
+    seen: HashSet<u64>,
+    expiry: VecDeque<(Instant, u64)>,
+}
+
+impl<S> Dedup<S> {
+    /// Wrap a stream of events, such as a [`Stream`](struct.Stream.html).
+    pub fn new(inner: S, config: DedupConfig) -> Self {
+        Self {
+            inner,
+            config,
+            seen: Default::default(),
+            expiry: Default::default(),
+        }
+    }
+
+    /// Unwrap the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Record `message` as seen at `now`, returning `false` if it was already seen.
+    fn is_new(&mut self, message: &Message, now: Instant) -> bool {
+        while let Some((received, hash)) = self.expiry.front() {
+            if now.saturating_duration_since(*received) < self.config.window {
+                break;
+            }
+            self.seen.remove(hash);
+            self.expiry.pop_front();
+        }
+
+        let hash = self.config.key.hash(message);
+        if self.seen.insert(hash) {
+            self.expiry.push_back((now, hash));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<S: futures::Stream<Item = StreamEvent> + Unpin> futures::Stream for Dedup<S> {
+    type Item = StreamEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(StreamEvent::Message(message))) => {
+                    if self.is_new(&message, Instant::now()) {
+                        return Poll::Ready(Some(StreamEvent::Message(message)));
+                    }
+                    debug!("dropping duplicate product {}", message.id);
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/// A stream adapter which drops messages whose [`Message::id`](struct.Message.html#structfield.id)
+/// was seen recently.
+///
+/// A single server can deliver the same message twice, for example when room history overlaps
+/// live delivery after a reconnect. This is much cheaper than [`Dedup`](struct.Dedup.html), but
+/// since IDs are assigned by each server, it does not catch the same product received from two
+/// servers. All other events are passed through unchanged.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let stream = nwws_oi::Stream::new(("user", "pass")).dedup_by_id(1024);
+/// ```
+pub struct DedupById<S> {
+    inner: S,
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl<S> DedupById<S> {
+    /// Wrap a stream of events, remembering the `capacity` most recent IDs.
+    ///
+    /// 1024 IDs is enough to cover a history replay. A `capacity` of 0 is treated as 1.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            inner,
+            capacity,
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Unwrap the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Record `id` as seen, returning `false` if it was already seen.
+    fn is_new(&mut self, id: &str) -> bool {
+        if self.seen.contains(id) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+impl<S: futures::Stream<Item = StreamEvent> + Unpin> futures::Stream for DedupById<S> {
+    type Item = StreamEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(StreamEvent::Message(message))) => {
+                    if self.is_new(&message.id) {
+                        return Poll::Ready(Some(StreamEvent::Message(message)));
+                    }
+                    debug!("dropping duplicate message {}", message.id);
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn message(id: &str, body: &str) -> Message {
+        Message::builder().id(id).message(body).build()
+    }
+
+    fn ids(events: Vec<StreamEvent>) -> Vec<String> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                StreamEvent::Message(message) => Some(message.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let events = vec![
+            StreamEvent::ConnectionState(ConnectionState::Joined),
+            StreamEvent::Message(message("1.1", "SRUS43 KLMK 040254\n")),
+            // Same product from another server
+            StreamEvent::Message(message("2.1", "SRUS43 KLMK 040254\n")),
+            StreamEvent::Message(message("1.2", "SRUS43 KLMK 040254 RRA\n")),
+            StreamEvent::ConnectionState(ConnectionState::Disconnected),
+        ];
+
+        let events: Vec<_> = Dedup::new(futures::stream::iter(events), Default::default())
+            .collect()
+            .await;
+        assert_eq!(events.len(), 4);
+        assert!(matches!(
+            events.last(),
+            Some(StreamEvent::ConnectionState(ConnectionState::Disconnected))
+        ));
+        assert_eq!(ids(events), vec!["1.1", "1.2"]);
+    }
+
+    #[test]
+    fn window() {
+        let mut dedup = Dedup::new(
+            (),
+            DedupConfig {
+                window: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+        let t0 = Instant::now();
+        let product = message("1.1", "body");
+
+        assert!(dedup.is_new(&product, t0));
+        assert!(!dedup.is_new(&product, t0 + Duration::from_secs(59)));
+        assert!(dedup.is_new(&product, t0 + Duration::from_secs(60)));
+        assert_eq!(dedup.seen.len(), 1);
+    }
+
+    #[test]
+    fn key() {
+        let mut dedup = Dedup::new(
+            (),
+            DedupConfig {
+                key: DedupKey {
+                    message: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let t0 = Instant::now();
+
+        assert!(dedup.is_new(&message("1.1", "body"), t0));
+        assert!(!dedup.is_new(&message("1.2", "different body"), t0));
+    }
+
+    #[tokio::test]
+    async fn by_id() {
+        let events = vec![
+            StreamEvent::Message(message("1.1", "SRUS43 KLMK 040254\n")),
+            // Replayed from history after reconnecting
+            StreamEvent::ConnectionState(ConnectionState::Joined),
+            StreamEvent::Message(message("1.1", "SRUS43 KLMK 040254\n")),
+            StreamEvent::Message(message("1.2", "SRUS43 KLMK 040254 RRA\n")),
+        ];
+
+        let events: Vec<_> = DedupById::new(futures::stream::iter(events), 1024)
+            .collect()
+            .await;
+        assert_eq!(events.len(), 3);
+        assert_eq!(ids(events), vec!["1.1", "1.2"]);
+    }
+
+    #[test]
+    fn by_id_capacity() {
+        let mut dedup = DedupById::new((), 2);
+        assert!(dedup.is_new("1.1"));
+        assert!(dedup.is_new("1.2"));
+        assert!(!dedup.is_new("1.1"));
+        // Forgets 1.1
+        assert!(dedup.is_new("1.3"));
+        assert!(dedup.is_new("1.1"));
+        assert!(!dedup.is_new("1.3"));
+        assert_eq!(dedup.seen.len(), 2);
+    }
+}
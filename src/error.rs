@@ -6,12 +6,154 @@ pub enum Error {
     Configuration(tokio_xmpp::Error),
     #[error("the credentials were refused: {0}")]
     Credentials(tokio_xmpp::Error),
+    #[error("the server offers no allowed SASL mechanism, only {0:?}")]
+    SaslMechanism(Vec<String>),
+    #[error("NWWS-OI does not allow anonymous authentication")]
+    AnonymousNotAllowed,
     #[error("a network error occurred: {0}")]
     Network(tokio_xmpp::Error),
     #[error("an XMPP parse error occurred: {0}")]
     XmppParseError(#[from] xmpp_parsers::Error),
     #[error("the XMPP stream ended")]
     StreamEnded,
+    #[error("the server's TLS certificate does not match any pinned certificate")]
+    CertificateMismatch,
+    /// The whole connection attempt, from resolving the server to joining the channel, took longer
+    /// than [`Config::connect_timeout`](struct.Config.html#structfield.connect_timeout).
+    ///
+    /// [`HandshakeTimeout`](#variant.HandshakeTimeout) and [`JoinTimeout`](#variant.JoinTimeout)
+    /// report the phases of the attempt timing out on their own.
+    #[error("connecting and joining the channel timed out")]
+    Timeout,
+    /// Establishing the XMPP connection, from resolving the server through TLS and authentication
+    /// to binding a resource, took longer than
+    /// [`Config::handshake_timeout`](struct.Config.html#structfield.handshake_timeout).
+    ///
+    /// This is one phase of the attempt which [`Timeout`](#variant.Timeout) covers as a whole.
+    #[error("the XMPP handshake (connecting, TLS, and authentication) timed out")]
+    HandshakeTimeout,
+    #[error("joining the channel timed out")]
+    JoinTimeout,
+    #[error("leaving the channel timed out")]
+    LeaveTimeout,
+    #[error("no product was received within the idle timeout")]
+    Idle,
+    #[error("no reply to a ping was received within the ping timeout")]
+    PingTimeout,
+    #[error("the server {0:?} is invalid")]
+    InvalidServer(String),
+    #[error("the channel {0:?} is not a valid bare JID")]
+    InvalidChannel(String),
+    #[error("the nickname {0:?} is not a valid JID resourcepart")]
+    InvalidNickname(String),
+    #[error("the channel password was refused")]
+    ChannelPassword,
+    #[error("joining the channel was refused: {0}")]
+    JoinRefused(String),
+    #[error("the nickname is already in use in the channel")]
+    NicknameConflict,
+    #[error("{} the channel{}", if *.banned { "banned from" } else { "kicked from" }, .reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default())]
+    RemovedFromRoom {
+        reason: Option<String>,
+        banned: bool,
+    },
+    #[error("the channel was closed{}", .reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default())]
+    RoomClosed { reason: Option<String> },
+    #[error("the environment variable {0} is not set")]
+    MissingEnvVar(String),
+    #[error("the environment variable {0} is invalid: {1}")]
+    InvalidEnvVar(String, String),
+    #[error("the connection URL is invalid: {0}")]
+    InvalidUrl(String),
+    #[error("the credential provider failed: {0}")]
+    CredentialProvider(String),
+    #[error("an I/O error occurred: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("a {ttaaii} {cccc} product of {size} bytes was dropped, exceeding the limit of {limit} bytes")]
+    MessageTooLarge {
+        size: usize,
+        limit: usize,
+        ttaaii: String,
+        cccc: String,
+    },
+    #[error("connecting was cancelled")]
+    Cancelled,
+}
+
+/// Why a stanza could not be parsed into a [`Message`](struct.Message.html).
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum MessageParseError {
+    #[error("not a groupchat message")]
+    NotGroupchat,
+    #[error("no NWWS-OI payload")]
+    MissingPayload,
+    #[error("{0}")]
+    InvalidStanza(String),
+    #[error("missing {0} attribute")]
+    MissingAttribute(&'static str),
+    #[error("invalid {0} attribute: {1:?}")]
+    InvalidAttribute(&'static str, String),
+    #[error("invalid issue attribute: {0}")]
+    InvalidIssue(chrono::ParseError),
+    #[error("invalid delay stamp: {0:?}")]
+    InvalidDelayStamp(String),
+    #[error("not valid XML: {0}")]
+    InvalidXml(String),
+}
+
+impl MessageParseError {
+    /// Returns `true` if the stanza carries an NWWS-OI product which could not be parsed, or
+    /// `false` if it does not carry a product at all, or is not valid XML.
+    pub fn is_product(&self) -> bool {
+        !matches!(
+            self,
+            MessageParseError::NotGroupchat
+                | MessageParseError::MissingPayload
+                | MessageParseError::InvalidXml(_)
+        )
+    }
+}
+
+impl Error {
+    /// Returns `true` if retrying is unlikely to help until the configuration is changed, or
+    /// `false` if the error is likely transient.
+    ///
+    /// [`Stream`](struct.Stream.html) waits
+    /// [`Backoff::credential_delay`](struct.Backoff.html#structfield.credential_delay) after fatal
+    /// errors, instead of its usual backoff.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Configuration(_)
+            | Error::Credentials(_)
+            | Error::SaslMechanism(_)
+            | Error::AnonymousNotAllowed
+            | Error::CertificateMismatch
+            | Error::InvalidServer(_)
+            | Error::InvalidChannel(_)
+            | Error::InvalidNickname(_)
+            | Error::ChannelPassword
+            | Error::JoinRefused(_)
+            | Error::MissingEnvVar(_)
+            | Error::InvalidEnvVar(_, _)
+            | Error::InvalidUrl(_) => true,
+            Error::RemovedFromRoom { banned, .. } => *banned,
+            Error::Network(_)
+            | Error::XmppParseError(_)
+            | Error::StreamEnded
+            | Error::Timeout
+            | Error::HandshakeTimeout
+            | Error::JoinTimeout
+            | Error::LeaveTimeout
+            | Error::Idle
+            | Error::PingTimeout
+            | Error::NicknameConflict
+            | Error::RoomClosed { .. }
+            | Error::CredentialProvider(_)
+            | Error::Io(_)
+            | Error::MessageTooLarge { .. }
+            | Error::Cancelled => false,
+        }
+    }
 }
 
 impl From<tokio_xmpp::Error> for Error {
@@ -21,3 +163,63 @@ impl From<tokio_xmpp::Error> for Error {
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_fatal() {
+        let xmpp = || tokio_xmpp::Error::Disconnected;
+        for fatal in [
+            Error::Configuration(xmpp()),
+            Error::Credentials(xmpp()),
+            Error::SaslMechanism(vec!["PLAIN".into()]),
+            Error::AnonymousNotAllowed,
+            Error::CertificateMismatch,
+            Error::InvalidServer("".into()),
+            Error::InvalidChannel("".into()),
+            Error::InvalidNickname("".into()),
+            Error::ChannelPassword,
+            Error::JoinRefused("forbidden".into()),
+            Error::MissingEnvVar("NWWS_OI_USERNAME".into()),
+            Error::InvalidEnvVar("NWWS_OI_SERVER".into(), "".into()),
+            Error::InvalidUrl("".into()),
+            Error::RemovedFromRoom {
+                reason: None,
+                banned: true,
+            },
+        ] {
+            assert!(fatal.is_fatal(), "{:?}", fatal);
+        }
+
+        for transient in [
+            Error::Network(xmpp()),
+            Error::XmppParseError(xmpp_parsers::Error::ParseError("test")),
+            Error::StreamEnded,
+            Error::Timeout,
+            Error::HandshakeTimeout,
+            Error::JoinTimeout,
+            Error::LeaveTimeout,
+            Error::Idle,
+            Error::PingTimeout,
+            Error::NicknameConflict,
+            Error::RemovedFromRoom {
+                reason: Some("flooding".into()),
+                banned: false,
+            },
+            Error::RoomClosed { reason: None },
+            Error::CredentialProvider("vault is sealed".into()),
+            Error::Io(std::io::ErrorKind::UnexpectedEof.into()),
+            Error::MessageTooLarge {
+                size: 2,
+                limit: 1,
+                ttaaii: "SRUS43".into(),
+                cccc: "KLMK".into(),
+            },
+            Error::Cancelled,
+        ] {
+            assert!(!transient.is_fatal(), "{:?}", transient);
+        }
+    }
+}
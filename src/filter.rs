@@ -0,0 +1,134 @@
+use crate::*;
+
+/// Selects which products a [`Stream`](struct.Stream.html) delivers.
+///
+/// Each field is a list of patterns. A pattern ending in `*` matches any value starting with the
+/// rest of the pattern, and any other pattern matches only that exact value. A message matches a
+/// field if it matches any of that field's patterns, or if the field is empty. A message matches
+/// the filter if it matches every field.
+///
+/// The default filter is empty, and so matches every message.
+///
+/// # Example
+///
+/// ```rust
+/// // Warnings of any kind, plus hourly weather roundups, from Topeka or Wichita
+/// let filter = nwws_oi::MessageFilter {
+///     ttaaii: vec!["WFUS*".into(), "WWUS*".into(), "ASUS*".into()],
+///     cccc: vec!["KTOP".into(), "KICT".into()],
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MessageFilter {
+    /// Patterns for `Message::ttaaii`
+    pub ttaaii: Vec<String>,
+    /// Patterns for `Message::cccc`
+    pub cccc: Vec<String>,
+    /// Patterns for `Message::awips_id`
+    ///
+    /// Messages without an AWIPS ID match only if this is empty.
+    pub awips_id: Vec<String>,
+}
+
+impl MessageFilter {
+    /// Returns `true` if `message` should be delivered.
+    pub fn matches(&self, message: &Message) -> bool {
+        matches_any(&self.ttaaii, Some(&message.ttaaii))
+            && matches_any(&self.cccc, Some(&message.cccc))
+            && matches_any(&self.awips_id, message.awips_id.as_deref())
+    }
+
+    /// Returns `true` if this filter matches every message.
+    pub fn is_empty(&self) -> bool {
+        self.ttaaii.is_empty() && self.cccc.is_empty() && self.awips_id.is_empty()
+    }
+}
+
+fn matches_any(patterns: &[String], value: Option<&str>) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let value = match value {
+        Some(value) => value,
+        None => return false,
+    };
+
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == pattern,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ttaaii: &str, cccc: &str, awips_id: Option<&str>) -> Message {
+        Message::builder()
+            .ttaaii(ttaaii)
+            .cccc(cccc)
+            .awips_id(awips_id)
+            .build()
+    }
+
+    #[test]
+    fn empty() {
+        let filter = MessageFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&message("SRUS43", "KLMK", Some("RRMLMK"))));
+        assert!(filter.matches(&message("SRUS43", "KLMK", None)));
+    }
+
+    #[test]
+    fn awips_id_allowlist() {
+        let filter = MessageFilter {
+            awips_id: vec!["TORTOP".into(), "SVRTOP".into()],
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+        assert!(filter.matches(&message("WFUS53", "KTOP", Some("TORTOP"))));
+        assert!(filter.matches(&message("WUUS53", "KTOP", Some("SVRTOP"))));
+        assert!(!filter.matches(&message("WUUS53", "KICT", Some("SVRICT"))));
+        assert!(!filter.matches(&message("WUUS53", "KTOP", Some("SVRTOPX"))));
+        assert!(!filter.matches(&message("SRUS43", "KLMK", None)));
+    }
+
+    #[test]
+    fn ttaaii_prefix() {
+        let filter = MessageFilter {
+            ttaaii: vec!["WFUS*".into(), "SRUS43".into()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&message("WFUS53", "KTOP", Some("TORTOP"))));
+        assert!(filter.matches(&message("WFUS54", "KFWD", None)));
+        assert!(filter.matches(&message("SRUS43", "KLMK", Some("RRMLMK"))));
+        assert!(!filter.matches(&message("SRUS44", "KLMK", Some("RRMLMK"))));
+        assert!(!filter.matches(&message("WUUS53", "KTOP", Some("SVRTOP"))));
+
+        // Every field must match
+        let filter = MessageFilter {
+            cccc: vec!["KTOP".into()],
+            ..filter
+        };
+        assert!(filter.matches(&message("WFUS53", "KTOP", Some("TORTOP"))));
+        assert!(!filter.matches(&message("WFUS54", "KFWD", None)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let filter: MessageFilter = toml::from_str(r#"ttaaii = ["WFUS*"]"#).unwrap();
+        assert_eq!(
+            filter,
+            MessageFilter {
+                ttaaii: vec!["WFUS*".into()],
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,61 @@
+/// Answers IQ requests from the server or other entities, ahead of this crate's own responder.
+///
+/// By default, a [`Connection`](struct.Connection.html) answers pings, disco#info, and software
+/// version queries itself, and answers every other `get` or `set` with `service-unavailable`. Set
+/// [`Config::iq_handler`](struct.Config.html#structfield.iq_handler) to an `IqHandler` to answer
+/// some IQs differently. IQs it returns `None` for still get the default answer, so unhandled
+/// requests are never left without a reply.
+///
+/// Closures taking `&Iq` and returning `Option<Iq>` implement `IqHandler`.
+///
+/// # Example
+///
+/// ```rust
+/// use xmpp_parsers::iq::{Iq, IqType};
+///
+/// let config = nwws_oi::Config::builder("user", "pass")
+///     .iq_handler(|iq: &Iq| match &iq.payload {
+///         IqType::Get(payload) if payload.is("time", "urn:xmpp:time") => Some(Iq {
+///             from: iq.to.clone(),
+///             to: iq.from.clone(),
+///             id: iq.id.clone(),
+///             payload: IqType::Result(None),
+///         }),
+///         _ => None,
+///     })
+///     .build();
+/// ```
+pub trait IqHandler: Send + Sync {
+    /// Return the response to `iq`, or `None` to leave it to the default responder.
+    ///
+    /// This is called for every IQ received, including the `result` and `error` IQs answering
+    /// this connection's pings, which need no response.
+    fn handle(&self, iq: &xmpp_parsers::iq::Iq) -> Option<xmpp_parsers::iq::Iq>;
+}
+
+impl<F> IqHandler for F
+where
+    F: Fn(&xmpp_parsers::iq::Iq) -> Option<xmpp_parsers::iq::Iq> + Send + Sync,
+{
+    fn handle(&self, iq: &xmpp_parsers::iq::Iq) -> Option<xmpp_parsers::iq::Iq> {
+        self(iq)
+    }
+}
+
+impl std::fmt::Debug for dyn IqHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IqHandler")
+    }
+}
+
+/// Handlers are equal only if they are the same handler.
+impl PartialEq for dyn IqHandler {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const Self as *const u8,
+        )
+    }
+}
+
+impl Eq for dyn IqHandler {}
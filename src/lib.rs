@@ -1,13 +1,57 @@
 #![forbid(unsafe_code)]
 
+#[cfg(not(any(feature = "tls-native", feature = "tls-rustls")))]
+compile_error!("either the `tls-native` or the `tls-rustls` feature must be enabled");
+#[cfg(all(feature = "tls-native", feature = "tls-rustls"))]
+compile_error!("the `tls-native` and `tls-rustls` features cannot both be enabled");
+
+#[cfg(feature = "blocking")]
+mod blocking;
+mod client;
 mod config;
 mod connection;
+mod credentials;
+mod dedup;
 mod error;
+mod filter;
+mod iq;
+mod logging;
 mod message;
+mod messages;
+mod metrics;
+mod presence;
+mod proxy;
+mod queue;
+mod resume;
+mod sequence;
+mod source;
 mod stream;
+mod tls;
+mod ugc;
 
-pub use config::{Channel, Config, Server};
-pub use connection::Connection;
-pub use error::{Error, Result};
-pub use message::Message;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingStream;
+pub use config::{
+    AddressFamily, Backoff, Channel, Config, ConfigBuilder, History, OverflowPolicy, Oversize,
+    Proxy, ProxyAuth, Resource, SaslMechanism, SaslPolicy, Server, SoftwareVersion, Transport,
+};
+pub use connection::{Connection, ConnectionEvent, ConnectionInfo, ConnectionStats, EndOutcome};
+pub use credentials::CredentialProvider;
+pub use dedup::{Dedup, DedupById, DedupConfig, DedupKey};
+pub use error::{Error, MessageParseError, Result};
+pub use filter::MessageFilter;
+pub use iq::IqHandler;
+pub use jid;
+pub use message::{Message, ParseMode, ProductCategory, UnparsedMessage, WmoHeading};
+pub use messages::Messages;
+pub use metrics::StreamMetrics;
+pub use presence::{Occupant, Presence, PresenceStatus};
+pub use resume::ResumeState;
+pub use source::{FileSource, MessageSource};
 pub use stream::{ConnectionState, Stream, StreamEvent};
+pub use tls::TlsConnector;
+#[cfg(feature = "tls-native")]
+pub use tokio_native_tls::native_tls;
+#[cfg(feature = "tls-rustls")]
+pub use tokio_rustls::rustls;
+pub use ugc::{Ugc, UgcCode, UgcKind};
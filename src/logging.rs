@@ -0,0 +1,6 @@
+// The logging macros used throughout the crate, from `tracing` with the `tracing` feature and from
+// `log` otherwise
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, trace, warn};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, trace, warn};
@@ -1,3 +1,5 @@
+use crate::MessageParseError;
+
 /// A message received from NWWS-OI.
 ///
 /// See the [NWS Communications Header Policy Document](https://www.weather.gov/tg/awips) for
@@ -26,7 +28,9 @@ pub struct Message {
     /// The time at which the message was originally sent by the NWS ingest process to the NWWS-OI
     /// XMPP server, if it differs substantially from the current time.
     ///
-    /// See [XEP-0203](https://xmpp.org/extensions/xep-0203.html) for more details.
+    /// See [XEP-0203](https://xmpp.org/extensions/xep-0203.html) for more details. The legacy
+    /// [XEP-0091](https://xmpp.org/extensions/xep-0091.html) delay is used if that is all the
+    /// server sent.
     pub delay_stamp: Option<chrono::DateTime<chrono::FixedOffset>>,
 
     /// The LDM sequence number assigned to this product.
@@ -38,16 +42,129 @@ pub struct Message {
 
     /// The contents of the message
     pub message: String,
+
+    /// The `<message>` stanza from which this message was parsed
+    ///
+    /// This retains everything which arrived over the wire, including the `<html>` body, any
+    /// payloads this crate does not model, and any attributes of the `nwws-oi` payload which are
+    /// not parsed into the fields above. Messages parsed from an `xmpp_parsers::Element` retain
+    /// that exact element; messages parsed from an `xmpp_parsers::message::Message` retain its
+    /// conversion back into an element.
+    pub raw: Option<xmpp_parsers::Element>,
+}
+
+impl Message {
+    /// Parse the WMO abbreviated heading from the first line of the message.
+    ///
+    /// Some products repeat the heading on subsequent lines; only the first line is considered.
+    pub fn wmo_heading(&self) -> Option<WmoHeading> {
+        self.message.lines().next().and_then(WmoHeading::parse)
+    }
+
+    /// Parse the first UGC block from the message, e.g. `KYC111-112-040300-`.
+    ///
+    /// Products with several segments carry a UGC block in each segment; only the first is
+    /// considered. Returns `None` if the message has no UGC block or if it is malformed.
+    pub fn ugc(&self) -> Option<crate::Ugc> {
+        crate::Ugc::find(&self.message)
+    }
+
+    /// The XHTML alternative to the message's plain text `<body>`, from its
+    /// [XEP-0071](https://xmpp.org/extensions/xep-0071.html) `<html>` payload.
+    ///
+    /// This is the serialized XHTML `<body>` element, including its namespace declaration, e.g.
+    /// `<body xmlns='http://www.w3.org/1999/xhtml'>KLMK issues RRM valid 2022-02-04T02:54:00Z</body>`.
+    /// NWWS-OI sends this summary rather than the product itself, which is always `message`.
+    ///
+    /// Returns `None` if the stanza had no XHTML body, or if `raw` is `None`.
+    pub fn html_body(&self) -> Option<String> {
+        self.raw
+            .as_ref()?
+            .get_child("html", "http://jabber.org/protocol/xhtml-im")?
+            .get_child("body", "http://www.w3.org/1999/xhtml")
+            .map(String::from)
+    }
+
+    /// The product category: the first three characters of the AWIPS ID, e.g. `TOR` or `AFD`.
+    ///
+    /// Returns `None` if the message has no AWIPS ID.
+    pub fn product_category(&self) -> Option<&str> {
+        self.awips_id.as_deref().and_then(|id| id.get(..3))
+    }
+
+    /// The product category, parsed into a [`ProductCategory`](enum.ProductCategory.html).
+    ///
+    /// Returns `None` if the message has no AWIPS ID.
+    pub fn category(&self) -> Option<ProductCategory> {
+        self.product_category().map(ProductCategory::from)
+    }
+
+    /// Whether this is a communications test rather than a real product.
+    ///
+    /// These are recognized as tests:
+    ///
+    /// * WMO headings beginning `NT`, which WMO reserves for test messages, like the Pacific
+    ///   Tsunami Warning Center's `NTXX98 PHEB` redundant-side test
+    /// * `WOUS99 KNCF`, the hourly communications test from the AWIPS Network Control Facility
+    /// * AWIPS IDs beginning `TST`
+    /// * a line of the message consisting only of the `RZRZRZ...` test pattern, at least eight
+    ///   characters long
+    /// * a line of the message which is exactly `TEST`, or which begins with `THIS IS A TEST` or
+    ///   `THIS IS A COMMUNICATIONS TEST`
+    ///
+    /// Other mentions of "test" do not count, so that real products which discuss tests, like a
+    /// statement about siren testing, are not mistaken for test messages.
+    pub fn is_test(&self) -> bool {
+        if self.ttaaii.starts_with("NT")
+            || (self.ttaaii == "WOUS99" && self.cccc == "KNCF")
+            || self.product_category() == Some("TST")
+        {
+            return true;
+        }
+
+        self.message.lines().map(str::trim).any(|line| {
+            let pattern = line.len() >= 8
+                && line.len() % 2 == 0
+                && line.as_bytes().chunks(2).all(|pair| pair == b"RZ");
+            pattern
+                || line == "TEST"
+                || line.starts_with("THIS IS A TEST")
+                || line.starts_with("THIS IS A COMMUNICATIONS TEST")
+        })
+    }
+
+    /// The MUC room this message was received from, e.g. `nwws@conference.nwws-oi.weather.gov`.
+    ///
+    /// This tells apart messages from
+    /// [`Config::additional_channels`](struct.Config.html#structfield.additional_channels). It
+    /// is read from `raw`, so it is `None` for messages which were not parsed from a stanza.
+    pub fn source_channel(&self) -> Option<jid::BareJid> {
+        let from: jid::Jid = self.raw.as_ref()?.attr("from")?.parse().ok()?;
+        Some(from.into())
+    }
+
+    /// A canonical identifier for this product, e.g. `202202040254-KLMK-SRUS43-RRMLMK`.
+    ///
+    /// This is `{issue}-{cccc}-{ttaaii}-{awips_id}`, with `issue` in UTC formatted as
+    /// `%Y%m%d%H%M`, which is the naming convention used by archives such as the
+    /// [IEM](https://mesonet.agron.iastate.edu/). Products without an AWIPS ID use `NONE` in its
+    /// place.
+    pub fn product_id(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            self.issue.with_timezone(&chrono::Utc).format("%Y%m%d%H%M"),
+            self.cccc,
+            self.ttaaii,
+            self.awips_id.as_deref().unwrap_or("NONE")
+        )
+    }
 }
 
 impl TryFrom<xmpp_parsers::Element> for Message {
-    type Error = ();
+    type Error = MessageParseError;
 
     fn try_from(value: xmpp_parsers::Element) -> Result<Self, Self::Error> {
-        xmpp_parsers::message::Message::try_from(value)
-            .ok()
-            .and_then(|msg| Self::try_from(msg).ok())
-            .ok_or(())
+        Self::parse_element(value, ParseMode::Lenient).map_err(|(e, _)| e)
     }
 }
 
@@ -55,91 +172,697 @@ impl TryFrom<xmpp_parsers::message::Message> for Message {
     type Error = xmpp_parsers::message::Message;
 
     fn try_from(value: xmpp_parsers::message::Message) -> std::result::Result<Self, Self::Error> {
+        match Self::parse(&value, ParseMode::Lenient) {
+            Ok(msg) => Ok(Self {
+                raw: Some(value.into()),
+                ..msg
+            }),
+            Err(_) => Err(value),
+        }
+    }
+}
+
+impl std::str::FromStr for Message {
+    type Err = MessageParseError;
+
+    /// Parse a `<message/>` stanza from its XML, like `ParseMode::Lenient`.
+    ///
+    /// This accepts stanzas as logged by `nwws_oi` at the trace level. Returns
+    /// `Err(MessageParseError::InvalidXml)` if `s` is not a single XML element, and otherwise
+    /// fails like `TryFrom<Element>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let element = s
+            .trim()
+            .parse::<xmpp_parsers::Element>()
+            .map_err(|e| MessageParseError::InvalidXml(e.to_string()))?;
+        Self::try_from(element)
+    }
+}
+
+impl TryFrom<&str> for Message {
+    type Error = MessageParseError;
+
+    /// Equivalent to [`str::parse()`](#impl-FromStr).
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The product text, as a NOAAPort or AFOS consumer would expect it.
+///
+/// This is the LDM sequence number as a zero-padded three digit line, if there is one, followed
+/// by `message` exactly as received, beginning with the WMO heading. Lines end with `\n`; no
+/// control characters are added.
+///
+/// ```text
+/// 987
+/// SRUS43 KLMK 040254
+/// RRMLMK
+/// .ER PRSK2 20220203 Z DC202202040254/DUE/DQG/DH17/HGIFE/DIH1/
+/// ```
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(sequence) = self.ldm_sequence_number {
+            writeln!(f, "{:03}", sequence)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// A stanza which carries an NWWS-OI product, but which could not be parsed into a
+/// [`Message`](struct.Message.html).
+///
+/// These usually indicate that the NWWS-OI format has changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnparsedMessage {
+    /// Why parsing failed, e.g. `MissingAttribute("ttaaii")`, which displays as
+    /// `missing ttaaii attribute`
+    pub error: MessageParseError,
+
+    /// The XMPP stanza
+    pub raw: xmpp_parsers::Element,
+}
+
+/// How products are parsed into [`Message`](struct.Message.html)s, for
+/// [`Config::parse_mode`](struct.Config.html#structfield.parse_mode).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ParseMode {
+    /// Repair the product text, and accept unusual attributes.
+    ///
+    /// Doubled newlines are undone, the LDM sequence number is moved into
+    /// `ldm_sequence_number`, and a delay stamp which cannot be parsed is ignored. Timestamps
+    /// without an offset are taken to be UTC, and may omit the seconds.
+    Lenient,
+    /// Repair the product text like `Lenient`, but reject products whose attributes are malformed.
+    ///
+    /// `ttaaii` must be six letters and digits, `cccc` four, `awipsid` empty or up to six, `id`
+    /// two numbers separated by a period, and `issue` and any delay stamp must be RFC 3339
+    /// timestamps. Rejected products are reported as
+    /// [`UnparsedMessage`](struct.UnparsedMessage.html)s.
+    Strict,
+    /// Deliver the product text exactly as received, without undoing doubled newlines or removing
+    /// the LDM sequence number. Timestamps are parsed like `Lenient`.
+    Raw,
+}
+
+impl Default for ParseMode {
+    fn default() -> Self {
+        ParseMode::Lenient
+    }
+}
+
+impl Message {
+    /// Parse a `<message/>` stanza using `mode`.
+    ///
+    /// Returns `Err(None)` if the stanza does not carry a product, or `Err(Some(UnparsedMessage))`
+    /// if it carries a product which could not be parsed. The `TryFrom` implementations parse
+    /// like `ParseMode::Lenient`.
+    #[allow(clippy::result_large_err)]
+    pub fn parse_with(
+        element: xmpp_parsers::Element,
+        mode: ParseMode,
+    ) -> Result<Self, Option<UnparsedMessage>> {
+        match Self::parse_element(element, mode) {
+            Ok(msg) => Ok(msg),
+            Err((error, _)) if !error.is_product() => Err(None),
+            Err((error, raw)) => Err(Some(UnparsedMessage { error, raw })),
+        }
+    }
+
+    /// Parse a stanza using `mode`, returning it alongside the error if that fails.
+    #[allow(clippy::result_large_err)]
+    fn parse_element(
+        element: xmpp_parsers::Element,
+        mode: ParseMode,
+    ) -> Result<Self, (MessageParseError, xmpp_parsers::Element)> {
+        let result = match xmpp_parsers::message::Message::try_from(element.clone()) {
+            Ok(msg) => Self::parse(&msg, mode),
+            Err(_) if !element.has_child("x", "nwws-oi") => Err(MessageParseError::MissingPayload),
+            Err(e) => Err(MessageParseError::InvalidStanza(e.to_string())),
+        };
+        match result {
+            Ok(msg) => Ok(Self {
+                raw: Some(element),
+                ..msg
+            }),
+            Err(e) => Err((e, element)),
+        }
+    }
+
+    /// Parse everything except `raw`.
+    fn parse(
+        value: &xmpp_parsers::message::Message,
+        mode: ParseMode,
+    ) -> Result<Self, MessageParseError> {
         if value.type_ != xmpp_parsers::message::MessageType::Groupchat {
-            return Err(value);
+            return Err(MessageParseError::NotGroupchat);
         }
 
-        let delay_stamp = value
+        let oi = value
             .payloads
             .iter()
-            .find(|p| p.is("delay", "urn:xmpp:delay"))
-            .and_then(|delay| delay.attr("stamp"))
-            .and_then(|v| chrono::DateTime::parse_from_rfc3339(v).ok());
+            .find(|p| p.is("x", "nwws-oi"))
+            .ok_or(MessageParseError::MissingPayload)?;
 
-        let oi = if let Some(oi) = value.payloads.iter().find(|p| p.is("x", "nwws-oi")) {
-            oi
-        } else {
-            return Err(value);
+        let delay = value
+            .payloads
+            .iter()
+            .find(|p| p.is("delay", "urn:xmpp:delay"))
+            .and_then(|delay| delay.attr("stamp"));
+        let legacy_delay = value
+            .payloads
+            .iter()
+            .find(|p| p.is("x", "jabber:x:delay"))
+            .and_then(|delay| delay.attr("stamp"));
+        let timestamp = |v: &str| match mode {
+            ParseMode::Strict => chrono::DateTime::parse_from_rfc3339(v),
+            ParseMode::Lenient | ParseMode::Raw => parse_timestamp(v),
         };
+        let delay_stamp = delay.and_then(|v| timestamp(v).ok()).or_else(|| {
+            // XEP-0091 stamps are always UTC, formatted like 20020910T23:08:25
+            legacy_delay
+                .and_then(|v| chrono::NaiveDateTime::parse_from_str(v, "%Y%m%dT%H:%M:%S").ok())
+                .map(|v| chrono::TimeZone::from_utc_datetime(&chrono::Utc, &v).into())
+        });
+        if mode == ParseMode::Strict && delay_stamp.is_none() {
+            if let Some(stamp) = delay.or(legacy_delay) {
+                return Err(MessageParseError::InvalidDelayStamp(stamp.into()));
+            }
+        }
 
         let message = oi.text();
 
-        // Some messages have every \n replaced with \n\n
-        // Detect and undo that transformation
-        let message = if message.matches("\n").count() == message.matches("\n\n").count() * 2 {
-            message.replace("\n\n", "\n")
+        let (ldm_sequence_number, message) = if mode == ParseMode::Raw {
+            (None, message)
         } else {
-            message
-        };
+            let message = undouble_newlines(message);
 
-        // Fish out the LDM sequence number, if any
-        let (ldm_sequence_number, message) = match {
-            let mut i = message.splitn(3, '\n');
-            (i.next(), i.next().and_then(|s| s.parse().ok()), i.next())
-        } {
-            (Some(""), Some(ldm_sequence_number), Some(rest)) => {
-                (Some(ldm_sequence_number), rest.into())
+            // Fish out the LDM sequence number, if any
+            let mut lines = message.splitn(3, '\n');
+            match (
+                lines.next(),
+                lines.next().and_then(|s| s.parse().ok()),
+                lines.next(),
+            ) {
+                (Some(""), Some(ldm_sequence_number), Some(rest)) => {
+                    (Some(ldm_sequence_number), rest.into())
+                }
+                _ => (None, message),
             }
-            _ => (None, message),
         };
 
-        return match (
-            oi.attr("awipsid"),
-            oi.attr("cccc"),
-            oi.attr("id"),
-            oi.attr("issue").map(chrono::DateTime::parse_from_rfc3339),
-            oi.attr("ttaaii"),
-        ) {
-            (Some(awipsid), Some(cccc), Some(id), Some(Ok(issue)), Some(ttaaii)) => Ok(Self {
-                awips_id: Some(awipsid).filter(|s| s.len() > 0).map(|s| s.into()),
-                cccc: cccc.into(),
-                id: id.into(),
-                issue,
-                ttaaii: ttaaii.into(),
-                delay_stamp,
-                ldm_sequence_number,
-                message,
-            }),
-            _ => Err(value),
+        let attr = |name| {
+            oi.attr(name)
+                .ok_or(MessageParseError::MissingAttribute(name))
         };
+        let awipsid = attr("awipsid")?;
+        let cccc = attr("cccc")?;
+        let id = attr("id")?;
+        let issue = timestamp(attr("issue")?).map_err(MessageParseError::InvalidIssue)?;
+        let ttaaii = attr("ttaaii")?;
+
+        if mode == ParseMode::Strict {
+            let alphanumeric = |s: &str, lengths: std::ops::RangeInclusive<usize>| {
+                lengths.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric())
+            };
+            let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+            let invalid =
+                |name, value: &str| Err(MessageParseError::InvalidAttribute(name, value.into()));
+            if !alphanumeric(ttaaii, 6..=6) {
+                return invalid("ttaaii", ttaaii);
+            }
+            if !alphanumeric(cccc, 4..=4) {
+                return invalid("cccc", cccc);
+            }
+            if !awipsid.is_empty() && !alphanumeric(awipsid, 1..=6) {
+                return invalid("awipsid", awipsid);
+            }
+            if !matches!(id.split_once('.'), Some((pid, seq)) if numeric(pid) && numeric(seq)) {
+                return invalid("id", id);
+            }
+        }
+
+        Ok(Self {
+            awips_id: Some(awipsid).filter(|s| !s.is_empty()).map(|s| s.into()),
+            cccc: cccc.into(),
+            id: id.into(),
+            issue,
+            ttaaii: ttaaii.into(),
+            delay_stamp,
+            ldm_sequence_number,
+            message,
+            raw: None,
+        })
+    }
+}
+
+/// Parse an RFC 3339 timestamp, or failing that, one without an offset, which is taken to be UTC.
+///
+/// NWWS-OI has been seen to send `issue` without the `Z`, and without the seconds. The error is
+/// the RFC 3339 one.
+fn parse_timestamp(
+    value: &str,
+) -> Result<chrono::DateTime<chrono::FixedOffset>, chrono::ParseError> {
+    chrono::DateTime::parse_from_rfc3339(value).or_else(|e| {
+        ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"]
+            .iter()
+            .find_map(|format| chrono::NaiveDateTime::parse_from_str(value, format).ok())
+            .map(|v| chrono::TimeZone::from_utc_datetime(&chrono::Utc, &v).into())
+            .ok_or(e)
+    })
+}
+
+/// Undo the replacement of every `\n` with `\n\n`, which many messages have undergone.
+///
+/// The body is only collapsed if it is uniformly doubled: every run of consecutive newlines must
+/// have an even length, so that each newline is one half of a pair. A single newline anywhere
+/// shows that the body was not doubled, and that its blank lines are intentional.
+fn undouble_newlines(message: String) -> String {
+    let doubled = message.split(|c| c != '\n').all(|run| run.len() % 2 == 0);
+    if !doubled || !message.contains('\n') {
+        return message;
+    }
+
+    // Halve each run, so that a doubled blank line becomes a single blank line
+    let mut undoubled = String::with_capacity(message.len());
+    let mut newlines = 0;
+    for c in message.chars() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines % 2 == 0 {
+                undoubled.push(c);
+            }
+        } else {
+            newlines = 0;
+            undoubled.push(c);
+        }
+    }
+    undoubled
+}
+
+/// The category of an NWS product, from the first three characters of its AWIPS ID.
+///
+/// NWS Directive 10-1701, "Text Product Formats and Codes", lists every category. Categories
+/// without a variant of their own are represented as `Other`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ProductCategory {
+    /// `TOR`
+    TornadoWarning,
+    /// `SVR`
+    SevereThunderstormWarning,
+    /// `SVS`
+    SevereWeatherStatement,
+    /// `FFW`
+    FlashFloodWarning,
+    /// `FFS`
+    FlashFloodStatement,
+    /// `FLW`
+    FloodWarning,
+    /// `FLS`
+    FloodStatement,
+    /// `WSW`
+    WinterWeatherMessage,
+    /// `NPW`
+    NonPrecipitationMessage,
+    /// `SPS`
+    SpecialWeatherStatement,
+    /// `HWO`
+    HazardousWeatherOutlook,
+    /// `AFD`
+    AreaForecastDiscussion,
+    /// `ZFP`
+    ZoneForecast,
+    /// `RR1` through `RR9`, `RRA`, `RRM`, and `RRS`
+    HydrometeorologicalData,
+    /// Any other category, e.g. `CFP`
+    Other(String),
+}
+
+impl From<&str> for ProductCategory {
+    fn from(category: &str) -> Self {
+        match category {
+            "TOR" => Self::TornadoWarning,
+            "SVR" => Self::SevereThunderstormWarning,
+            "SVS" => Self::SevereWeatherStatement,
+            "FFW" => Self::FlashFloodWarning,
+            "FFS" => Self::FlashFloodStatement,
+            "FLW" => Self::FloodWarning,
+            "FLS" => Self::FloodStatement,
+            "WSW" => Self::WinterWeatherMessage,
+            "NPW" => Self::NonPrecipitationMessage,
+            "SPS" => Self::SpecialWeatherStatement,
+            "HWO" => Self::HazardousWeatherOutlook,
+            "AFD" => Self::AreaForecastDiscussion,
+            "ZFP" => Self::ZoneForecast,
+            "RR1" | "RR2" | "RR3" | "RR4" | "RR5" | "RR6" | "RR7" | "RR8" | "RR9" | "RRA"
+            | "RRM" | "RRS" => Self::HydrometeorologicalData,
+            other => Self::Other(other.into()),
+        }
+    }
+}
+
+/// A WMO abbreviated heading, e.g. `SRUS43 KLMK 040254` or `WFUS53 KJKL 040254 CCA`.
+///
+/// See the [NWS Communications Header Policy Document](https://www.weather.gov/tg/awips) for the
+/// meaning of each field.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WmoHeading {
+    /// The data type designator, e.g. `SR`
+    pub t1t2: String,
+
+    /// The geographical designator, e.g. `US`
+    pub a1a2: String,
+
+    /// The two digit bulletin number, e.g. `43`
+    pub ii: String,
+
+    /// Four character issuing center
+    pub cccc: String,
+
+    /// The day of the month
+    pub day: u8,
+
+    /// The hour, in UTC
+    pub hour: u8,
+
+    /// The minute
+    pub minute: u8,
+
+    /// The BBB indicator, if any, e.g. `RRA` (delayed), `AAB` (amended), or `CCA` (corrected)
+    pub bbb: Option<String>,
+}
+
+impl WmoHeading {
+    fn parse(line: &str) -> Option<Self> {
+        fn is(s: &str, len: usize, f: fn(&u8) -> bool) -> bool {
+            s.len() == len && s.as_bytes().iter().all(f)
+        }
+        fn upper(b: &u8) -> bool {
+            b.is_ascii_uppercase()
+        }
+        fn digit(b: &u8) -> bool {
+            b.is_ascii_digit()
+        }
+        fn upper_or_digit(b: &u8) -> bool {
+            b.is_ascii_uppercase() || b.is_ascii_digit()
+        }
+
+        let mut parts = line.split_ascii_whitespace();
+        let (ttaaii, cccc, yygggg, bbb) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next());
+        if parts.next().is_some() {
+            return None;
+        }
+
+        if !(is(ttaaii, 6, upper_or_digit)
+            && is(&ttaaii[..4], 4, upper)
+            && is(&ttaaii[4..], 2, digit)
+            && is(cccc, 4, upper_or_digit)
+            && is(yygggg, 6, digit)
+            && bbb.map_or(true, |bbb| is(bbb, 3, upper_or_digit)))
+        {
+            return None;
+        }
+
+        let (day, hour, minute) = (
+            yygggg[0..2].parse().ok()?,
+            yygggg[2..4].parse().ok()?,
+            yygggg[4..6].parse().ok()?,
+        );
+        if !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+            return None;
+        }
+
+        Some(Self {
+            t1t2: ttaaii[0..2].into(),
+            a1a2: ttaaii[2..4].into(),
+            ii: ttaaii[4..6].into(),
+            cccc: cccc.into(),
+            day,
+            hour,
+            minute,
+            bbb: bbb.map(|s| s.into()),
+        })
+    }
+}
+
+/// Builds `Message`s for tests, starting from a KLMK SRUS43 product.
+#[cfg(test)]
+pub(crate) struct MessageBuilder(Message);
+
+#[cfg(test)]
+impl Message {
+    pub(crate) fn builder() -> MessageBuilder {
+        MessageBuilder(Message {
+            ttaaii: "SRUS43".into(),
+            cccc: "KLMK".into(),
+            awips_id: Some("RRMLMK".into()),
+            issue: "2022-02-04T02:54:00Z".parse().unwrap(),
+            id: "14425.25117".into(),
+            delay_stamp: None,
+            ldm_sequence_number: None,
+            message: "SRUS43 KLMK 040254\n".into(),
+            raw: None,
+        })
+    }
+}
+
+#[cfg(test)]
+impl MessageBuilder {
+    pub(crate) fn ttaaii(mut self, ttaaii: &str) -> Self {
+        self.0.ttaaii = ttaaii.into();
+        self
+    }
+
+    pub(crate) fn cccc(mut self, cccc: &str) -> Self {
+        self.0.cccc = cccc.into();
+        self
+    }
+
+    pub(crate) fn awips_id(mut self, awips_id: Option<&str>) -> Self {
+        self.0.awips_id = awips_id.map(Into::into);
+        self
+    }
+
+    pub(crate) fn issue(mut self, issue: &str) -> Self {
+        self.0.issue = issue.parse().unwrap();
+        self
+    }
+
+    pub(crate) fn id(mut self, id: &str) -> Self {
+        self.0.id = id.into();
+        self
+    }
+
+    pub(crate) fn message(mut self, message: &str) -> Self {
+        self.0.message = message.into();
+        self
+    }
+
+    pub(crate) fn build(self) -> Message {
+        self.0
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::Timelike;
 
-    fn msg(xml: &str) -> Result<Message, ()> {
+    fn stamp(s: &str) -> chrono::DateTime<chrono::FixedOffset> {
+        s.parse().unwrap()
+    }
+
+    fn msg(xml: &str) -> Result<Message, MessageParseError> {
         let element: xmpp_parsers::Element = xml.parse().unwrap();
-        let msg: xmpp_parsers::message::Message = element.try_into().unwrap();
 
-        Message::try_from(msg).map_err(|_| ())
+        // Fixtures compare only the parsed fields; see `raw()` for the retained element
+        Message::try_from(element).map(|msg| Message { raw: None, ..msg })
     }
 
     #[test]
     fn parse_banner() {
         assert_eq!(
             msg("<message xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov\" to=\"w.glynn@nwws-oi.weather.gov/todo\" type=\"groupchat\"><subject>National Weather Wire Service Open Interface</subject><delay xmlns=\"urn:xmpp:delay\" from=\"nwws@conference.nwws-oi.weather.gov\" stamp=\"2015-02-03T20:48:44.222Z\"/></message>"),
-            Err(())
+            Err(MessageParseError::MissingPayload)
+        );
+    }
+
+    #[test]
+    fn from_str() {
+        let xml = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n]]></x></message>";
+        let parsed: Message = xml.parse().unwrap();
+        assert_eq!(
+            Message {
+                raw: None,
+                ..parsed.clone()
+            },
+            msg(xml).unwrap()
+        );
+        assert_eq!(
+            parsed.raw,
+            Some(xml.parse::<xmpp_parsers::Element>().unwrap())
+        );
+        // Surrounding whitespace, like a trailing newline from a capture, is ignored
+        assert_eq!(
+            Message::try_from(format!("{}\n", xml).as_str()).unwrap(),
+            parsed
+        );
+
+        assert!(matches!(
+            "<message xmlns=\"jabber:client\"><body>truncated".parse::<Message>(),
+            Err(MessageParseError::InvalidXml(_))
+        ));
+        assert!(matches!(
+            "<message xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov\" type=\"groupchat\"><subject>National Weather Wire Service Open Interface</subject></message>".parse::<Message>(),
+            Err(MessageParseError::MissingPayload)
+        ));
+        match "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KKCI\" issue=\"2022-02-04T02:00:00Z\" awipsid=\"CFP03\" id=\"14425.22838\"/></message>".parse::<Message>() {
+            Err(e) => assert_eq!(e, MessageParseError::MissingAttribute("ttaaii")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn parse_unparsed() {
+        let parse = |xml: &str| Message::parse_with(xml.parse().unwrap(), ParseMode::Lenient);
+
+        // Not products at all
+        assert!(matches!(
+            parse("<message xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov\" to=\"w.glynn@nwws-oi.weather.gov/todo\" type=\"groupchat\"><subject>National Weather Wire Service Open Interface</subject></message>"),
+            Err(None)
+        ));
+
+        // Products which do not parse
+        let xml = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KKCI issues CFP valid 2022-02-04T02:00:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KKCI\" issue=\"2022-02-04T02:00:00Z\" awipsid=\"CFP03\" id=\"14425.22838\"><![CDATA[\n\n631\n\nFAUS29 KKCI 040200\n\n]]></x></message>";
+        match parse(xml) {
+            Err(Some(unparsed)) => {
+                assert_eq!(unparsed.error.to_string(), "missing ttaaii attribute");
+                assert_eq!(
+                    unparsed.error,
+                    MessageParseError::MissingAttribute("ttaaii")
+                );
+                assert!(unparsed.error.is_product());
+                assert_eq!(unparsed.raw, xml.parse::<xmpp_parsers::Element>().unwrap());
+            }
+            other => panic!("{:?}", other),
+        }
+        match parse("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KKCI\" ttaaii=\"FAUS29\" issue=\"yesterday\" awipsid=\"CFP03\" id=\"14425.22838\"/></message>") {
+            Err(Some(unparsed)) => {
+                assert!(unparsed
+                    .error
+                    .to_string()
+                    .starts_with("invalid issue attribute"));
+                assert!(matches!(unparsed.error, MessageParseError::InvalidIssue(_)));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_issue() {
+        let klmk = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"ISSUE\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\n]]></x></message>";
+        let issue = |value: &str| {
+            Message::parse_with(
+                klmk.replace("ISSUE", value).parse().unwrap(),
+                ParseMode::Lenient,
+            )
+            .map(|message| message.issue)
+            .map_err(|unparsed| unparsed.unwrap().error.to_string())
+        };
+        let utc = |h: u32, m: u32, s: u32, ms: u32| {
+            stamp(&format!("2022-02-04T{:02}:{:02}:{:02}.{:03}Z", h, m, s, ms))
+        };
+
+        assert_eq!(issue("2022-02-04T02:54:00Z"), Ok(utc(2, 54, 0, 0)));
+        assert_eq!(issue("2022-02-04T02:54:00.250Z"), Ok(utc(2, 54, 0, 250)));
+        assert_eq!(issue("2022-02-03T20:54:00-06:00"), Ok(utc(2, 54, 0, 0)));
+        assert_eq!(
+            issue("2022-02-03T20:54:00-06:00").unwrap().offset(),
+            &chrono::FixedOffset::west_opt(6 * 3600).unwrap()
+        );
+        // Without an offset, UTC is assumed
+        assert_eq!(issue("2022-02-04T02:54:00"), Ok(utc(2, 54, 0, 0)));
+        assert_eq!(issue("2022-02-04T02:54:00.250"), Ok(utc(2, 54, 0, 250)));
+        assert_eq!(issue("2022-02-04T02:54"), Ok(utc(2, 54, 0, 0)));
+        assert_eq!(
+            issue("2022-02-04"),
+            Err("invalid issue attribute: premature end of input".to_string())
         );
     }
 
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn parse_modes() {
+        let parse = |xml: &str, mode| Message::parse_with(xml.parse().unwrap(), mode);
+        let klmk = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n]]></x><delay xmlns=\"urn:xmpp:delay\" stamp=\"2022-02-04T02:55:11.810Z\"/></message>";
+
+        let lenient = parse(klmk, ParseMode::Lenient).unwrap();
+        assert_eq!(lenient.message, "SRUS43 KLMK 040254\nRRMLMK\n");
+        assert_eq!(lenient.ldm_sequence_number, Some(987));
+        assert_eq!(parse(klmk, ParseMode::Strict).unwrap(), lenient);
+
+        let raw = parse(klmk, ParseMode::Raw).unwrap();
+        assert_eq!(raw.message, "\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n");
+        assert_eq!(raw.ldm_sequence_number, None);
+        assert_eq!(raw.delay_stamp, lenient.delay_stamp);
+
+        // Malformed attributes are only rejected by Strict
+        for (from, to, reason) in [
+            (
+                "ttaaii=\"SRUS43\"",
+                "ttaaii=\"SRUS4\"",
+                "invalid ttaaii attribute: \"SRUS4\"",
+            ),
+            (
+                "cccc=\"KLMK\"",
+                "cccc=\"K LMK\"",
+                "invalid cccc attribute: \"K LMK\"",
+            ),
+            (
+                "awipsid=\"RRMLMK\"",
+                "awipsid=\"RRM-LMK\"",
+                "invalid awipsid attribute: \"RRM-LMK\"",
+            ),
+            (
+                "id=\"14425.25117\"",
+                "id=\"14425\"",
+                "invalid id attribute: \"14425\"",
+            ),
+            (
+                "stamp=\"2022-02-04T02:55:11.810Z\"",
+                "stamp=\"soon\"",
+                "invalid delay stamp: \"soon\"",
+            ),
+            (
+                "issue=\"2022-02-04T02:54:00Z\"",
+                "issue=\"2022-02-04T02:54:00\"",
+                "invalid issue attribute: premature end of input",
+            ),
+        ] {
+            let xml = klmk.replace(from, to);
+            assert!(parse(&xml, ParseMode::Lenient).is_ok(), "{}", xml);
+            match parse(&xml, ParseMode::Strict) {
+                Err(Some(unparsed)) => assert_eq!(unparsed.error.to_string(), reason),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        // A missing AWIPS ID is valid
+        let ptwc = klmk.replace("awipsid=\"RRMLMK\"", "awipsid=\"\"");
+        assert_eq!(parse(&ptwc, ParseMode::Strict).unwrap().awips_id, None);
+    }
+
     #[test]
     fn parse_terms() {
         assert_eq!(
             msg("<message xmlns=\"jabber:client\" from=\"nwws-oi.weather.gov\" to=\"w.glynn@nwws-oi.weather.gov/uuid/56d00e55-29f5-446a-8e18-0dd6af8e7dcd\"><subject>US Federal Government</subject><body>**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**\n\nThis is a United States Federal Government computer system, which may be\naccessed and used only for official Government business by authorized\npersonnel.  Unauthorized access or use of this computer system may\nsubject violators to criminal, civil, and/or administrative action.\n\nAll information on this computer system may be intercepted, recorded,\nread, copied, and disclosed by and to authorized personnel for official\npurposes, including criminal investigations. Access or use of this\ncomputer system by any person whether authorized or unauthorized,\nCONSTITUTES CONSENT to these terms.\n\n**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**WARNING**</body></message>"),
-            Err(())
+            Err(MessageParseError::NotGroupchat)
         );
     }
 
@@ -153,13 +876,14 @@ mod tests {
                 awips_id: Some(
                     "RRMLMK".into()
                 ),
-                issue: chrono::DateTime::from_utc(chrono::NaiveDate::from_ymd(2022, 2, 4).and_hms(2, 54, 0), chrono::FixedOffset::east(0)),
+                issue: stamp("2022-02-04T02:54:00Z"),
                 id: "14425.25117".into(),
                 delay_stamp: Some(
-                    chrono::DateTime::from_utc(chrono::NaiveDate::from_ymd(2022, 2, 4).and_hms(2, 55, 11).with_nanosecond(810_000_000).unwrap(), chrono::FixedOffset::east(0))
+                    stamp("2022-02-04T02:55:11.810Z")
                 ),
                 ldm_sequence_number: Some(987),
                 message: "SRUS43 KLMK 040254\nRRMLMK\n.ER PRSK2 20220203 Z DC202202040254/DUE/DQG/DH17/HGIFE/DIH1/\n.E1 15.4/15.6/15.8/16.1/16.5/17.0/17.6/18.1\n.E2 18.6/18.8/18.8/18.9/19.2/19.2/19.3/19.3\n.E3 19.2/19.2/19.2/19.1/19.0/19.0/18.8/18.7\n.E4 18.6/18.4/18.4/18.4/18.4/18.3/18.2/18.1\n.E5 18.1/18.0/17.9/17.9/17.9/17.7/17.7/17.6\n.E6 17.5/17.6/17.5/17.4/17.3/17.2/17.2/17.0\n".into(),
+                raw: None,
             })
         );
 
@@ -169,11 +893,12 @@ mod tests {
                 ttaaii: "SRAK57".into(),
                 cccc: "PAJK".into(),
                 awips_id: Some("RR3AJK".into()),
-                issue: chrono::DateTime::from_utc(chrono::NaiveDate::from_ymd(2022, 2, 4).and_hms(2, 11, 0), chrono::FixedOffset::east(0)),
+                issue: stamp("2022-02-04T02:11:00Z"),
                 id: "14425.24041".into(),
                 delay_stamp: None,
                 ldm_sequence_number: Some(876),
                 message: "SRAK57 PAJK 040211\nRR3AJK\nSRAK57 PAJK 040210\n\n.A NDIA2 220204 Z DH0202/TA 26/TD 27/UD 0/US 0/UG 0/UP 0/PA 29.57\n".into(),
+                raw: None,
             }));
 
         assert_eq!(
@@ -182,14 +907,25 @@ mod tests {
                 ttaaii: "FAUS29".to_string(),
                 cccc: "KKCI".to_string(),
                 awips_id: Some("CFP03".into()),
-                issue: chrono::DateTime::from_utc(chrono::NaiveDate::from_ymd(2022, 2, 4).and_hms(2, 0, 0), chrono::FixedOffset::east(0)),
+                issue: stamp("2022-02-04T02:00:00Z"),
                 id: "14425.22838".into(),
                 delay_stamp: None,
                 ldm_sequence_number: Some(631),
-                message: "FAUS29 KKCI 040200\nCFP03 \nCCFP 20220204_0200 20220204_0800\nCANADA OFF\n".into()
+                message: "FAUS29 KKCI 040200\nCFP03 \nCCFP 20220204_0200 20220204_0800\nCANADA OFF\n".into(),
+                raw: None,
             }));
     }
 
+    #[test]
+    fn parse_legacy_delay() {
+        let message = msg("<message xmlns=\"jabber:client\" to=\"w.glynn@nwws-oi.weather.gov/uuid/851c737e-ead3-460d-b0a6-6749602fccd9\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KKCI issues CFP valid 2022-02-04T02:00:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KKCI\" ttaaii=\"FAUS29\" issue=\"2022-02-04T02:00:00Z\" awipsid=\"CFP03\" id=\"14425.22838\"><![CDATA[\n\n631\n\nFAUS29 KKCI 040200\n\nCFP03 \n\n]]></x><x xmlns=\"jabber:x:delay\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\" stamp=\"20220204T02:01:17\"/></message>").unwrap();
+        assert_eq!(message.delay_stamp, Some(stamp("2022-02-04T02:01:17Z")));
+
+        // XEP-0203 takes precedence
+        let message = msg("<message xmlns=\"jabber:client\" to=\"w.glynn@nwws-oi.weather.gov/uuid/851c737e-ead3-460d-b0a6-6749602fccd9\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KKCI issues CFP valid 2022-02-04T02:00:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KKCI\" ttaaii=\"FAUS29\" issue=\"2022-02-04T02:00:00Z\" awipsid=\"CFP03\" id=\"14425.22838\"><![CDATA[\n\n631\n\nFAUS29 KKCI 040200\n\nCFP03 \n\n]]></x><x xmlns=\"jabber:x:delay\" stamp=\"20220204T02:01:17\"/><delay xmlns=\"urn:xmpp:delay\" stamp=\"2022-02-04T02:01:17.250Z\"/></message>").unwrap();
+        assert_eq!(message.delay_stamp, Some(stamp("2022-02-04T02:01:17.250Z")));
+    }
+
     #[test]
     fn parse_test() {
         assert_eq!(
@@ -198,12 +934,277 @@ mod tests {
                 ttaaii: "NTXX98".into(),
                 cccc: "PHEB".into(),
                 awips_id: None,
-                issue: chrono::DateTime::from_utc(chrono::NaiveDate::from_ymd(2022, 2, 4).and_hms(1, 23, 0), chrono::FixedOffset::east(0)),
+                issue: stamp("2022-02-04T01:23:00Z"),
                 id: "14425.22800".into(),
                 delay_stamp: None,
                 ldm_sequence_number: Some(593),
                 message: "NTXX98 PHEB 040123\nPTWC REDUNDANT-SIDE TEST FROM IRC\nRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZ\nRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZ\n".into(),
+                raw: None,
+            })
+        );
+    }
+
+    #[test]
+    fn raw() {
+        let xml = "<message xmlns=\"jabber:client\" to=\"w.glynn@nwws-oi.weather.gov/uuid/851c737e-ead3-460d-b0a6-6749602fccd9\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>PAJK issues RR3 valid 2022-02-04T02:11:00Z</body><html xmlns=\"http://jabber.org/protocol/xhtml-im\"><body xmlns=\"http://www.w3.org/1999/xhtml\">PAJK issues RR3 valid 2022-02-04T02:11:00Z</body></html><x xmlns=\"nwws-oi\" cccc=\"PAJK\" ttaaii=\"SRAK57\" issue=\"2022-02-04T02:11:00Z\" awipsid=\"RR3AJK\" id=\"14425.24041\" future=\"attribute\"><![CDATA[\n\n876\n\nSRAK57 PAJK 040211\n\nRR3AJK\n\n]]></x></message>";
+        let element: xmpp_parsers::Element = xml.parse().unwrap();
+
+        let message = Message::try_from(element.clone()).unwrap();
+        let raw = message.raw.expect("raw");
+        assert_eq!(raw, element);
+
+        // Re-serializing the retained element reproduces the original stanza
+        let mut reserialized = Vec::new();
+        raw.write_to(&mut reserialized).unwrap();
+        let reparsed: xmpp_parsers::Element =
+            String::from_utf8(reserialized).unwrap().parse().unwrap();
+        assert_eq!(reparsed, element);
+
+        // ...including the parts we don't model
+        assert!(reparsed.has_child("html", "http://jabber.org/protocol/xhtml-im"));
+        assert_eq!(
+            reparsed.get_child("x", "nwws-oi").unwrap().attr("future"),
+            Some("attribute")
+        );
+    }
+
+    #[test]
+    fn undouble_newlines() {
+        let undouble = |s: &str| super::undouble_newlines(s.into());
+
+        // Uniformly doubled, including a doubled blank line
+        assert_eq!(
+            undouble("\n\n987\n\nFGUS73 KFSD 041200\n\nESFFSD\n\n\n\nSPRING FLOOD OUTLOOK\n\n"),
+            "\n987\nFGUS73 KFSD 041200\nESFFSD\n\nSPRING FLOOD OUTLOOK\n"
+        );
+
+        // Single newlines with intentional blank lines are left alone
+        let hydrology =
+            "\n987\nRVAFSD\n\n.B FSD 0204 C DH07/HGIRZ\n\nBRKS2  3.2\nFLDS2  4.1\n\n.END\n";
+        assert_eq!(undouble(hydrology), hydrology);
+        let mixed = "FGUS73 KFSD 041200\nESFFSD\n\n\nSPRING FLOOD OUTLOOK\n\n";
+        assert_eq!(undouble(mixed), mixed);
+
+        // A doubled body with one stray single newline cannot be uniformly undoubled
+        let stray = "FGUS73 KFSD 041200\n\nESFFSD\n\nSPRING FLOOD OUTLOOK\n";
+        assert_eq!(undouble(stray), stray);
+
+        assert_eq!(undouble(""), "");
+        assert_eq!(undouble("no newlines"), "no newlines");
+    }
+
+    #[test]
+    fn html_body() {
+        let xml = "<message xmlns=\"jabber:client\" to=\"w.glynn@nwws-oi.weather.gov/uuid/25976f21-a846-4e08-8890-d750a95d96a2\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><html xmlns=\"http://jabber.org/protocol/xhtml-im\"><body xmlns=\"http://www.w3.org/1999/xhtml\">KLMK issues RRM valid 2022-02-04T02:54:00Z</body></html><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n]]></x></message>";
+        let element: xmpp_parsers::Element = xml.parse().unwrap();
+        let expected = "<body xmlns='http://www.w3.org/1999/xhtml'>KLMK issues RRM valid 2022-02-04T02:54:00Z</body>";
+
+        let message = Message::try_from(element.clone()).unwrap();
+        assert_eq!(message.html_body().as_deref(), Some(expected));
+
+        let message: xmpp_parsers::message::Message = element.try_into().unwrap();
+        let message = Message::try_from(message).unwrap();
+        assert_eq!(message.html_body().as_deref(), Some(expected));
+
+        // No <html> payload
+        let xml = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n]]></x></message>";
+        let element: xmpp_parsers::Element = xml.parse().unwrap();
+        let message = Message::try_from(element).unwrap();
+        assert_eq!(message.html_body(), None);
+        assert_eq!(
+            Message {
+                raw: None,
+                ..message
+            }
+            .html_body(),
+            None
+        );
+    }
+
+    #[test]
+    fn product_category() {
+        let message = |awips_id: Option<&str>| Message::builder().awips_id(awips_id).build();
+
+        let tor = message(Some("TORTOP"));
+        assert_eq!(tor.product_category(), Some("TOR"));
+        assert_eq!(tor.category(), Some(ProductCategory::TornadoWarning));
+        assert_eq!(
+            message(Some("AFDLMK")).category(),
+            Some(ProductCategory::AreaForecastDiscussion)
+        );
+        assert_eq!(
+            message(Some("RR3AJK")).category(),
+            Some(ProductCategory::HydrometeorologicalData)
+        );
+        assert_eq!(
+            message(Some("CFP03")).category(),
+            Some(ProductCategory::Other("CFP".into()))
+        );
+
+        // Too short to have a category
+        assert_eq!(message(Some("AB")).product_category(), None);
+
+        // The PTWC test has no AWIPS ID
+        let ptwc = msg("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"PHEB\" ttaaii=\"NTXX98\" issue=\"2022-02-04T01:23:00Z\" awipsid=\"\" id=\"14425.22800\"><![CDATA[\n\n593\n\nNTXX98 PHEB 040123\n\nPTWC REDUNDANT-SIDE TEST FROM IRC\n\n]]></x></message>").unwrap();
+        assert_eq!(ptwc.awips_id, None);
+        assert_eq!(ptwc.product_category(), None);
+        assert_eq!(ptwc.category(), None);
+    }
+
+    #[test]
+    fn is_test() {
+        let message = |ttaaii: &str, cccc: &str, awips_id: Option<&str>, text: &str| {
+            Message::builder()
+                .ttaaii(ttaaii)
+                .cccc(cccc)
+                .awips_id(awips_id)
+                .message(text)
+                .build()
+        };
+
+        let ptwc = msg("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"PHEB\" ttaaii=\"NTXX98\" issue=\"2022-02-04T01:23:00Z\" awipsid=\"\" id=\"14425.22800\"><![CDATA[\n\n593\n\nNTXX98 PHEB 040123\n\nPTWC REDUNDANT-SIDE TEST FROM IRC\n\nRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZRZ\n\n]]></x></message>").unwrap();
+        assert!(ptwc.is_test());
+        // The test pattern alone is enough
+        assert!(message(
+            "SXUS99",
+            "KXXX",
+            None,
+            "SXUS99 KXXX 040123\nRZRZRZRZRZRZRZRZ\n"
+        )
+        .is_test());
+
+        let ancf = message(
+            "WOUS99",
+            "KNCF",
+            None,
+            "WOUS99 KNCF 040300\nTHIS IS A COMMUNICATIONS TEST MESSAGE ORIGINATING FROM THE ANCF\n",
+        );
+        assert!(ancf.is_test());
+        assert!(message(
+            "WOUS99",
+            "KXXX",
+            None,
+            "WOUS99 KXXX 040300\nTHIS IS A COMMUNICATIONS TEST MESSAGE\n"
+        )
+        .is_test());
+        assert!(message(
+            "NOUS41",
+            "KLMK",
+            Some("TSTLMK"),
+            "NOUS41 KLMK 040300\nTSTLMK\n"
+        )
+        .is_test());
+        assert!(message("NOUS41", "KLMK", None, "NOUS41 KLMK 040300\n\nTEST\n").is_test());
+
+        // Real products which mention testing
+        assert!(!message(
+            "WWUS83",
+            "KTOP",
+            Some("SPSTOP"),
+            "WWUS83 KTOP 040300\nSPSTOP\nOUTDOOR WARNING SIRENS WILL BE TESTED AT NOON. THIS TEST\nIS NOT A DRILL... CONTEST RESULTS AND THE GREATEST RAINFALL TOTALS FOLLOW.\nTESTING WILL LAST FIVE MINUTES.\n",
+        )
+        .is_test());
+        assert!(!message(
+            "SRUS43",
+            "KLMK",
+            Some("RRMLMK"),
+            "SRUS43 KLMK 040254\nRRMLMK\n.ER RZRZ 20220203\n"
+        )
+        .is_test());
+        assert!(!message(
+            "WOUS99",
+            "KLMK",
+            Some("ADMLMK"),
+            "WOUS99 KLMK 040254\nADMLMK\n"
+        )
+        .is_test());
+    }
+
+    #[test]
+    fn display() {
+        let klmk = msg("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n.ER PRSK2 20220203 Z DC202202040254/DUE/DQG/DH17/HGIFE/DIH1/\n\n.E1 15.4/15.6/15.8/16.1/16.5/17.0/17.6/18.1\n\n]]></x></message>").unwrap();
+        assert_eq!(
+            klmk.to_string(),
+            "987\nSRUS43 KLMK 040254\nRRMLMK\n.ER PRSK2 20220203 Z DC202202040254/DUE/DQG/DH17/HGIFE/DIH1/\n.E1 15.4/15.6/15.8/16.1/16.5/17.0/17.6/18.1\n"
+        );
+
+        let short = Message {
+            ldm_sequence_number: Some(7),
+            ..klmk.clone()
+        };
+        assert!(short.to_string().starts_with("007\nSRUS43 KLMK 040254\n"));
+
+        let unnumbered = Message {
+            ldm_sequence_number: None,
+            ..klmk
+        };
+        assert_eq!(unnumbered.to_string(), unnumbered.message);
+    }
+
+    #[test]
+    fn product_id() {
+        let klmk = msg("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\"><![CDATA[\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n]]></x></message>").unwrap();
+        assert_eq!(klmk.product_id(), "202202040254-KLMK-SRUS43-RRMLMK");
+
+        // Normalized to UTC, across a date boundary
+        let offset = Message {
+            issue: "2022-02-03T21:54:00-05:00".parse().unwrap(),
+            ..klmk.clone()
+        };
+        assert_eq!(offset.product_id(), "202202040254-KLMK-SRUS43-RRMLMK");
+
+        let no_awips = Message {
+            awips_id: None,
+            ..klmk
+        };
+        assert_eq!(no_awips.product_id(), "202202040254-KLMK-SRUS43-NONE");
+    }
+
+    #[test]
+    fn wmo_heading() {
+        let message = |body: &str| Message::builder().message(body).build();
+
+        assert_eq!(
+            message("SRUS43 KLMK 040254\nRRMLMK\n").wmo_heading(),
+            Some(WmoHeading {
+                t1t2: "SR".into(),
+                a1a2: "US".into(),
+                ii: "43".into(),
+                cccc: "KLMK".into(),
+                day: 4,
+                hour: 2,
+                minute: 54,
+                bbb: None,
             })
         );
+
+        // Duplicated headings: the first one wins
+        let pajk = message("SRAK57 PAJK 040211\nRR3AJK\nSRAK57 PAJK 040210\n\n.A NDIA2\n");
+        assert_eq!(pajk.wmo_heading().unwrap().minute, 11);
+        assert!(pajk.message.contains("SRAK57 PAJK 040210"));
+
+        // BBB indicators for corrected, amended, and delayed products
+        for (line, bbb) in [
+            ("WFUS53 KJKL 040254 CCA", "CCA"),
+            ("FXUS61 KBOX 041530 AAB", "AAB"),
+            ("SXUS74 KOUN 040104 RRA", "RRA"),
+        ] {
+            let heading = message(&format!("{}\r\nBODY\n", line))
+                .wmo_heading()
+                .unwrap();
+            assert_eq!(heading.bbb.as_deref(), Some(bbb));
+        }
+
+        // Not a heading
+        assert_eq!(message("").wmo_heading(), None);
+        assert_eq!(
+            message("PTWC REDUNDANT-SIDE TEST FROM IRC\n").wmo_heading(),
+            None
+        );
+        assert_eq!(message("SRUS43 KLMK 042554\n").wmo_heading(), None);
+        assert_eq!(
+            message("SRUS43 KLMK 040254 RRA EXTRA\n").wmo_heading(),
+            None
+        );
     }
 }
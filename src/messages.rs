@@ -0,0 +1,102 @@
+use crate::logging::error;
+use crate::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A stream adapter which yields only the [`Message`](struct.Message.html)s from a stream of
+/// events.
+///
+/// Every other event is dropped. Errors are logged by default, since `Stream` reconnects on its
+/// own; use [`log_errors()`](#method.log_errors) to drop them silently instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+///
+/// # async fn example() {
+/// let mut messages = nwws_oi::Stream::new(("user", "pass")).messages();
+/// while let Some(message) = messages.next().await {
+///     println!("{}", message.ttaaii);
+/// }
+/// # }
+/// ```
+pub struct Messages<S> {
+    inner: S,
+    log_errors: bool,
+}
+
+impl<S> Messages<S> {
+    /// Wrap a stream of events, such as a [`Stream`](struct.Stream.html) or a
+    /// [`Dedup`](struct.Dedup.html).
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            log_errors: true,
+        }
+    }
+
+    /// Set whether dropped errors are logged.
+    pub fn log_errors(mut self, log_errors: bool) -> Self {
+        self.log_errors = log_errors;
+        self
+    }
+
+    /// Unwrap the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: futures::Stream<Item = StreamEvent> + Unpin> futures::Stream for Messages<S> {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(StreamEvent::Message(message))) => {
+                    return Poll::Ready(Some(message))
+                }
+                Poll::Ready(Some(StreamEvent::Error(error))) if self.log_errors => {
+                    error!("error: {}", error);
+                }
+                Poll::Ready(Some(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn message(id: &str) -> Message {
+        Message::builder().id(id).build()
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        for event in [
+            StreamEvent::ConnectionState(ConnectionState::Connecting),
+            StreamEvent::ConnectionState(ConnectionState::Joined),
+            StreamEvent::Message(message("1.1")),
+            StreamEvent::Error(Error::StreamEnded),
+            StreamEvent::ConnectionState(ConnectionState::Disconnected),
+            StreamEvent::Message(message("1.2")),
+        ] {
+            tx.unbounded_send(event).unwrap();
+        }
+        drop(tx);
+
+        let ids: Vec<_> = Messages::new(rx)
+            .log_errors(false)
+            .map(|message| message.id)
+            .collect()
+            .await;
+        assert_eq!(ids, vec!["1.1", "1.2"]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Counters describing a [`Stream`](struct.Stream.html)'s activity.
+///
+/// `StreamMetrics` is updated by the `Stream`'s background thread, and can be read from any
+/// thread at any time, e.g. to export it to a monitoring system. Counters start at zero when the
+/// `Stream` is created and only increase.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let stream = nwws_oi::Stream::new(("user", "pass"));
+/// let metrics = stream.metrics();
+/// std::thread::spawn(move || loop {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     println!(
+///         "{} messages, {} errors, {} reconnects",
+///         metrics.messages(),
+///         metrics.errors(),
+///         metrics.reconnects()
+///     );
+/// });
+/// ```
+#[derive(Debug, Default)]
+pub struct StreamMetrics {
+    messages: AtomicU64,
+    errors: AtomicU64,
+    reconnects: AtomicU64,
+    missing: AtomicU64,
+    dropped: AtomicU64,
+    parse_failures: AtomicU64,
+    bytes: AtomicU64,
+    /// Milliseconds since the UNIX epoch, or 0 if no message has been received
+    last_message: AtomicU64,
+}
+
+impl StreamMetrics {
+    /// The number of messages received from the server.
+    ///
+    /// This includes messages dropped by [`Stream::filtered()`](struct.Stream.html#method.filtered).
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// The number of [`StreamEvent::Error`](enum.StreamEvent.html#variant.Error)s.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of connection attempts after the first, whether or not they succeeded.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// The number of messages which never arrived, totalled from
+    /// [`StreamEvent::SequenceGap`](enum.StreamEvent.html#variant.SequenceGap)s.
+    pub fn missing(&self) -> u64 {
+        self.missing.load(Ordering::Relaxed)
+    }
+
+    /// The number of messages dropped because the consumer fell behind, totalled from
+    /// [`StreamEvent::Overflow`](enum.StreamEvent.html#variant.Overflow)s.
+    ///
+    /// This is always 0 with [`OverflowPolicy::Block`](enum.OverflowPolicy.html#variant.Block).
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of products which could not be parsed, totalled from
+    /// [`ConnectionStats::parse_failures`](struct.ConnectionStats.html#structfield.parse_failures).
+    ///
+    /// Like `bytes()`, this counts connections which have ended, so it lags behind the current
+    /// connection.
+    pub fn parse_failures(&self) -> u64 {
+        self.parse_failures.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of product text received, totalled from
+    /// [`ConnectionStats::bytes_received`](struct.ConnectionStats.html#structfield.bytes_received).
+    ///
+    /// Each connection's bytes are added once it ends, so this lags behind the current connection.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// When the most recent message was received, if any.
+    pub fn last_message(&self) -> Option<SystemTime> {
+        match self.last_message.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    pub(crate) fn message_received(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.last_message.store(millis.max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn messages_missing(&self, count: u64) {
+        self.missing.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_ended(&self, stats: &crate::ConnectionStats) {
+        self.parse_failures
+            .fetch_add(stats.parse_failures, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(stats.bytes_received, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnecting(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,230 @@
+/// A participant joining or leaving the NWWS MUC room.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Presence {
+    /// The room
+    pub room: jid::BareJid,
+
+    /// The participant's nickname within the room
+    pub nickname: String,
+
+    /// Whether the participant joined or left
+    pub status: PresenceStatus,
+
+    /// Whether this presence is about this connection's own participant
+    ///
+    /// The server sends self-presence to confirm joining the room, and again if this connection is
+    /// removed from the room.
+    pub self_presence: bool,
+}
+
+/// Whether a participant joined or left.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PresenceStatus {
+    /// The participant is in the room
+    Joined,
+    /// The participant left the room
+    Left,
+}
+
+impl TryFrom<xmpp_parsers::Element> for Presence {
+    type Error = ();
+
+    fn try_from(value: xmpp_parsers::Element) -> Result<Self, Self::Error> {
+        let presence = xmpp_parsers::presence::Presence::try_from(value).map_err(|_| ())?;
+
+        let status = match presence.type_ {
+            xmpp_parsers::presence::Type::None => PresenceStatus::Joined,
+            xmpp_parsers::presence::Type::Unavailable => PresenceStatus::Left,
+            _ => return Err(()),
+        };
+
+        let (room, nickname) = match presence.from {
+            Some(xmpp_parsers::Jid::Full(jid)) => (
+                jid::BareJid {
+                    node: jid.node,
+                    domain: jid.domain,
+                },
+                jid.resource,
+            ),
+            _ => return Err(()),
+        };
+
+        // Only presence from a MUC carries a MucUser payload
+        let muc_user = presence
+            .payloads
+            .into_iter()
+            .find_map(|payload| xmpp_parsers::muc::MucUser::try_from(payload).ok())
+            .ok_or(())?;
+        let self_presence = muc_user
+            .status
+            .iter()
+            .any(|s| s == &xmpp_parsers::muc::user::Status::SelfPresence);
+
+        Ok(Self {
+            room,
+            nickname,
+            status,
+            self_presence,
+        })
+    }
+}
+
+/// A participant currently in the NWWS MUC room, as listed by
+/// [`Connection::occupants()`](struct.Connection.html#method.occupants).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Occupant {
+    /// The participant's nickname within the room
+    pub nickname: String,
+
+    /// When this connection learned that the participant is in the room
+    ///
+    /// For participants who were already in the room, this is when this connection joined.
+    pub since: chrono::DateTime<chrono::Utc>,
+
+    /// Whether this is this connection's own participant
+    pub self_presence: bool,
+}
+
+/// The occupants of the room, kept up to date from presence.
+#[derive(Debug, Default)]
+pub(crate) struct Roster {
+    occupants: std::collections::HashMap<String, Occupant>,
+}
+
+impl Roster {
+    /// Note a presence received at `now`.
+    pub(crate) fn update(&mut self, presence: &Presence, now: chrono::DateTime<chrono::Utc>) {
+        match presence.status {
+            PresenceStatus::Joined => {
+                self.occupants
+                    .entry(presence.nickname.clone())
+                    .or_insert_with(|| Occupant {
+                        nickname: presence.nickname.clone(),
+                        since: now,
+                        self_presence: presence.self_presence,
+                    });
+            }
+            // Once this connection is out of the room, it no longer hears about anyone else
+            PresenceStatus::Left if presence.self_presence => self.occupants.clear(),
+            PresenceStatus::Left => {
+                self.occupants.remove(&presence.nickname);
+            }
+        }
+    }
+
+    /// The occupants, sorted by nickname.
+    pub(crate) fn occupants(&self) -> Vec<Occupant> {
+        let mut occupants: Vec<_> = self.occupants.values().cloned().collect();
+        occupants.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+        occupants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(xml: &str) -> Result<Presence, ()> {
+        let element: xmpp_parsers::Element = xml.parse().unwrap();
+        Presence::try_from(element)
+    }
+
+    fn room() -> jid::BareJid {
+        "nwws@conference.nwws-oi.weather.gov".parse().unwrap()
+    }
+
+    #[test]
+    fn others() {
+        assert_eq!(
+            presence("<presence xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\" to=\"user@nwws-oi.weather.gov/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"owner\" role=\"moderator\"/></x></presence>"),
+            Ok(Presence {
+                room: room(),
+                nickname: "nwws-oi".into(),
+                status: PresenceStatus::Joined,
+                self_presence: false,
+            })
+        );
+
+        assert_eq!(
+            presence("<presence xmlns=\"jabber:client\" type=\"unavailable\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\" to=\"user@nwws-oi.weather.gov/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"owner\" role=\"none\"/></x></presence>"),
+            Ok(Presence {
+                room: room(),
+                nickname: "nwws-oi".into(),
+                status: PresenceStatus::Left,
+                self_presence: false,
+            })
+        );
+    }
+
+    #[test]
+    fn self_presence() {
+        assert_eq!(
+            presence("<presence xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov/user/res\" to=\"user@nwws-oi.weather.gov/res\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"none\" role=\"participant\"/><status code=\"110\"/></x></presence>"),
+            Ok(Presence {
+                room: room(),
+                nickname: "user/res".into(),
+                status: PresenceStatus::Joined,
+                self_presence: true,
+            })
+        );
+    }
+
+    #[test]
+    fn not_muc() {
+        // Presence from an ordinary contact
+        assert_eq!(
+            presence("<presence xmlns=\"jabber:client\" from=\"friend@example.com/phone\"/>"),
+            Err(())
+        );
+        // Presence errors
+        assert_eq!(
+            presence("<presence xmlns=\"jabber:client\" type=\"error\" from=\"nwws@conference.nwws-oi.weather.gov/user\"><x xmlns=\"http://jabber.org/protocol/muc#user\"/></presence>"),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn roster() {
+        let at = |seconds| chrono::TimeZone::timestamp_opt(&chrono::Utc, seconds, 0).unwrap();
+        let occupant = |nickname: &str, status, self_presence| Presence {
+            room: room(),
+            nickname: nickname.into(),
+            status,
+            self_presence,
+        };
+        let nicknames = |roster: &Roster| -> Vec<(String, i64)> {
+            roster
+                .occupants()
+                .into_iter()
+                .map(|o| (o.nickname, o.since.timestamp()))
+                .collect()
+        };
+
+        let mut roster = Roster::default();
+        roster.update(&occupant("nwws-oi", PresenceStatus::Joined, false), at(1));
+        roster.update(&occupant("alice", PresenceStatus::Joined, false), at(1));
+        roster.update(&occupant("user", PresenceStatus::Joined, true), at(2));
+        assert_eq!(
+            nicknames(&roster),
+            vec![
+                ("alice".into(), 1),
+                ("nwws-oi".into(), 1),
+                ("user".into(), 2)
+            ]
+        );
+        assert!(roster.occupants()[2].self_presence);
+
+        // Repeated presence does not reset `since`
+        roster.update(&occupant("alice", PresenceStatus::Joined, false), at(3));
+        roster.update(&occupant("bob", PresenceStatus::Joined, false), at(4));
+        roster.update(&occupant("nwws-oi", PresenceStatus::Left, false), at(5));
+        assert_eq!(
+            nicknames(&roster),
+            vec![("alice".into(), 1), ("bob".into(), 4), ("user".into(), 2)]
+        );
+
+        // Removed from the room
+        roster.update(&occupant("user", PresenceStatus::Left, true), at(6));
+        assert!(roster.occupants().is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+use crate::*;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The longest HTTP proxy response header accepted.
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+/// The proxy refused to connect because of its configuration, e.g. its credentials.
+#[derive(Debug)]
+struct ProxyRefused(String);
+
+impl std::fmt::Display for ProxyRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProxyRefused {}
+
+/// Whether `error` was caused by the proxy refusing to connect because of its configuration.
+pub(crate) fn is_refused(error: &tokio_xmpp::Error) -> bool {
+    match error {
+        tokio_xmpp::Error::Io(e) => is_refused_io(e),
+        _ => false,
+    }
+}
+
+fn is_refused_io(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .map_or(false, |inner| inner.is::<ProxyRefused>())
+}
+
+/// Open a TCP connection to `host:port` through `proxy`, which resolves `host` itself.
+///
+/// Errors name the proxy, since they would otherwise be indistinguishable from errors connecting
+/// to the server.
+pub(crate) async fn connect(proxy: &Proxy, host: &str, port: u16) -> io::Result<TcpStream> {
+    let (proxy_host, proxy_port) = proxy.address();
+    let result = async {
+        let mut stream = TcpStream::connect((proxy_host, proxy_port)).await?;
+        match proxy {
+            Proxy::Socks5 { auth, .. } => socks5(&mut stream, auth.as_ref(), host, port).await?,
+            Proxy::HttpConnect { auth, .. } => {
+                http_connect(&mut stream, auth.as_ref(), host, port).await?
+            }
+        }
+        Ok(stream)
+    };
+    result.await.map_err(|e: io::Error| {
+        let message = format!("proxy {}: {}", authority(proxy_host, proxy_port), e);
+        if is_refused_io(&e) {
+            io::Error::new(e.kind(), ProxyRefused(message))
+        } else {
+            io::Error::new(e.kind(), message)
+        }
+    })
+}
+
+/// `host:port`, with brackets around IPv6 addresses.
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    kind: io::ErrorKind,
+    e: E,
+) -> io::Error {
+    io::Error::new(kind, e)
+}
+
+/// Ask a SOCKS5 proxy to connect to `host:port`.
+///
+/// See [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928) and, for authentication,
+/// [RFC 1929](https://www.rfc-editor.org/rfc/rfc1929).
+async fn socks5(
+    stream: &mut TcpStream,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    const NO_AUTHENTICATION: u8 = 0x00;
+    const USERNAME_PASSWORD: u8 = 0x02;
+    const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+    // Offer to authenticate only if we can
+    if auth.is_some() {
+        stream
+            .write_all(&[5, 2, NO_AUTHENTICATION, USERNAME_PASSWORD])
+            .await?;
+    } else {
+        stream.write_all(&[5, 1, NO_AUTHENTICATION]).await?;
+    }
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(error(io::ErrorKind::InvalidData, "not a SOCKS5 proxy"));
+    }
+    match (reply[1], auth) {
+        (NO_AUTHENTICATION, _) => {}
+        (USERNAME_PASSWORD, Some(auth)) => {
+            let (username, password) = (auth.username.as_bytes(), auth.password.as_bytes());
+            if username.len() > 255 || password.len() > 255 {
+                return Err(error(
+                    io::ErrorKind::InvalidInput,
+                    "SOCKS5 usernames and passwords are limited to 255 bytes",
+                ));
+            }
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await?;
+
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(error(
+                    io::ErrorKind::PermissionDenied,
+                    ProxyRefused("the SOCKS5 proxy refused the username and password".into()),
+                ));
+            }
+        }
+        (NO_ACCEPTABLE_METHODS, None) => {
+            return Err(error(
+                io::ErrorKind::PermissionDenied,
+                ProxyRefused("the SOCKS5 proxy requires authentication".into()),
+            ))
+        }
+        (method, _) => {
+            return Err(error(
+                io::ErrorKind::InvalidData,
+                format!("the SOCKS5 proxy chose an unsupported method {}", method),
+            ))
+        }
+    }
+
+    // CONNECT, passing hostnames through for the proxy to resolve
+    let mut request = vec![5, 1, 0];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.octets());
+        }
+        Ok(std::net::IpAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.octets());
+        }
+        Err(_) if host.len() > 255 => {
+            return Err(error(
+                io::ErrorKind::InvalidInput,
+                "SOCKS5 hostnames are limited to 255 bytes",
+            ))
+        }
+        Err(_) => {
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    let failure = match reply[1] {
+        0 => None,
+        2 => Some((
+            io::ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        )),
+        3 => Some((io::ErrorKind::Other, "network unreachable")),
+        4 => Some((io::ErrorKind::Other, "host unreachable")),
+        5 => Some((io::ErrorKind::ConnectionRefused, "connection refused")),
+        6 => Some((io::ErrorKind::TimedOut, "TTL expired")),
+        _ => Some((io::ErrorKind::Other, "general failure")),
+    };
+    if let Some((kind, reason)) = failure {
+        return Err(error(
+            kind,
+            format!(
+                "the SOCKS5 proxy could not connect to {}:{}: {}",
+                host, port, reason
+            ),
+        ));
+    }
+
+    // Skip the address the proxy bound
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(error(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the SOCKS5 proxy replied with unknown address type {}",
+                    atyp
+                ),
+            ))
+        }
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Ask an HTTP proxy to connect to `host:port` using the `CONNECT` method.
+///
+/// See [RFC 9110 § 9.3.6](https://www.rfc-editor.org/rfc/rfc9110#section-9.3.6).
+async fn http_connect(
+    stream: &mut TcpStream,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    use base64::Engine;
+
+    let target = authority(host, port);
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(auth) = auth {
+        let credentials = format!("{}:{}", auth.username, auth.password);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response header a byte at a time, so as not to consume anything after it
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(error(
+                io::ErrorKind::InvalidData,
+                "the HTTP proxy's response is too long",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status.parse::<u16>().ok(),
+        _ => None,
+    };
+    match status {
+        Some(200..=299) => Ok(()),
+        Some(403) | Some(407) => Err(error(
+            io::ErrorKind::PermissionDenied,
+            ProxyRefused(format!(
+                "the HTTP proxy refused to connect: {}",
+                status_line
+            )),
+        )),
+        Some(_) => Err(error(
+            io::ErrorKind::Other,
+            format!(
+                "the HTTP proxy could not connect to {}: {}",
+                target, status_line
+            ),
+        )),
+        None => Err(error(
+            io::ErrorKind::InvalidData,
+            format!("the HTTP proxy sent an invalid response: {:?}", status_line),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Accept one SOCKS5 connection, expecting `auth`, and reply to CONNECT with `reply`.
+    ///
+    /// Returns the proxy's port, and the CONNECT request's address and port.
+    async fn fake_socks5(
+        auth: Option<(&'static str, &'static str)>,
+        reply: u8,
+    ) -> (u16, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            match auth {
+                Some((username, password)) => {
+                    assert!(methods.contains(&2));
+                    stream.write_all(&[5, 2]).await.unwrap();
+
+                    let mut request = vec![0; 3 + username.len() + password.len()];
+                    stream.read_exact(&mut request).await.unwrap();
+                    let mut expected = vec![1, username.len() as u8];
+                    expected.extend_from_slice(username.as_bytes());
+                    expected.push(password.len() as u8);
+                    expected.extend_from_slice(password.as_bytes());
+                    if request != expected {
+                        stream.write_all(&[1, 1]).await.unwrap();
+                        return Vec::new();
+                    }
+                    stream.write_all(&[1, 0]).await.unwrap();
+                }
+                None => {
+                    assert_eq!(methods, vec![0]);
+                    stream.write_all(&[5, 0]).await.unwrap();
+                }
+            }
+
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut address = vec![0; request[4] as usize + 2];
+            stream.read_exact(&mut address).await.unwrap();
+
+            stream
+                .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x30, 0x39])
+                .await
+                .unwrap();
+            if reply == 0 {
+                stream.write_all(b"<stream>").await.unwrap();
+            }
+            address
+        });
+        (port, handle)
+    }
+
+    fn socks5(port: u16, auth: Option<ProxyAuth>) -> Proxy {
+        Proxy::Socks5 {
+            host: "127.0.0.1".into(),
+            port,
+            auth,
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_connect() {
+        let (port, proxy) = fake_socks5(None, 0).await;
+        let mut stream = connect(&socks5(port, None), "nwws-oi.weather.gov", 5222)
+            .await
+            .unwrap();
+
+        // The hostname is passed through, not resolved
+        let mut expected = b"nwws-oi.weather.gov".to_vec();
+        expected.extend_from_slice(&5222u16.to_be_bytes());
+        assert_eq!(proxy.await.unwrap(), expected);
+
+        // The tunnel carries data
+        let mut data = [0; 8];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"<stream>");
+    }
+
+    #[tokio::test]
+    async fn socks5_auth() {
+        let (port, proxy) = fake_socks5(Some(("user", "pass")), 0).await;
+        let auth = ProxyAuth {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        connect(&socks5(port, Some(auth)), "xmpp.example.com", 5222)
+            .await
+            .unwrap();
+        proxy.await.unwrap();
+
+        // A wrong password is the proxy's refusal, like HTTP 407
+        let (port, proxy) = fake_socks5(Some(("user", "pass")), 0).await;
+        let auth = ProxyAuth {
+            username: "user".into(),
+            password: "wrong".into(),
+        };
+        let e = connect(&socks5(port, Some(auth)), "xmpp.example.com", 5222)
+            .await
+            .unwrap_err();
+        proxy.await.unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(is_refused_io(&e), "{:?}", e);
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "proxy 127.0.0.1:{}: the SOCKS5 proxy refused the username and password",
+                port
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_refused() {
+        let (port, proxy) = fake_socks5(None, 5).await;
+        let e = connect(&socks5(port, None), "xmpp.example.com", 5222)
+            .await
+            .unwrap_err();
+        proxy.await.unwrap();
+
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "proxy 127.0.0.1:{}: the SOCKS5 proxy could not connect to xmpp.example.com:5222: connection refused",
+                port
+            )
+        );
+    }
+
+    /// Accept one HTTP connection, and reply to CONNECT with `status`.
+    ///
+    /// Returns the proxy's port, and the request header.
+    async fn fake_http(status: &'static str) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(format!("HTTP/1.1 {}\r\nVia: test\r\n\r\n", status).as_bytes())
+                .await
+                .unwrap();
+            if status.starts_with('2') {
+                stream.write_all(b"<stream>").await.unwrap();
+            }
+            String::from_utf8(request).unwrap()
+        });
+        (port, handle)
+    }
+
+    fn http(port: u16, auth: Option<ProxyAuth>) -> Proxy {
+        Proxy::HttpConnect {
+            host: "127.0.0.1".into(),
+            port,
+            auth,
+        }
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let (port, proxy) = fake_http("200 Connection established").await;
+        let auth = ProxyAuth {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        let mut stream = connect(&http(port, Some(auth)), "nwws-oi.weather.gov", 5222)
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy.await.unwrap(),
+            "CONNECT nwws-oi.weather.gov:5222 HTTP/1.1\r\nHost: nwws-oi.weather.gov:5222\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+
+        // The tunnel carries data, starting right after the response
+        let mut data = [0; 8];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"<stream>");
+
+        let (port, proxy) = fake_http("200 OK").await;
+        connect(&http(port, None), "::1", 5222).await.unwrap();
+        assert_eq!(
+            proxy.await.unwrap(),
+            "CONNECT [::1]:5222 HTTP/1.1\r\nHost: [::1]:5222\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_connect_refused() {
+        for status in ["407 Proxy Authentication Required", "403 Forbidden"] {
+            let (port, proxy) = fake_http(status).await;
+            let e = connect(&http(port, None), "nwws-oi.weather.gov", 5222)
+                .await
+                .unwrap_err();
+            proxy.await.unwrap();
+
+            assert!(is_refused_io(&e), "{:?}", e);
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    "proxy 127.0.0.1:{}: the HTTP proxy refused to connect: HTTP/1.1 {}",
+                    port, status
+                )
+            );
+            assert!(is_refused(&e.into()));
+        }
+
+        // Other failures are not the configuration's fault
+        let (port, proxy) = fake_http("502 Bad Gateway").await;
+        let e = connect(&http(port, None), "nwws-oi.weather.gov", 5222)
+            .await
+            .unwrap_err();
+        proxy.await.unwrap();
+        assert!(!is_refused_io(&e), "{:?}", e);
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "proxy 127.0.0.1:{}: the HTTP proxy could not connect to nwws-oi.weather.gov:5222: HTTP/1.1 502 Bad Gateway",
+                port
+            )
+        );
+    }
+}
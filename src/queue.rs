@@ -0,0 +1,258 @@
+use crate::*;
+use futures::task::AtomicWaker;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::Semaphore;
+
+/// The buffer between a `Stream`'s background thread and its consumer.
+///
+/// This is a bounded channel like `tokio::sync::mpsc`, except that once it is full, messages are
+/// handled according to an [`OverflowPolicy`](enum.OverflowPolicy.html). Only messages are ever
+/// dropped: other events are always queued, even past `capacity` under the drop policies.
+struct Queue {
+    state: Mutex<State>,
+    /// One permit per free slot, closed once the receiver is gone
+    space: Semaphore,
+    policy: OverflowPolicy,
+    /// The receiver, waiting for an event
+    waker: AtomicWaker,
+    metrics: Arc<StreamMetrics>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Each event, and whether it holds a slot
+    events: VecDeque<(StreamEvent, bool)>,
+    /// Messages dropped since the receiver last heard about it
+    dropped: u64,
+    senders: usize,
+}
+
+/// The queue was closed by dropping its receiver, so an event could not be sent.
+#[derive(Debug)]
+pub(crate) struct SendError;
+
+/// Create a queue for up to `capacity` events, treating 0 as 1.
+pub(crate) fn queue(
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<StreamMetrics>,
+) -> (Sender, Receiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(State {
+            senders: 1,
+            ..Default::default()
+        }),
+        space: Semaphore::new(capacity.max(1)),
+        policy,
+        waker: AtomicWaker::new(),
+        metrics,
+    });
+    (Sender(queue.clone()), Receiver(queue))
+}
+
+/// The sending half of a queue.
+pub(crate) struct Sender(Arc<Queue>);
+
+impl Sender {
+    /// Queue `event`, waiting for room if the policy is `Block`.
+    pub(crate) async fn send(&self, event: StreamEvent) -> Result<(), SendError> {
+        let queue = &self.0;
+        let message = matches!(event, StreamEvent::Message(_));
+        let holds_slot = match queue.policy {
+            OverflowPolicy::Block => match queue.space.acquire().await {
+                Ok(permit) => {
+                    permit.forget();
+                    true
+                }
+                Err(_) => return Err(SendError),
+            },
+            _ if queue.space.is_closed() => return Err(SendError),
+            _ if !message => false,
+            _ => match queue.space.try_acquire() {
+                Ok(permit) => {
+                    permit.forget();
+                    true
+                }
+                Err(_) => {
+                    let mut state = queue.state.lock().unwrap();
+                    let oldest = state.events.iter().position(|(_, slot)| *slot);
+                    if let (OverflowPolicy::DropOldest, Some(oldest)) = (queue.policy, oldest) {
+                        // The new message takes over the dropped one's slot
+                        state.events.remove(oldest);
+                        state.events.push_back((event, true));
+                    }
+                    state.dropped += 1;
+                    drop(state);
+                    queue.metrics.message_dropped();
+                    queue.waker.wake();
+                    return Ok(());
+                }
+            },
+        };
+
+        queue
+            .state
+            .lock()
+            .unwrap()
+            .events
+            .push_back((event, holds_slot));
+        queue.waker.wake();
+        Ok(())
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.0.state.lock().unwrap().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().senders -= 1;
+        self.0.waker.wake();
+    }
+}
+
+/// The receiving half of a queue.
+pub(crate) struct Receiver(Arc<Queue>);
+
+impl Receiver {
+    /// Receive the next event, or `None` once every sender is gone and the queue is empty.
+    ///
+    /// After messages were dropped, a `StreamEvent::Overflow` comes first.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<StreamEvent>> {
+        let queue = &self.0;
+        let mut state = queue.state.lock().unwrap();
+        if state.dropped > 0 {
+            let dropped = std::mem::take(&mut state.dropped);
+            return Poll::Ready(Some(StreamEvent::Overflow { dropped }));
+        }
+        match state.events.pop_front() {
+            Some((event, holds_slot)) => {
+                if holds_slot {
+                    queue.space.add_permits(1);
+                }
+                Poll::Ready(Some(event))
+            }
+            None if state.senders == 0 => Poll::Ready(None),
+            None => {
+                // Senders wake the receiver after changing the state, which is locked
+                queue.waker.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Wait for the next event, blocking the current thread.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn blocking_recv(&mut self) -> Option<StreamEvent> {
+        futures::executor::block_on(futures::future::poll_fn(|cx| self.poll_recv(cx)))
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.space.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u32) -> StreamEvent {
+        StreamEvent::Message(Message::builder().id(&format!("14425.{}", id)).build())
+    }
+
+    /// Send five messages and a state change into a queue of three, then receive everything.
+    async fn overflow(policy: OverflowPolicy) -> (Vec<String>, u64) {
+        let metrics = Arc::new(StreamMetrics::default());
+        let (tx, mut rx) = queue(3, policy, metrics.clone());
+        let sending = async move {
+            for id in 1..=5 {
+                tx.send(message(id)).await.unwrap();
+            }
+            tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
+                .await
+                .unwrap();
+        };
+        // A slow receiver, which waits until the sender is done or stuck
+        let receiving = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let mut events = Vec::new();
+            while let Some(event) = futures::future::poll_fn(|cx| rx.poll_recv(cx)).await {
+                events.push(match event {
+                    StreamEvent::Message(message) => message.id,
+                    StreamEvent::Overflow { dropped } => format!("dropped {}", dropped),
+                    StreamEvent::ConnectionState(_) => "state".into(),
+                    other => panic!("unexpected {:?}", other),
+                });
+            }
+            events
+        };
+        let ((), events) = tokio::join!(sending, receiving);
+        (events, metrics.dropped())
+    }
+
+    #[tokio::test]
+    async fn block() {
+        assert_eq!(
+            overflow(OverflowPolicy::Block).await,
+            (
+                vec!["14425.1", "14425.2", "14425.3", "14425.4", "14425.5", "state"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                0
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        assert_eq!(
+            overflow(OverflowPolicy::DropOldest).await,
+            (
+                vec!["dropped 2", "14425.3", "14425.4", "14425.5", "state"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                2
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        assert_eq!(
+            overflow(OverflowPolicy::DropNewest).await,
+            (
+                vec!["dropped 2", "14425.1", "14425.2", "14425.3", "state"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                2
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (tx, rx) = queue(1, OverflowPolicy::Block, Default::default());
+        tx.send(message(1)).await.unwrap();
+        // Blocked on a full queue until the receiver goes away
+        let sending = tx.send(message(2));
+        futures::pin_mut!(sending);
+        assert!(futures::poll!(sending.as_mut()).is_pending());
+        drop(rx);
+        assert!(sending.await.is_err());
+
+        let (tx, rx) = queue(1, OverflowPolicy::DropNewest, Default::default());
+        drop(rx);
+        assert!(tx.send(message(1)).await.is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::*;
+
+/// How far a [`Stream`](struct.Stream.html) got, for picking up where it left off after a restart.
+///
+/// Take one with [`Stream::last_position()`](struct.Stream.html#method.last_position) after
+/// processing each message, persist it, and pass it to
+/// [`Stream::resume()`](struct.Stream.html#method.resume) on the next start. With the `serde`
+/// feature, it serializes as e.g.
+/// `{"last_message":"2022-02-04T02:54:00+00:00","last_id":"14425.25117"}`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeState {
+    /// When the last message was sent: its `delay_stamp`, or its `issue` time if it was not
+    /// delayed
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "crate::config::rfc3339::option")
+    )]
+    pub last_message: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The [`Message::id`](struct.Message.html#structfield.id) with the highest sequence number
+    /// from the current ingest process
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_id: Option<String>,
+}
+
+impl ResumeState {
+    /// Whether `message` was already delivered before this position was taken.
+    ///
+    /// Only messages from the same ingest process as `last_id` can be told apart, by their
+    /// sequence numbers. Messages from any other process count as new.
+    pub fn contains(&self, message: &Message) -> bool {
+        let last = self.last_id.as_deref().and_then(sequence::parse_id);
+        match (last, sequence::parse_id(&message.id)) {
+            (Some((last_pid, last)), Some((pid, sequence))) => pid == last_pid && sequence <= last,
+            _ => false,
+        }
+    }
+
+    /// Advance past `message`.
+    pub(crate) fn update(&mut self, message: &Message) {
+        let sent = message
+            .delay_stamp
+            .unwrap_or(message.issue)
+            .with_timezone(&chrono::Utc);
+        self.last_message = std::cmp::max(self.last_message, Some(sent));
+        if !self.contains(message) {
+            self.last_id = Some(message.id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, issue: &str) -> Message {
+        Message::builder().id(id).issue(issue).build()
+    }
+
+    #[test]
+    fn update() {
+        let mut state = ResumeState::default();
+        assert!(!state.contains(&message("14425.100", "2022-02-04T02:54:00Z")));
+
+        state.update(&message("14425.100", "2022-02-04T02:54:00Z"));
+        state.update(&message("14425.101", "2022-02-04T02:55:00Z"));
+        // Delivered out of order
+        state.update(&message("14425.99", "2022-02-04T02:53:00Z"));
+        assert_eq!(
+            state,
+            ResumeState {
+                last_message: Some("2022-02-04T02:55:00Z".parse().unwrap()),
+                last_id: Some("14425.101".into()),
+            }
+        );
+
+        assert!(state.contains(&message("14425.100", "2022-02-04T02:54:00Z")));
+        assert!(state.contains(&message("14425.101", "2022-02-04T02:55:00Z")));
+        assert!(!state.contains(&message("14425.102", "2022-02-04T02:56:00Z")));
+        // The ingest process restarted
+        assert!(!state.contains(&message("20001.1", "2022-02-04T02:56:00Z")));
+        state.update(&message("20001.1", "2022-02-04T02:56:00Z"));
+        assert_eq!(state.last_id.as_deref(), Some("20001.1"));
+        assert!(!state.contains(&message("14425.102", "2022-02-04T02:56:00Z")));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let state = ResumeState {
+            last_message: Some("2022-02-04T02:54:00Z".parse().unwrap()),
+            last_id: Some("14425.25117".into()),
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"last_message":"2022-02-04T02:54:00+00:00","last_id":"14425.25117"}"#
+        );
+        assert_eq!(serde_json::from_str::<ResumeState>(&json).unwrap(), state);
+
+        assert_eq!(
+            serde_json::from_str::<ResumeState>("{}").unwrap(),
+            ResumeState::default()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::*;
+
+/// Tracks the sequence numbers in [`Message::id`](struct.Message.html#structfield.id), to detect
+/// messages which never arrived.
+///
+/// Only the most recent ingest process is tracked. When its process ID changes, the ingest process
+/// has restarted and its sequence starts over, so tracking starts over too.
+#[derive(Debug, Default)]
+pub(crate) struct SequenceTracker {
+    /// The process ID and the highest sequence number seen from it
+    last: Option<(u32, u64)>,
+}
+
+impl SequenceTracker {
+    /// Note a message's `id`, returning a `StreamEvent::SequenceGap` if messages were skipped.
+    ///
+    /// Sequence numbers at or below the highest one seen, e.g. from history replayed after a
+    /// reconnect, are ignored. So are IDs which are not `pid.sequence`.
+    pub(crate) fn observe(&mut self, id: &str) -> Option<StreamEvent> {
+        let (pid, sequence) = parse_id(id)?;
+        match self.last {
+            Some((last_pid, last)) if last_pid == pid => {
+                if sequence <= last {
+                    return None;
+                }
+                self.last = Some((pid, sequence));
+                (sequence > last + 1).then(|| StreamEvent::SequenceGap {
+                    pid,
+                    from: last,
+                    to: sequence,
+                    missing: sequence - last - 1,
+                })
+            }
+            _ => {
+                self.last = Some((pid, sequence));
+                None
+            }
+        }
+    }
+}
+
+/// Split an ID like `14425.25117` into its process ID and sequence number.
+pub(crate) fn parse_id(id: &str) -> Option<(u32, u64)> {
+    let (pid, sequence) = id.split_once('.')?;
+    Some((pid.parse().ok()?, sequence.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observe() {
+        let mut tracker = SequenceTracker::default();
+        let gaps: Vec<_> = [
+            "14425.100",
+            "14425.101",
+            "14425.102",
+            // Two messages went missing
+            "14425.105",
+            // Replayed history is not a gap
+            "14425.103",
+            "14425.105",
+            "14425.106",
+            "garbage",
+            "14425.108",
+            // The ingest process restarted
+            "20001.1",
+            "20001.2",
+        ]
+        .iter()
+        .filter_map(|id| tracker.observe(id))
+        .map(|event| match event {
+            StreamEvent::SequenceGap {
+                pid,
+                from,
+                to,
+                missing,
+            } => (pid, from, to, missing),
+            other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+        assert_eq!(gaps, vec![(14425, 102, 105, 2), (14425, 106, 108, 1)]);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::logging::{error, warn};
+use crate::*;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Something which produces events for a [`Stream`](struct.Stream.html).
+///
+/// [`Connection`](struct.Connection.html) is the live source, which `Stream::new()` and friends
+/// connect and reconnect on their own. Other sources, such as a
+/// [`FileSource`](struct.FileSource.html) replaying a capture, are wrapped with
+/// [`Stream::from_source()`](struct.Stream.html#method.from_source), which runs them through the
+/// same filtering and metrics without touching the network.
+pub trait MessageSource {
+    /// Receive the next event, or `None` once the source is exhausted.
+    ///
+    /// An `Err` also ends the source.
+    fn next_event(&mut self) -> LocalBoxFuture<'_, Option<Result<ConnectionEvent>>>;
+}
+
+impl MessageSource for Connection {
+    fn next_event(&mut self) -> LocalBoxFuture<'_, Option<Result<ConnectionEvent>>> {
+        Box::pin(async move { Some(Connection::next_event(self).await) })
+    }
+}
+
+/// A [`MessageSource`](trait.MessageSource.html) which replays stanzas captured from NWWS-OI.
+///
+/// The capture is a text file of `<message/>` and `<presence/>` stanzas, one after another, as
+/// logged by `nwws_oi` at the trace level. Each stanza starts on a new line, and anything before
+/// its first `<`, like a log prefix, is ignored. Stanzas may continue over several lines, since
+/// products contain line breaks. Lines outside of stanzas are skipped, as are other stanzas.
+///
+/// The capture is read as fast as the `Stream` consumes it, and the stream ends after the last
+/// stanza.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use futures::StreamExt;
+///
+/// # async fn example() -> std::io::Result<()> {
+/// let source = nwws_oi::FileSource::open("capture.log")?;
+/// let mut messages = nwws_oi::Stream::from_source(source).messages();
+/// while let Some(message) = messages.next().await {
+///     println!("{}", message.ttaaii);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct FileSource {
+    reader: Box<dyn BufRead + Send>,
+    /// A stanza which has been started but not yet completed
+    buffer: String,
+    parse_mode: ParseMode,
+}
+
+impl FileSource {
+    /// Open a capture file.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(Self::new(std::io::BufReader::new(file)))
+    }
+
+    /// Read a capture from `reader`.
+    pub fn new<R: BufRead + Send + 'static>(reader: R) -> Self {
+        Self {
+            reader: Box::new(reader),
+            buffer: String::new(),
+            parse_mode: ParseMode::default(),
+        }
+    }
+
+    /// Parse products using `mode`, rather than `ParseMode::Lenient`.
+    pub fn parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Read the next complete stanza.
+    fn next_element(&mut self) -> Option<Result<xmpp_parsers::Element>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    if !self.buffer.is_empty() {
+                        warn!("discarding an incomplete stanza at the end of the capture");
+                        self.buffer.clear();
+                    }
+                    return None;
+                }
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+            if self.buffer.is_empty() {
+                match line.find('<') {
+                    Some(start) => self.buffer.push_str(&line[start..]),
+                    None => continue,
+                }
+            } else if is_stanza_start(line) {
+                // Text within a stanza is escaped, so this can only be a new stanza
+                warn!("discarding a malformed stanza in the capture");
+                self.buffer.clear();
+                self.buffer.push_str(line);
+            } else {
+                self.buffer.push('\n');
+                self.buffer.push_str(line);
+            }
+
+            if self.buffer.ends_with('>') {
+                if let Ok(element) = self.buffer.parse() {
+                    self.buffer.clear();
+                    return Some(Ok(element));
+                }
+            }
+        }
+    }
+}
+
+impl MessageSource for FileSource {
+    fn next_event(&mut self) -> LocalBoxFuture<'_, Option<Result<ConnectionEvent>>> {
+        let event = loop {
+            match self.next_element() {
+                Some(Ok(element)) if element.is("iq", "jabber:client") => {}
+                Some(Ok(element)) => {
+                    if let Some(event) = ConnectionEvent::from_element(element, self.parse_mode) {
+                        break Some(Ok(event));
+                    }
+                }
+                Some(Err(e)) => break Some(Err(e)),
+                None => break None,
+            }
+        };
+        Box::pin(futures::future::ready(event))
+    }
+}
+
+/// Call `handler` with each message from `source`, until it fails or is exhausted.
+///
+/// A handler which panics is logged, and does not stop the loop.
+pub(crate) async fn run_with<S, F, Fut>(source: &mut S, mut handler: F) -> Error
+where
+    S: MessageSource,
+    F: FnMut(Message) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    loop {
+        match source.next_event().await {
+            Some(Ok(ConnectionEvent::Message(message))) => {
+                let id = message.id.clone();
+                let handled = std::panic::AssertUnwindSafe(async { handler(message).await })
+                    .catch_unwind()
+                    .await;
+                if handled.is_err() {
+                    error!("the handler panicked on product {}", id);
+                }
+            }
+            Some(Ok(ConnectionEvent::Error(e))) => warn!("{}", e),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return e,
+            None => return Error::StreamEnded,
+        }
+    }
+}
+
+/// Whether `line` begins a stanza.
+fn is_stanza_start(line: &str) -> bool {
+    ["<message", "<presence", "<iq"]
+        .iter()
+        .any(|start| line.starts_with(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(capture: &str) -> Vec<ConnectionEvent> {
+        let mut source = FileSource::new(std::io::Cursor::new(capture.to_string()));
+        let mut events = Vec::new();
+        while let Some(event) = futures::executor::block_on(source.next_event()) {
+            events.push(event.unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn multiline() {
+        let events = events("[TRACE nwws_oi::connection] received: <message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><body>KLMK issues RRM valid 2022-02-04T02:54:00Z</body><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\">\n\n987\n\nSRUS43 KLMK 040254\n\nRRMLMK\n\n</x></message>\r\nunrelated log line\n<iq xmlns=\"jabber:client\" type=\"get\" id=\"1\"><ping xmlns=\"urn:xmpp:ping\"/></iq>\n<presence xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"http://jabber.org/protocol/muc#user\"><item affiliation=\"owner\" role=\"moderator\"/></x></presence>\n");
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            ConnectionEvent::Message(message) => {
+                assert_eq!(message.id, "14425.25117");
+                assert_eq!(message.message, "SRUS43 KLMK 040254\nRRMLMK\n");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(&events[1], ConnectionEvent::Presence(p) if p.nickname == "nwws-oi"));
+    }
+
+    #[test]
+    fn parse_mode() {
+        let capture = "<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.25117\">\n\n987\n\nSRUS43 KLMK 040254\n\n</x></message>\n";
+        let mut source =
+            FileSource::new(std::io::Cursor::new(capture.to_string())).parse_mode(ParseMode::Raw);
+        match futures::executor::block_on(source.next_event()) {
+            Some(Ok(ConnectionEvent::Message(message))) => {
+                assert_eq!(message.message, "\n\n987\n\nSRUS43 KLMK 040254\n\n");
+                assert_eq!(message.ldm_sequence_number, None);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_with() {
+        let capture = (1..=3).map(|sequence| format!("<message xmlns=\"jabber:client\" type=\"groupchat\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"nwws-oi\" cccc=\"KLMK\" ttaaii=\"SRUS43\" issue=\"2022-02-04T02:54:00Z\" awipsid=\"RRMLMK\" id=\"14425.{}\">\n\n987\n\nSRUS43 KLMK 040254\n\n</x></message>\n", sequence)).collect::<String>();
+        let mut source = FileSource::new(std::io::Cursor::new(capture));
+
+        let mut handled = Vec::new();
+        let error = futures::executor::block_on(super::run_with(&mut source, |message| {
+            if message.id == "14425.2" {
+                panic!("the handler is broken");
+            }
+            handled.push(message.id);
+            async {}
+        }));
+        assert!(matches!(error, Error::StreamEnded));
+        // The panic did not stop later messages
+        assert_eq!(handled, vec!["14425.1", "14425.3"]);
+    }
+
+    #[test]
+    fn malformed() {
+        let events = events("<message xmlns=\"jabber:client\"><body>truncated\n<presence xmlns=\"jabber:client\" from=\"nwws@conference.nwws-oi.weather.gov/nwws-oi\"><x xmlns=\"http://jabber.org/protocol/muc#user\"/></presence>\n<message xmlns=\"jabber:client\"><body>also truncated");
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ConnectionEvent::Presence(_)));
+    }
+}
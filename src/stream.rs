@@ -1,34 +1,386 @@
+use crate::logging::{debug, info, trace};
+use crate::sequence::SequenceTracker;
 use crate::*;
+use futures::future::LocalBoxFuture;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How many events a `Stream` buffers by default.
+pub(crate) const DEFAULT_CAPACITY: usize = 32;
 
 /// A stream of events from NWWS-OI.
 ///
-/// `Stream` automatically re-connects if it was disconnected and generally retries on failure.
+/// `Stream` automatically re-connects if it was disconnected and generally retries on failure,
+/// unless [`Config::auto_reconnect`](struct.Config.html#structfield.auto_reconnect) is `false`.
+/// If [`Config::fallback_servers`](struct.Config.html#structfield.fallback_servers) is set, failed
+/// attempts move on to the next server.
+///
+/// Dropping a `Stream` disconnects it in the background. Use [`shutdown()`](#method.shutdown) to
+/// wait until it has left the room and disconnected.
 pub struct Stream {
-    rx: tokio::sync::mpsc::Receiver<StreamEvent>,
+    rx: queue::Receiver,
+    filter: tokio::sync::watch::Sender<MessageFilter>,
+    /// Dropped to tell the `run()` loops to stop
+    shutdown: tokio::sync::watch::Sender<()>,
+    /// Completes once the background thread has finished
+    done: tokio::sync::oneshot::Receiver<()>,
+    metrics: Arc<StreamMetrics>,
+    /// The position as of the last message received from `rx`
+    position: ResumeState,
 }
 
 impl Stream {
     pub fn new<C: Into<Config>>(config: C) -> Self {
         let config = config.into();
-        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let (capacity, policy) = (config.channel_capacity, config.overflow_policy);
+        let tag_server = !config.fallback_servers.is_empty();
+        Self::spawn(
+            vec![connect(config, tag_server)],
+            capacity,
+            policy,
+            None,
+            ResumeState::default(),
+        )
+    }
 
-        std::thread::spawn(move || {
+    /// Like [`new()`](#method.new), but setting
+    /// [`Config::channel_capacity`](struct.Config.html#structfield.channel_capacity) to
+    /// `capacity`.
+    pub fn with_capacity<C: Into<Config>>(config: C, capacity: usize) -> Self {
+        let mut config = config.into();
+        config.channel_capacity = capacity;
+        Self::new(config)
+    }
+
+    /// Like [`new()`](#method.new), but running on an existing multi-threaded Tokio runtime
+    /// instead of a dedicated runtime.
+    ///
+    /// The connection's futures are not `Send`, so they cannot be spawned onto the runtime's
+    /// worker threads. Instead, they run in a `LocalSet` on one of the runtime's blocking threads,
+    /// using the runtime's I/O and timer drivers. A current-thread runtime cannot drive those
+    /// from another thread, so `handle` must belong to a multi-threaded runtime.
+    ///
+    /// Shut down or drop the `Stream` before shutting down the runtime, which otherwise waits for
+    /// the blocking thread to exit.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # async fn example() {
+    /// let stream = nwws_oi::Stream::spawn_on(("user", "pass"), tokio::runtime::Handle::current());
+    /// # }
+    /// ```
+    pub fn spawn_on<C: Into<Config>>(config: C, handle: tokio::runtime::Handle) -> Self {
+        let config = config.into();
+        let (capacity, policy) = (config.channel_capacity, config.overflow_policy);
+        let tag_server = !config.fallback_servers.is_empty();
+        Self::spawn(
+            vec![connect(config, tag_server)],
+            capacity,
+            policy,
+            Some(handle),
+            ResumeState::default(),
+        )
+    }
+
+    /// Maintain connections to two servers simultaneously, merging their messages.
+    ///
+    /// Either server can drop products, so staying connected to both dramatically reduces gaps.
+    /// Both connections carry the same products, so most products will be received twice.
+    ///
+    /// Connection state changes are reported as
+    /// [`StreamEvent::ServerConnectionState`](enum.StreamEvent.html#variant.ServerConnectionState)
+    /// instead of `StreamEvent::ConnectionState`, identifying which server's connection changed.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let stream = nwws_oi::Stream::with_failover(
+    ///     nwws_oi::Config::primary("user", "pass"),
+    ///     nwws_oi::Config::backup("user", "pass"),
+    /// );
+    /// ```
+    pub fn with_failover<P: Into<Config>, B: Into<Config>>(primary: P, backup: B) -> Self {
+        let primary = primary.into();
+        let (capacity, policy) = (primary.channel_capacity, primary.overflow_policy);
+        Self::spawn(
+            vec![connect(primary, true), connect(backup.into(), true)],
+            capacity,
+            policy,
+            None,
+            ResumeState::default(),
+        )
+    }
+
+    /// Deliver the events of `source` instead of connecting to NWWS-OI.
+    ///
+    /// Filters and metrics apply as usual, but `source` is not reconnected: the stream reports
+    /// `Joined`, then every event, then `Disconnected`, and ends once `source` is exhausted or
+    /// fails. This is most useful with a [`FileSource`](struct.FileSource.html), for testing
+    /// against a recorded feed.
+    ///
+    /// `source` runs on the stream's background thread, so it must be `Send`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # fn example() -> std::io::Result<()> {
+    /// let source = nwws_oi::FileSource::open("capture.log")?;
+    /// let stream = nwws_oi::Stream::from_source(source).dedup_by_id(1024);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_source<S: MessageSource + Send + 'static>(source: S) -> Self {
+        let leg: Leg = (
+            false,
+            Box::new(move |events| Box::pin(run_source(source, events))),
+        );
+        Self::spawn(
+            vec![leg],
+            DEFAULT_CAPACITY,
+            OverflowPolicy::default(),
+            None,
+            ResumeState::default(),
+        )
+    }
+
+    /// Like [`new()`](#method.new), but picking up where a previous `Stream` left off, as of
+    /// its [`last_position()`](#method.last_position).
+    ///
+    /// Joining requests the room's history since `state`'s last message, whatever
+    /// `Config::history` says, and messages which `state` already covers are dropped.
+    /// Reconnecting resumes as with [`History::Resume`](enum.History.html#variant.Resume).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # #[cfg(feature = "serde")]
+    /// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// let state = serde_json::from_str(&std::fs::read_to_string("position.json")?)?;
+    /// let stream = nwws_oi::Stream::resume(("user", "pass"), state);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn resume<C: Into<Config>>(config: C, state: ResumeState) -> Self {
+        let config = resuming(config.into(), &state);
+        let (capacity, policy) = (config.channel_capacity, config.overflow_policy);
+        let tag_server = !config.fallback_servers.is_empty();
+        Self::spawn(
+            vec![connect(config, tag_server)],
+            capacity,
+            policy,
+            None,
+            state,
+        )
+    }
+
+    /// Like [`spawn_on()`](#method.spawn_on), but picking up where a previous `Stream` left off,
+    /// as with [`resume()`](#method.resume).
+    pub fn resume_on<C: Into<Config>>(
+        config: C,
+        handle: tokio::runtime::Handle,
+        state: ResumeState,
+    ) -> Self {
+        let config = resuming(config.into(), &state);
+        let (capacity, policy) = (config.channel_capacity, config.overflow_policy);
+        let tag_server = !config.fallback_servers.is_empty();
+        Self::spawn(
+            vec![connect(config, tag_server)],
+            capacity,
+            policy,
+            Some(handle),
+            state,
+        )
+    }
+
+    /// Like [`with_failover()`](#method.with_failover), but picking up where a previous `Stream`
+    /// left off, as with [`resume()`](#method.resume).
+    ///
+    /// Both servers carry the same products, so one `state` covers both connections.
+    pub fn resume_with_failover<P: Into<Config>, B: Into<Config>>(
+        primary: P,
+        backup: B,
+        state: ResumeState,
+    ) -> Self {
+        let primary = resuming(primary.into(), &state);
+        let backup = resuming(backup.into(), &state);
+        let (capacity, policy) = (primary.channel_capacity, primary.overflow_policy);
+        Self::spawn(
+            vec![connect(primary, true), connect(backup, true)],
+            capacity,
+            policy,
+            None,
+            state,
+        )
+    }
+
+    /// Start each leg on a new thread, using `handle`'s runtime or else a new one, and resuming
+    /// from `resume`.
+    fn spawn(
+        legs: Vec<Leg>,
+        capacity: usize,
+        policy: OverflowPolicy,
+        handle: Option<tokio::runtime::Handle>,
+        resume: ResumeState,
+    ) -> Self {
+        let metrics = Arc::new(StreamMetrics::default());
+        let (tx, rx) = queue::queue(capacity, policy, metrics.clone());
+        let (filter, filter_rx) = tokio::sync::watch::channel(MessageFilter::default());
+        let (shutdown, shutdown_rx) = tokio::sync::watch::channel(());
+        let (done_tx, done) = tokio::sync::oneshot::channel();
+        let sequence = Arc::default();
+        let position = resume.clone();
+        let resume = Arc::new(resume);
+
+        let legs: Vec<_> = legs
+            .into_iter()
+            .map(|(tag_server, start)| {
+                let events = Events {
+                    tx: tx.clone(),
+                    tag_server,
+                    filter: filter_rx.clone(),
+                    shutdown: shutdown_rx.clone(),
+                    metrics: metrics.clone(),
+                    sequence: Arc::clone(&sequence),
+                    resume: Arc::clone(&resume),
+                };
+                (start, events)
+            })
+            .collect();
+
+        // LocalSet is not Send, so it is created on the thread which runs it
+        let local = move || {
             let local = tokio::task::LocalSet::new();
+            for (start, events) in legs {
+                local.spawn_local(start(events));
+            }
+            local
+        };
 
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
+        match handle {
+            Some(handle) => {
+                handle.clone().spawn_blocking(move || {
+                    handle.block_on(local());
+                    done_tx.send(()).ok();
+                });
+            }
+            None => {
+                std::thread::spawn(move || {
+                    let rt = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
 
-            local.spawn_local(run(config, tx));
+                    rt.block_on(local());
+                    done_tx.send(()).ok();
+                });
+            }
+        }
 
-            rt.block_on(local);
-        });
+        Self {
+            rx,
+            filter,
+            shutdown,
+            done,
+            metrics,
+            position,
+        }
+    }
+
+    /// Leave the room, disconnect, and wait for the background thread to exit.
+    ///
+    /// Events which have not yet been received are discarded.
+    pub async fn shutdown(self) {
+        let Self {
+            rx,
+            filter,
+            shutdown,
+            done,
+            ..
+        } = self;
+        drop((rx, filter, shutdown));
+        done.await.ok();
+    }
+}
+
+impl Stream {
+    /// Drop duplicate products from this stream.
+    ///
+    /// See [`Dedup`](struct.Dedup.html).
+    pub fn dedup(self, config: DedupConfig) -> Dedup<Self> {
+        Dedup::new(self, config)
+    }
+
+    /// Drop messages whose ID was among the `capacity` most recently seen.
+    ///
+    /// See [`DedupById`](struct.DedupById.html).
+    pub fn dedup_by_id(self, capacity: usize) -> DedupById<Self> {
+        DedupById::new(self, capacity)
+    }
+
+    /// Yield only messages, dropping every other event.
+    ///
+    /// See [`Messages`](struct.Messages.html).
+    pub fn messages(self) -> Messages<Self> {
+        Messages::new(self)
+    }
+
+    /// Deliver only messages matching `filter`.
+    ///
+    /// Messages are filtered before they are queued for the consumer, so products which do not
+    /// match cost very little.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// let stream = nwws_oi::Stream::new(("user", "pass")).filtered(nwws_oi::MessageFilter {
+    ///     ttaaii: vec!["WFUS*".into()],
+    ///     ..Default::default()
+    /// });
+    /// ```
+    pub fn filtered(self, filter: MessageFilter) -> Self {
+        self.set_filter(filter);
+        self
+    }
+
+    /// Replace the filter set by [`filtered()`](#method.filtered).
+    ///
+    /// Messages which were already queued are not affected.
+    pub fn set_filter(&self, filter: MessageFilter) {
+        // If every receiver is gone, so is every run() loop, and there is nothing to filter
+        self.filter.send(filter).ok();
+    }
+
+    /// Counters describing this stream's activity, which remain readable after the stream is
+    /// dropped.
+    pub fn metrics(&self) -> Arc<StreamMetrics> {
+        self.metrics.clone()
+    }
+
+    /// The position of the last message received from this stream, to pass to
+    /// [`resume()`](#method.resume) after a restart.
+    ///
+    /// The position covers messages which have been received from the `Stream` itself. Messages
+    /// still in its buffer, or dropped by its filter, are not covered.
+    pub fn last_position(&self) -> ResumeState {
+        self.position.clone()
+    }
+
+    /// Note an event as it is received.
+    fn received(&mut self, event: Option<StreamEvent>) -> Option<StreamEvent> {
+        if let Some(StreamEvent::Message(message)) = &event {
+            self.position.update(message);
+        }
+        event
+    }
 
-        Self { rx }
+    /// Wait for the next event, blocking the current thread.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn blocking_recv(&mut self) -> Option<StreamEvent> {
+        let event = self.rx.blocking_recv();
+        self.received(event)
     }
 }
 
@@ -36,94 +388,964 @@ impl futures::Stream for Stream {
     type Item = StreamEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.rx).poll_recv(cx)
+        let event = futures::ready!(self.rx.poll_recv(cx));
+        Poll::Ready(self.received(event))
     }
 }
 
-async fn run(
-    config: Config,
-    tx: tokio::sync::mpsc::Sender<StreamEvent>,
-) -> Result<(), tokio::sync::mpsc::error::SendError<StreamEvent>> {
+use queue::SendError;
+type SendResult = Result<(), SendError>;
+
+/// One source of events for a `Stream`: whether to tag connection states with the server, and a
+/// function which starts delivering events once it is on the background thread.
+type Leg = (
+    bool,
+    Box<dyn FnOnce(Events) -> LocalBoxFuture<'static, SendResult> + Send>,
+);
+
+/// `config`, requesting history since `state`'s last message if there is one.
+fn resuming(mut config: Config, state: &ResumeState) -> Config {
+    if state.last_message.is_some() {
+        // The seconds go unused, since there is always a last message to resume from
+        config.history = History::Resume(0);
+    }
+    config
+}
+
+/// A leg which connects to NWWS-OI as `config` specifies.
+fn connect(config: Config, tag_server: bool) -> Leg {
+    (
+        tag_server,
+        Box::new(move |events| Box::pin(run(config, events))),
+    )
+}
+
+/// The sending half of a `Stream`, as seen by one `run()` loop.
+#[derive(Clone)]
+struct Events {
+    tx: queue::Sender,
+    /// Report connection states as `ServerConnectionState`
+    tag_server: bool,
+    filter: tokio::sync::watch::Receiver<MessageFilter>,
+    shutdown: tokio::sync::watch::Receiver<()>,
+    metrics: Arc<StreamMetrics>,
+    /// Shared by every leg, so that a failover stream reports each gap once
+    sequence: Arc<std::sync::Mutex<SequenceTracker>>,
+    /// Where the `Stream` resumed from
+    resume: Arc<ResumeState>,
+}
+
+impl Events {
+    async fn send(&self, event: StreamEvent) -> SendResult {
+        match &event {
+            StreamEvent::Message(message) => {
+                self.metrics.message_received();
+                let gap = self.sequence.lock().unwrap().observe(&message.id);
+                if let Some(gap) = gap {
+                    if let StreamEvent::SequenceGap { missing, .. } = &gap {
+                        self.metrics.messages_missing(*missing);
+                    }
+                    self.tx.send(gap).await?;
+                }
+                if self.resume.contains(message) {
+                    trace!(
+                        "dropping product {}, which was already delivered",
+                        message.id
+                    );
+                    return Ok(());
+                }
+                if !self.filter.borrow().matches(message) {
+                    trace!("filtering product {}", message.id);
+                    return Ok(());
+                }
+            }
+            StreamEvent::Error(_) => self.metrics.error(),
+            _ => {}
+        }
+        self.tx.send(event).await
+    }
+
+    async fn state(&self, config: &Config, state: ConnectionState) -> SendResult {
+        self.send(if self.tag_server {
+            StreamEvent::ServerConnectionState(config.server.clone(), state)
+        } else {
+            StreamEvent::ConnectionState(state)
+        })
+        .await
+    }
+
+    /// Wait until the `Stream` is shut down or dropped.
+    fn shut_down(&self) -> impl std::future::Future<Output = ()> {
+        let mut shutdown = self.shutdown.clone();
+        async move { while shutdown.changed().await.is_ok() {} }
+    }
+
+    /// Run `future` to completion, unless the `Stream` is shut down or dropped first.
+    async fn until_shutdown<F: std::future::Future>(&self, future: F) -> Option<F::Output> {
+        let shutdown = self.shut_down();
+        futures::pin_mut!(future, shutdown);
+        match futures::future::select(shutdown, future).await {
+            futures::future::Either::Left(((), _)) => None,
+            futures::future::Either::Right((output, _)) => Some(output),
+        }
+    }
+}
+
+/// End a connection, giving up if the server does not respond.
+async fn end(conn: Connection) {
+    match conn.end().await {
+        EndOutcome::Clean => {}
+        EndOutcome::Left => debug!("server did not acknowledge the end of the connection"),
+        EndOutcome::Dropped => debug!("leaving the channel failed; dropped the connection"),
+    }
+}
+
+async fn run(config: Config, tx: Events) -> SendResult {
+    let servers = config.servers();
+    let mut current = 0;
+    let mut failures = 0;
+    // Reconnection attempts since the last connection which lasted `reset_after`
+    let mut reconnects = 0;
+    let mut first = true;
+    let mut last_message = tx.resume.last_message;
     loop {
-        tx.send(StreamEvent::ConnectionState(ConnectionState::Connecting))
-            .await?;
-        run_once(config.clone(), tx.clone()).await?;
+        let attempt = attempt(&config, &servers[current], last_message);
+        if !std::mem::take(&mut first) {
+            tx.metrics.reconnecting();
+        }
+        tx.state(&attempt, ConnectionState::Connecting).await?;
+        let outcome = run_once(attempt.clone(), tx.clone(), &mut last_message).await?;
+
+        let (delay, reason, error) = match outcome {
+            Outcome::Disconnected(connected, error)
+                if connected.elapsed() >= config.backoff.reset_after =>
+            {
+                failures = 0;
+                reconnects = 0;
+                (config.backoff.next_delay(failures), "disconnected", error)
+            }
+            Outcome::Disconnected(_, error) => {
+                failures += 1;
+                (
+                    config.backoff.next_delay(failures),
+                    "disconnected shortly after connecting",
+                    error,
+                )
+            }
+            Outcome::Failed(error) => {
+                failures += 1;
+                (
+                    config.backoff.next_delay(failures),
+                    "connecting failed",
+                    Some(error),
+                )
+            }
+            Outcome::Conflict(error) => {
+                failures += 1;
+                (
+                    config.backoff.next_credential_delay(),
+                    "the nickname is in use",
+                    Some(error),
+                )
+            }
+            Outcome::Refused(error) => {
+                failures += 1;
+                (
+                    config.backoff.next_credential_delay(),
+                    "connecting failed with a fatal error",
+                    Some(error),
+                )
+            }
+            Outcome::Shutdown => return Ok(()),
+        };
+        if !config.auto_reconnect {
+            info!("{}; not reconnecting", reason);
+            return Ok(());
+        }
+        reconnects += 1;
+
+        if failures > 0 && servers.len() > 1 {
+            current = (current + 1) % servers.len();
+            info!(
+                "{} from {}; trying {} in {:?}",
+                reason,
+                attempt.server.hostname(),
+                servers[current].hostname(),
+                delay
+            );
+        } else {
+            info!("{}; reconnecting in {:?}", reason, delay);
+        }
+        tx.send(StreamEvent::Reconnecting {
+            attempt: reconnects,
+            delay,
+            reason: error,
+        })
+        .await?;
+        if tx.until_shutdown(tokio::time::sleep(delay)).await.is_none() {
+            return Ok(());
+        }
+    }
+}
 
-        // Ensure a minimum delay
-        tokio::time::sleep(Duration::from_secs(5)).await;
+/// The `Config` for the next connection attempt, to `server`.
+fn attempt(
+    config: &Config,
+    server: &Server,
+    last_message: Option<chrono::DateTime<chrono::Utc>>,
+) -> Config {
+    Config {
+        server: server.clone(),
+        history: config.history.resume(last_message),
+        resource: if config.resource_per_attempt {
+            Resource::Uuid.into()
+        } else {
+            config.resource.clone()
+        },
+        ..config.clone()
     }
 }
 
+/// How `run_once()` ended, with a description of the error responsible, if any.
+enum Outcome {
+    /// The connection was established at this time and has since ended
+    Disconnected(Instant, Option<String>),
+    /// The connection could not be established
+    Failed(String),
+    /// The connection could not be established due to a fatal error
+    Refused(String),
+    /// The connection could not join the channel, because the nickname is in use
+    Conflict(String),
+    /// The `Stream` was shut down or dropped
+    Shutdown,
+}
+
+/// Connect once, and forward events until disconnected.
+///
+/// `last_message` is updated with the time of each message received, for `History::Resume`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(server = %config.server.hostname(), jid = tracing::field::Empty)
+    )
+)]
 async fn run_once(
     config: Config,
-    tx: tokio::sync::mpsc::Sender<StreamEvent>,
-) -> Result<(), tokio::sync::mpsc::error::SendError<StreamEvent>> {
-    let mut conn =
-        match tokio::time::timeout(Duration::from_secs(75), Connection::new(config)).await {
-            Ok(Ok(conn)) => {
-                tx.send(StreamEvent::ConnectionState(ConnectionState::Connected))
-                    .await?;
-                conn
-            }
-            Ok(Err(e)) => {
-                // Connecting failed
-                // Wait a little while or an extra long time before retrying, depending on the cause
-                let duration = match e {
-                    Error::Configuration(_) | Error::Credentials(_) => 300,
-                    _ => 10,
-                };
+    tx: Events,
+    last_message: &mut Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Outcome, SendError> {
+    // connect_timeout covers authenticating and joining together, as in new_with_timeout()
+    let deadline = connection::connect_deadline(&config);
+    let authenticating = tx.until_shutdown(connection::within(
+        deadline,
+        Connection::authenticate(config.clone()),
+    ));
+    let joined = match authenticating.await {
+        None => return Ok(Outcome::Shutdown),
+        Some(Ok(authenticated)) => {
+            tx.state(&config, ConnectionState::Authenticated).await?;
+            // Rather than dropping a join in progress, leave the room and disconnect
+            let joining = authenticated.join_or_cancel(tx.shut_down());
+            match connection::within(deadline, joining).await {
+                Err(Error::Cancelled) => return Ok(Outcome::Shutdown),
+                joined => joined,
+            }
+        }
+        Some(Err(e)) => Err(e),
+    };
+    let mut conn = match joined {
+        Ok(conn) => {
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("jid", tracing::field::display(conn.bound_jid()));
+            info!(
+                "connected to {} as {}",
+                config.server.hostname(),
+                conn.bound_jid()
+            );
+            tx.state(&config, ConnectionState::Joined).await?;
+            tx.send(StreamEvent::Joined {
+                server: config.server.clone(),
+                jid: conn.bound_jid().clone(),
+            })
+            .await?;
+            conn
+        }
+        Err(Error::Timeout) => {
+            // Connection timed out
+            tx.state(&config, ConnectionState::Disconnected).await?;
 
-                // Send the error and the disconnect event
-                tx.send(StreamEvent::Error(e)).await?;
-                tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
-                    .await?;
+            return Ok(Outcome::Failed(Error::Timeout.to_string()));
+        }
+        Err(e) => {
+            // Connecting failed
+            // The caller will wait a little while or an extra long time before retrying,
+            // depending on the cause
+            let outcome = match &e {
+                Error::NicknameConflict => Outcome::Conflict(e.to_string()),
+                _ if e.is_fatal() => Outcome::Refused(e.to_string()),
+                _ => Outcome::Failed(e.to_string()),
+            };
 
-                // Wait
-                tokio::time::sleep(Duration::from_secs(duration)).await;
+            // Send the error and the disconnect event
+            tx.send(StreamEvent::Error(e)).await?;
+            tx.state(&config, ConnectionState::Disconnected).await?;
 
-                return Ok(());
-            }
-            Err(_) => {
-                // Connection timed out
-                tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
-                    .await?;
+            return Ok(outcome);
+        }
+    };
 
-                return Ok(());
-            }
-        };
+    let connected = Instant::now();
+    // Connection enforces config.ping_timeout and config.idle_timeout, so a dead connection ends
+    // up as Error::PingTimeout and a stalled feed as Error::Idle
+    let forwarded = forward(&mut conn, &tx, last_message).await;
+    tx.metrics.connection_ended(conn.stats());
+    let result = match forwarded {
+        Ok(Forwarded::Ended(error)) => {
+            tx.state(&config, ConnectionState::Disconnected).await?;
+            tokio::task::spawn_local(end(conn));
 
+            return Ok(Outcome::Disconnected(connected, error));
+        }
+        Ok(Forwarded::Refused(error)) => {
+            // e.g. banned from the room, which reconnecting soon will not fix
+            tx.state(&config, ConnectionState::Disconnected).await?;
+            tokio::task::spawn_local(end(conn));
+
+            return Ok(Outcome::Refused(error));
+        }
+        Ok(Forwarded::Shutdown) => Ok(Outcome::Shutdown),
+        // The Stream was dropped
+        Err(e) => Err(e),
+    };
+
+    // Leave the room cleanly, so that we do not linger as an occupant
+    info!("disconnecting from {}", config.server.hostname());
+    end(conn).await;
+    tx.state(&config, ConnectionState::Disconnected).await.ok();
+    result
+}
+
+/// Deliver the events of a `Stream::from_source()` source.
+async fn run_source<S: MessageSource>(mut source: S, tx: Events) -> SendResult {
+    tx.send(StreamEvent::ConnectionState(ConnectionState::Joined))
+        .await?;
+    let mut last_message = None;
+    if let Forwarded::Ended(_) | Forwarded::Refused(_) =
+        forward(&mut source, &tx, &mut last_message).await?
+    {
+        tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
+            .await?;
+    }
+    Ok(())
+}
+
+/// How `forward()` ended.
+enum Forwarded {
+    /// The source was exhausted or failed with the given error, which has been sent
+    Ended(Option<String>),
+    /// The source failed with the given fatal error, which has been sent
+    Refused(String),
+    /// The `Stream` was shut down or dropped
+    Shutdown,
+}
+
+/// Forward events from `source` until it ends.
+///
+/// `last_message` is updated with the time of each message received, for `History::Resume`.
+async fn forward<S: MessageSource>(
+    source: &mut S,
+    tx: &Events,
+    last_message: &mut Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Forwarded, SendError> {
     loop {
-        match tokio::time::timeout(Duration::from_secs(180), conn.next_message()).await {
-            Ok(Ok(msg)) => tx.send(StreamEvent::Message(msg)).await?,
-            Ok(Err(e)) => {
-                tx.send(StreamEvent::Error(e)).await?;
-                tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
-                    .await?;
-                tokio::task::spawn_local(conn.end());
+        let event = match tx.until_shutdown(source.next_event()).await {
+            Some(Some(event)) => event,
+            Some(None) => return Ok(Forwarded::Ended(None)),
+            None => return Ok(Forwarded::Shutdown),
+        };
 
-                return Ok(());
+        match event {
+            Ok(ConnectionEvent::Message(msg)) => {
+                let sent = msg
+                    .delay_stamp
+                    .unwrap_or(msg.issue)
+                    .with_timezone(&chrono::Utc);
+                *last_message = std::cmp::max(*last_message, Some(sent));
+                tx.send(StreamEvent::Message(msg)).await?;
+            }
+            Ok(ConnectionEvent::Presence(presence)) => {
+                tx.send(StreamEvent::Presence(presence)).await?;
             }
-            Err(_) => {
-                // Timeout
-                tx.send(StreamEvent::ConnectionState(ConnectionState::Disconnected))
-                    .await?;
-                tokio::task::spawn_local(conn.end());
-                return Ok(());
+            Ok(ConnectionEvent::Subject(subject)) => {
+                tx.send(StreamEvent::Subject(subject)).await?;
+            }
+            Ok(ConnectionEvent::Unparsed(unparsed)) => {
+                tx.send(StreamEvent::Unparsed(unparsed)).await?;
+            }
+            Ok(ConnectionEvent::Error(e)) => {
+                tx.send(StreamEvent::Error(e)).await?;
+            }
+            Err(e) => {
+                let error = e.to_string();
+                let fatal = e.is_fatal();
+                tx.send(StreamEvent::Error(e)).await?;
+                return Ok(if fatal {
+                    Forwarded::Refused(error)
+                } else {
+                    Forwarded::Ended(Some(error))
+                });
             }
         }
     }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum ConnectionState {
     Connecting,
-    Connected,
+    /// The XMPP connection is established and authenticated, and is joining the room.
+    Authenticated,
+    /// The connection has joined the room.
+    Joined,
     Disconnected,
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StreamEvent {
     ConnectionState(ConnectionState),
+    /// The connection state of one server in a
+    /// [`Stream::with_failover()`](struct.Stream.html#method.with_failover) stream.
+    ServerConnectionState(Server, ConnectionState),
+    /// The connection was established and joined the room.
+    ///
+    /// This follows the `Joined` state, and adds the details of the connection.
+    Joined {
+        /// The server which was joined
+        ///
+        /// This tells apart the servers of a
+        /// [`Stream::with_failover()`](struct.Stream.html#method.with_failover) stream, or of
+        /// [`Config::fallback_servers`](struct.Config.html#structfield.fallback_servers).
+        server: Server,
+        /// The full JID the connection is bound to, as
+        /// [`Connection::bound_jid()`](struct.Connection.html#method.bound_jid) reports
+        ///
+        /// `Jid` comes from the [`jid`](jid/index.html) crate, which this crate re-exports. Its
+        /// resource is the one the server bound, which can differ from
+        /// [`Config::resource`](struct.Config.html#structfield.resource).
+        jid: jid::Jid,
+    },
     Error(Error),
     Message(Message),
+    /// A participant joined or left the room.
+    Presence(Presence),
+    /// The room's subject, e.g. "National Weather Wire Service Open Interface".
+    ///
+    /// NWWS-OI sends the subject once the room has been joined, before any products.
+    Subject(String),
+    /// Messages from the NWS ingest process were skipped, according to the sequence numbers in
+    /// [`Message::id`](struct.Message.html#structfield.id).
+    ///
+    /// This precedes the message which revealed the gap, and is reported whether or not
+    /// [`Stream::filtered()`](struct.Stream.html#method.filtered) would have delivered the missing
+    /// messages. A change of process ID means the ingest process restarted, which is not a gap.
+    SequenceGap {
+        /// The ingest process ID
+        pid: u32,
+        /// The last sequence number received before the gap
+        from: u64,
+        /// The sequence number received after the gap
+        to: u64,
+        /// How many messages were skipped, `to - from - 1`
+        missing: u64,
+    },
+    /// A product which could not be parsed, probably because the NWWS-OI format has changed.
+    Unparsed(UnparsedMessage),
+    /// Messages were dropped because the consumer fell behind, under
+    /// [`OverflowPolicy::DropOldest`](enum.OverflowPolicy.html#variant.DropOldest) or
+    /// [`OverflowPolicy::DropNewest`](enum.OverflowPolicy.html#variant.DropNewest).
+    ///
+    /// This is reported as soon as the consumer receives its next event, ahead of the buffered
+    /// events. [`StreamMetrics::dropped()`](struct.StreamMetrics.html#method.dropped) keeps the
+    /// running total.
+    Overflow {
+        /// How many messages were dropped since the last `Overflow`
+        dropped: u64,
+    },
+    /// The connection ended or could not be established, and will be retried after `delay`.
+    ///
+    /// This follows the `Disconnected` state. In a
+    /// [`Stream::with_failover()`](struct.Stream.html#method.with_failover) stream, each server
+    /// reconnects, and reports this, independently.
+    Reconnecting {
+        /// How many times this stream has reconnected in a row, starting at 1
+        ///
+        /// This is reset once a connection lasts for
+        /// [`Backoff::reset_after`](struct.Backoff.html#structfield.reset_after).
+        attempt: u32,
+        /// How long the stream waits before its next attempt
+        delay: Duration,
+        /// The error which ended the previous attempt, if any
+        reason: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn attempt() {
+        let config = Config::builder("user", "pass")
+            .fallback_servers(vec![Server::Backup])
+            .build();
+        let first = super::attempt(&config, &Server::Backup, None);
+        assert_eq!(first.server, Server::Backup);
+        assert_eq!(first.resource, config.resource);
+
+        let config = Config {
+            resource_per_attempt: true,
+            ..config
+        };
+        let first = super::attempt(&config, &Server::Primary, None);
+        let second = super::attempt(&config, &Server::Primary, None);
+        assert!(first.resource.starts_with("uuid/"));
+        assert_ne!(first.resource, config.resource);
+        assert_ne!(first.resource, second.resource);
+        assert_eq!(first.username, config.username);
+        assert_ne!(first.nickname().unwrap(), second.nickname().unwrap());
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        // Accept TCP connections, but never speak XMPP, so the Stream stays connecting
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .connect_timeout(None)
+            .build();
+
+        let mut stream = Stream::new(config);
+        assert!(matches!(
+            stream.next().await,
+            Some(StreamEvent::ConnectionState(ConnectionState::Connecting))
+        ));
+        let (_socket, _) = listener.accept().await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), stream.shutdown())
+            .await
+            .expect("shutdown() should not wait for the connection attempt");
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let metrics = Arc::new(StreamMetrics::default());
+        let (tx, mut rx) = queue::queue(32, OverflowPolicy::Block, metrics.clone());
+        let (_filter, filter_rx) = tokio::sync::watch::channel(MessageFilter {
+            cccc: vec!["KTOP".into()],
+            ..Default::default()
+        });
+        let (_shutdown, shutdown_rx) = tokio::sync::watch::channel(());
+        let events = Events {
+            tx,
+            tag_server: false,
+            filter: filter_rx,
+            shutdown: shutdown_rx,
+            metrics: metrics.clone(),
+            sequence: Default::default(),
+            resume: Default::default(),
+        };
+        assert_eq!(metrics.messages(), 0);
+        assert_eq!(metrics.last_message(), None);
+
+        let message = |cccc: &str| Message::builder().ttaaii("WFUS53").cccc(cccc).build();
+        let config = Config::from(("user", "pass"));
+        events
+            .state(&config, ConnectionState::Joined)
+            .await
+            .unwrap();
+        events
+            .send(StreamEvent::Message(message("KTOP")))
+            .await
+            .unwrap();
+        events
+            .send(StreamEvent::Message(message("KICT")))
+            .await
+            .unwrap();
+        events
+            .send(StreamEvent::Error(Error::StreamEnded))
+            .await
+            .unwrap();
+
+        // Filtered messages are counted, but not delivered
+        assert_eq!(metrics.messages(), 2);
+        assert_eq!(metrics.errors(), 1);
+        assert_eq!(metrics.reconnects(), 0);
+        assert!(metrics.last_message().unwrap() <= std::time::SystemTime::now());
+        drop(events);
+        let mut delivered = 0;
+        while futures::future::poll_fn(|cx| rx.poll_recv(cx))
+            .await
+            .is_some()
+        {
+            delivered += 1;
+        }
+        assert_eq!(delivered, 3);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn resume() {
+        struct Replay(std::vec::IntoIter<Message>);
+        impl MessageSource for Replay {
+            fn next_event(&mut self) -> LocalBoxFuture<'_, Option<Result<ConnectionEvent>>> {
+                let event = self.0.next().map(|m| Ok(ConnectionEvent::Message(m)));
+                Box::pin(futures::future::ready(event))
+            }
+        }
+        let replay = |sequences: std::ops::RangeInclusive<u32>, resume| {
+            let messages: Vec<_> = sequences
+                .map(|sequence| {
+                    Message::builder()
+                        .ttaaii("WFUS53")
+                        .cccc("KTOP")
+                        .issue(&format!("2022-02-04T02:{:02}:00Z", sequence))
+                        .id(&format!("14425.{}", sequence))
+                        .build()
+                })
+                .collect();
+            let mut source = Replay(messages.into_iter());
+            let leg: Leg = (
+                false,
+                Box::new(move |events| {
+                    Box::pin(
+                        async move { forward(&mut source, &events, &mut None).await.map(|_| ()) },
+                    )
+                }),
+            );
+            Stream::spawn(
+                vec![leg],
+                DEFAULT_CAPACITY,
+                OverflowPolicy::Block,
+                None,
+                resume,
+            )
+        };
+        async fn ids(stream: &mut Stream) -> Vec<String> {
+            let mut ids = Vec::new();
+            while let Some(event) = futures::StreamExt::next(stream).await {
+                if let StreamEvent::Message(message) = event {
+                    ids.push(message.id);
+                }
+            }
+            ids
+        }
+
+        let mut stream = replay(1..=3, ResumeState::default());
+        assert_eq!(
+            ids(&mut stream).await,
+            vec!["14425.1", "14425.2", "14425.3"]
+        );
+        let state = stream.last_position();
+        assert_eq!(
+            state,
+            ResumeState {
+                last_message: Some("2022-02-04T02:03:00Z".parse().unwrap()),
+                last_id: Some("14425.3".into()),
+            }
+        );
+
+        // Persisted, and restarted with overlapping history
+        #[cfg(feature = "serde")]
+        let state = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let mut stream = replay(2..=5, state);
+        assert_eq!(ids(&mut stream).await, vec!["14425.4", "14425.5"]);
+        assert_eq!(stream.last_position().last_id.as_deref(), Some("14425.5"));
+    }
+
+    #[test]
+    fn resume_constructors() {
+        let state = ResumeState {
+            last_message: Some("2022-02-04T02:03:00Z".parse().unwrap()),
+            last_id: Some("14425.3".into()),
+        };
+        let config = resuming(Config::from(("user", "pass")), &state);
+        assert_eq!(config.history, History::Resume(0));
+        let config = resuming(Config::from(("user", "pass")), &ResumeState::default());
+        assert_eq!(config.history, Config::from(("user", "pass")).history);
+
+        // Nothing is listening on this port, so every attempt fails immediately
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .build();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let stream = Stream::resume_on(config.clone(), rt.handle().clone(), state.clone());
+        assert_eq!(stream.last_position(), state);
+        rt.block_on(stream.shutdown());
+
+        let stream = Stream::resume_with_failover(config.clone(), config, state.clone());
+        assert_eq!(stream.last_position(), state);
+        rt.block_on(stream.shutdown());
+    }
+
+    #[tokio::test]
+    async fn timeout_counts_as_failure() {
+        // The server accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .connect_timeout(Some(Duration::from_millis(100)))
+            .backoff(Backoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_secs(1),
+                multiplier: 100,
+                credential_delay: Duration::from_secs(60),
+                jitter: Duration::from_secs(0),
+                reset_after: Duration::from_secs(0),
+            })
+            .build();
+
+        let mut stream = Stream::new(config);
+        let mut disconnected = None;
+        loop {
+            match stream.next().await {
+                Some(StreamEvent::ConnectionState(ConnectionState::Disconnected)) => {
+                    disconnected = Some(tokio::time::Instant::now())
+                }
+                Some(StreamEvent::ConnectionState(ConnectionState::Connecting)) => {
+                    if let Some(disconnected) = disconnected {
+                        // The wait after one failed attempt, not the wait after a disconnection
+                        assert!(disconnected.elapsed() >= Duration::from_millis(900));
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None => panic!("the stream ended"),
+            }
+        }
+        stream.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn metrics_reconnects() {
+        // Nothing is listening on this port, so every attempt fails immediately
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .backoff(Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(1),
+                multiplier: 1,
+                credential_delay: Duration::from_millis(1),
+                jitter: Duration::from_secs(0),
+                reset_after: Duration::from_secs(0),
+            })
+            .build();
+
+        let mut stream = Stream::new(config);
+        let mut connecting = 0;
+        while connecting < 3 {
+            if let Some(StreamEvent::ConnectionState(ConnectionState::Connecting)) =
+                stream.next().await
+            {
+                connecting += 1;
+            }
+        }
+        let metrics = stream.metrics();
+        assert!(metrics.reconnects() >= 2, "{:?}", metrics);
+        assert!(metrics.errors() >= 2, "{:?}", metrics);
+        assert_eq!(metrics.messages(), 0);
+        stream.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reconnecting() {
+        // Nothing is listening on this port, so every attempt fails immediately
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .backoff(Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(4),
+                multiplier: 2,
+                credential_delay: Duration::from_millis(1),
+                jitter: Duration::from_secs(0),
+                reset_after: Duration::from_secs(60),
+            })
+            .build();
+
+        let mut stream = Stream::new(config);
+        let mut reconnecting = Vec::new();
+        while reconnecting.len() < 4 {
+            match stream.next().await {
+                Some(StreamEvent::Reconnecting {
+                    attempt,
+                    delay,
+                    reason,
+                }) => {
+                    assert!(reason.unwrap().contains("network error"));
+                    reconnecting.push((attempt, delay));
+                }
+                Some(_) => {}
+                None => panic!("stream ended"),
+            }
+        }
+        assert_eq!(
+            reconnecting,
+            vec![
+                (1, Duration::from_millis(2)),
+                (2, Duration::from_millis(4)),
+                (3, Duration::from_millis(4)),
+                (4, Duration::from_millis(4)),
+            ]
+        );
+        stream.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn auto_reconnect() {
+        // The server hangs up on every connection
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                drop(socket);
+            }
+        });
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .auto_reconnect(false)
+            .build();
+
+        let events: Vec<StreamEvent> = tokio::time::timeout(
+            Duration::from_secs(5),
+            Stream::new(config).collect::<Vec<_>>(),
+        )
+        .await
+        .expect("the stream should end");
+        assert!(matches!(
+            events[..],
+            [
+                StreamEvent::ConnectionState(ConnectionState::Connecting),
+                StreamEvent::Error(_),
+                StreamEvent::ConnectionState(ConnectionState::Disconnected),
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_states() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            // Hang up as soon as the client has joined
+            drop(client::test_server::join(socket).await);
+        });
+        let config = client::test_server::config(addr)
+            .auto_reconnect(false)
+            .build();
+
+        let events: Vec<StreamEvent> = tokio::time::timeout(
+            Duration::from_secs(5),
+            Stream::new(config).collect::<Vec<_>>(),
+        )
+        .await
+        .expect("the stream should end");
+        let states: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                StreamEvent::ConnectionState(state) => Some(*state),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ConnectionState::Connecting,
+                ConnectionState::Authenticated,
+                ConnectionState::Joined,
+                ConnectionState::Disconnected,
+            ]
+        );
+        assert!(events.iter().any(
+            |event| matches!(event, StreamEvent::Joined { server, jid } if *server == Server::Custom("localhost".into()) && jid.to_string() == "user@localhost/res")
+        ));
+    }
+
+    #[tokio::test]
+    async fn with_capacity() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .build();
+
+        // A zero capacity still delivers events
+        let mut stream = Stream::with_capacity(config, 0);
+        assert!(matches!(
+            stream.next().await,
+            Some(StreamEvent::ConnectionState(ConnectionState::Connecting))
+        ));
+        stream.shutdown().await;
+    }
+
+    #[test]
+    fn spawn_on() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Nothing is listening on this port, so every attempt fails immediately
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Config::builder("user", "pass")
+            .server(Server::CustomWithPort("127.0.0.1".into(), port))
+            .backoff(Backoff {
+                initial: Duration::from_secs(60),
+                ..Default::default()
+            })
+            .build();
+
+        let mut stream = Stream::spawn_on(config, rt.handle().clone());
+        rt.block_on(async {
+            assert!(matches!(
+                stream.next().await,
+                Some(StreamEvent::ConnectionState(ConnectionState::Connecting))
+            ));
+            assert!(matches!(
+                stream.next().await,
+                Some(StreamEvent::Error(Error::Network(_)))
+            ));
+            stream.shutdown().await;
+        });
+    }
 }
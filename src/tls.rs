@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+/// A TLS client configuration built by the application, for
+/// [`Config::tls_connector`](struct.Config.html#structfield.tls_connector).
+///
+/// This is how to present a client certificate, restrict cipher suites, or trust a private CA.
+/// It converts from the TLS backend's own type: a
+/// [`native_tls::TlsConnector`](native_tls/struct.TlsConnector.html) with the `tls-native`
+/// feature, or a [`rustls::ClientConfig`](rustls/struct.ClientConfig.html) with `tls-rustls`. Both
+/// crates are re-exported, so that the versions match.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # #[cfg(feature = "tls-native")]
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use nwws_oi::native_tls;
+///
+/// let identity = native_tls::Identity::from_pkcs8(
+///     &std::fs::read("client.pem")?,
+///     &std::fs::read("client-key.pem")?,
+/// )?;
+/// let connector = native_tls::TlsConnector::builder()
+///     .identity(identity)
+///     .build()?;
+/// let config = nwws_oi::Config::builder("user", "pass")
+///     .server(nwws_oi::Server::Custom("xmpp.example.com".into()))
+///     .tls_connector(connector)
+///     .build();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TlsConnector {
+    #[cfg(feature = "tls-native")]
+    pub(crate) inner: Arc<tokio_native_tls::native_tls::TlsConnector>,
+    #[cfg(feature = "tls-rustls")]
+    pub(crate) inner: Arc<tokio_rustls::rustls::ClientConfig>,
+}
+
+#[cfg(feature = "tls-native")]
+impl From<tokio_native_tls::native_tls::TlsConnector> for TlsConnector {
+    fn from(connector: tokio_native_tls::native_tls::TlsConnector) -> Self {
+        Self {
+            inner: Arc::new(connector),
+        }
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+impl From<tokio_rustls::rustls::ClientConfig> for TlsConnector {
+    fn from(config: tokio_rustls::rustls::ClientConfig) -> Self {
+        Self::from(Arc::new(config))
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+impl From<Arc<tokio_rustls::rustls::ClientConfig>> for TlsConnector {
+    fn from(config: Arc<tokio_rustls::rustls::ClientConfig>) -> Self {
+        Self { inner: config }
+    }
+}
+
+impl std::fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TlsConnector")
+    }
+}
+
+/// Connectors are equal only if they are the same connector.
+impl PartialEq for TlsConnector {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for TlsConnector {}
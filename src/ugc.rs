@@ -0,0 +1,245 @@
+/// A Universal Geographic Code block, e.g. `KYC111-112-040300-`.
+///
+/// UGC blocks list the counties or zones a product applies to, followed by the time at which the
+/// product expires. See NWS Directive 10-1702, "Universal Geographic Code".
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Ugc {
+    /// The counties and zones, in order, with ranges like `026>029` expanded
+    pub codes: Vec<UgcCode>,
+
+    /// The day of the month on which the product expires
+    pub day: u8,
+
+    /// The hour at which the product expires, in UTC
+    pub hour: u8,
+
+    /// The minute at which the product expires
+    pub minute: u8,
+}
+
+/// One county or zone in a [`Ugc`](struct.Ugc.html) block, e.g. `KYC111`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UgcCode {
+    /// The two letter state, territory, or marine area, e.g. `KY`
+    pub state: String,
+
+    /// Whether this is a county or a zone
+    pub kind: UgcKind,
+
+    /// The county or zone number
+    ///
+    /// 0 means every county or zone in `state`, which is written as either `000` or `ALL`.
+    pub number: u16,
+}
+
+/// The kind of area identified by a [`UgcCode`](struct.UgcCode.html).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UgcKind {
+    /// `C`: a county, parish, or borough, numbered by its FIPS code
+    County,
+    /// `Z`: a public or marine forecast zone
+    Zone,
+}
+
+impl std::fmt::Display for UgcCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            UgcKind::County => 'C',
+            UgcKind::Zone => 'Z',
+        };
+        write!(f, "{}{}{:03}", self.state, kind, self.number)
+    }
+}
+
+impl Ugc {
+    /// Find and parse the first UGC block in `text`.
+    pub(crate) fn find(text: &str) -> Option<Self> {
+        let mut lines = text.lines().map(str::trim);
+        while let Some(line) = lines.next() {
+            if !(line.ends_with('-') && parse_prefix(line).is_some()) {
+                continue;
+            }
+
+            // The block continues onto following lines until the expiry time
+            let mut block = line.to_string();
+            while !has_expiry(&block) {
+                match lines.next() {
+                    Some(line) if line.ends_with('-') => block.push_str(line),
+                    _ => return None,
+                }
+            }
+            return Self::parse(&block);
+        }
+        None
+    }
+
+    /// Parse a complete UGC block, without line breaks.
+    fn parse(block: &str) -> Option<Self> {
+        let mut segments: Vec<&str> = block.strip_suffix('-')?.split('-').collect();
+        let expiry = segments.pop()?;
+        if expiry.len() != 6 || !expiry.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let (day, hour, minute) = (
+            expiry[0..2].parse().ok()?,
+            expiry[2..4].parse().ok()?,
+            expiry[4..6].parse().ok()?,
+        );
+        if !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+            return None;
+        }
+
+        let mut codes = Vec::new();
+        let mut current: Option<(String, UgcKind)> = None;
+        for segment in segments {
+            // Segments without a prefix continue the previous state and kind
+            let numbers = match parse_prefix(segment) {
+                Some((state, kind, numbers)) => {
+                    current = Some((state.into(), kind));
+                    numbers
+                }
+                None => segment,
+            };
+            let (state, kind) = current.clone()?;
+
+            let (first, last) = match numbers.split_once('>') {
+                Some((first, last)) => (parse_number(first)?, parse_number(last)?),
+                None => {
+                    let number = parse_number(numbers)?;
+                    (number, number)
+                }
+            };
+            if first > last {
+                return None;
+            }
+            codes.extend((first..=last).map(|number| UgcCode {
+                state: state.clone(),
+                kind,
+                number,
+            }));
+        }
+        if codes.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            codes,
+            day,
+            hour,
+            minute,
+        })
+    }
+}
+
+/// Split `SSF` off the front of a segment like `KYC111` or `KYZ026>029`.
+fn parse_prefix(segment: &str) -> Option<(&str, UgcKind, &str)> {
+    let bytes = segment.as_bytes();
+    if bytes.len() < 6 || !bytes[..2].iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+    let kind = match bytes[2] {
+        b'C' => UgcKind::County,
+        b'Z' => UgcKind::Zone,
+        _ => return None,
+    };
+    parse_number(&segment[3..6])?;
+    Some((&segment[..2], kind, &segment[3..]))
+}
+
+/// Parse a three digit county or zone number, or `ALL`.
+fn parse_number(s: &str) -> Option<u16> {
+    if s == "ALL" {
+        Some(0)
+    } else if s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Whether `block` ends with a `DDHHMM-` expiry time.
+fn has_expiry(block: &str) -> bool {
+    block
+        .strip_suffix('-')
+        .and_then(|block| block.rsplit('-').next())
+        .map_or(false, |last| {
+            last.len() == 6 && last.bytes().all(|b| b.is_ascii_digit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(state: &str, kind: UgcKind, number: u16) -> UgcCode {
+        UgcCode {
+            state: state.into(),
+            kind,
+            number,
+        }
+    }
+
+    #[test]
+    fn single_line() {
+        assert_eq!(
+            Ugc::find("WUUS53 KLMK 040254\nSVRLMK\nKYC111-112-040300-\n/O.NEW.KLMK.SV.W.0001.220204T0254Z-220204T0300Z/\n"),
+            Some(Ugc {
+                codes: vec![
+                    code("KY", UgcKind::County, 111),
+                    code("KY", UgcKind::County, 112),
+                ],
+                day: 4,
+                hour: 3,
+                minute: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn continuation() {
+        // From a Louisville winter weather message
+        let ugc = Ugc::find("WWUS43 KLMK 191015\nWSWLMK\n\nURGENT - WINTER WEATHER MESSAGE\n\nINZ076>078-083-084-KYZ023-024-026>029-\n033>035-191200-\n/O.EXP.KLMK.WW.Y.0004.000000T0000Z-220119T1200Z/\n").unwrap();
+        let codes: Vec<String> = ugc.codes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            codes,
+            vec![
+                "INZ076", "INZ077", "INZ078", "INZ083", "INZ084", "KYZ023", "KYZ024", "KYZ026",
+                "KYZ027", "KYZ028", "KYZ029", "KYZ033", "KYZ034", "KYZ035",
+            ]
+        );
+        assert_eq!((ugc.day, ugc.hour, ugc.minute), (19, 12, 0));
+    }
+
+    #[test]
+    fn marine_and_all() {
+        let ugc = Ugc::find("ANZ530>532-537-040415-\n").unwrap();
+        assert_eq!(
+            ugc.codes,
+            vec![
+                code("AN", UgcKind::Zone, 530),
+                code("AN", UgcKind::Zone, 531),
+                code("AN", UgcKind::Zone, 532),
+                code("AN", UgcKind::Zone, 537),
+            ]
+        );
+
+        let ugc = Ugc::find("TXZALL-OKC000-051800-\n").unwrap();
+        assert_eq!(
+            ugc.codes,
+            vec![code("TX", UgcKind::Zone, 0), code("OK", UgcKind::County, 0)]
+        );
+        assert_eq!(ugc.codes[0].to_string(), "TXZ000");
+    }
+
+    #[test]
+    fn invalid() {
+        // No UGC block
+        assert_eq!(Ugc::find("SRUS43 KLMK 040254\nRRMLMK\n.ER PRSK2 20220203 Z DC202202040254/DUE/DQG/DH17/HGIFE/DIH1/\n"), None);
+        // No expiry
+        assert_eq!(Ugc::find("KYC111-112-\n\nTEXT\n"), None);
+        // Invalid expiry
+        assert_eq!(Ugc::find("KYC111-112-042500-\n"), None);
+        // Backwards range
+        assert_eq!(Ugc::find("KYZ029>026-040300-\n"), None);
+    }
+}
@@ -0,0 +1,74 @@
+use futures::StreamExt;
+use nwws_oi::{ConnectionState, FileSource, MessageFilter, Stream, StreamEvent};
+
+fn capture() -> FileSource {
+    FileSource::open(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/capture.log"
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn replay() {
+    let events: Vec<StreamEvent> = Stream::from_source(capture()).collect().await;
+    let summary: Vec<String> = events
+        .iter()
+        .map(|event| match event {
+            StreamEvent::ConnectionState(state) => format!("{:?}", state),
+            StreamEvent::Presence(presence) => format!("presence {}", presence.nickname),
+            StreamEvent::Subject(subject) => format!("subject {}", subject),
+            StreamEvent::Message(message) => format!("{} {}", message.ttaaii, message.cccc),
+            StreamEvent::Unparsed(unparsed) => format!("unparsed {}", unparsed.error),
+            other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            "Joined",
+            "presence nwws-oi",
+            "subject National Weather Wire Service Open Interface",
+            "SRUS43 KLMK",
+            "SRAK57 PAJK",
+            "FAUS29 KKCI",
+            "NTXX98 PHEB",
+            "unparsed invalid issue attribute: premature end of input",
+            "Disconnected",
+        ]
+    );
+
+    match &events[3] {
+        StreamEvent::Message(message) => {
+            assert_eq!(message.id, "14425.25117");
+            assert!(message.message.starts_with("SRUS43 KLMK 040254\nRRMLMK\n"));
+            assert!(message.delay_stamp.is_some());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn replay_filtered() {
+    let stream = Stream::from_source(capture()).filtered(MessageFilter {
+        cccc: vec!["KLMK".into(), "PHEB".into()],
+        ..Default::default()
+    });
+    let metrics = stream.metrics();
+    let ids: Vec<String> = stream.messages().map(|message| message.id).collect().await;
+    assert_eq!(ids, vec!["14425.25117", "14425.22800"]);
+    assert_eq!(metrics.messages(), 4);
+    assert_eq!(metrics.errors(), 0);
+
+    // The stream ends with the capture
+    let mut stream = Stream::from_source(FileSource::new(std::io::Cursor::new(Vec::new())));
+    assert!(matches!(
+        stream.next().await,
+        Some(StreamEvent::ConnectionState(ConnectionState::Joined))
+    ));
+    assert!(matches!(
+        stream.next().await,
+        Some(StreamEvent::ConnectionState(ConnectionState::Disconnected))
+    ));
+    assert!(stream.next().await.is_none());
+}
@@ -10,23 +10,28 @@ async fn smoke_test() {
         .parse_default_env()
         .init();
 
-    let (username, password) = match (
-        std::env::var("NWWS_OI_USERNAME"),
-        std::env::var("NWWS_OI_PASSWORD"),
-    ) {
-        (Ok(user), Ok(pass)) if !user.is_empty() && !pass.is_empty() => (user, pass),
-        _ => {
-            log::warn!("NWWS_OI_USERNAME and NWWS_OI_PASSWORD must be set");
+    let config = match nwws_oi::Config::from_env() {
+        Ok(config) => config,
+        Err(error) => {
+            log::warn!("{}", error);
             log::warn!("Skipping smoke test");
             return;
         }
     };
 
-    let stream = nwws_oi::Stream::new((username, password));
+    let stream = nwws_oi::Stream::new(config);
 
     let received_test_message = stream.any(|event| {
         futures::future::ready(match event {
             StreamEvent::ConnectionState(_state) => false,
+            StreamEvent::ServerConnectionState(_server, _state) => false,
+            StreamEvent::Joined { .. } => false,
+            StreamEvent::Presence(_presence) => false,
+            StreamEvent::Subject(_subject) => false,
+            StreamEvent::SequenceGap { .. } => false,
+            StreamEvent::Overflow { .. } => false,
+            StreamEvent::Unparsed(_unparsed) => false,
+            StreamEvent::Reconnecting { .. } => false,
             StreamEvent::Error(error) => {
                 log::error!("error: {:?}", error);
                 false
@@ -41,6 +46,7 @@ async fn smoke_test() {
                     false
                 }
             }
+            _ => false,
         })
     });
 